@@ -1,5 +1,6 @@
 use futures::prelude::*;
 use irc::client::prelude::*;
+use irc::client::router::{ArgKind, Route, Router};
 
 #[tokio::main]
 async fn main() -> irc::error::Result<()> {
@@ -16,26 +17,22 @@ async fn main() -> irc::error::Result<()> {
     client.identify()?;
 
     let mut stream = client.stream()?;
-    let sender = client.sender();
+
+    let mut router = Router::new("!");
+    router.add(
+        Route::new("repeat", |_, call| {
+            for _ in 0..call.integer("count").unwrap_or(0).clamp(0, 255) {
+                call.reply(call.text("message").unwrap_or_default())?;
+            }
+            Ok(())
+        })
+        .arg("count", ArgKind::Integer)
+        .arg("message", ArgKind::Text)
+        .help("Repeats a message the specified number of times."),
+    );
 
     loop {
         let message = stream.select_next_some().await?;
-
-        if let Command::PRIVMSG(ref target, ref msg) = message.command {
-            if msg.starts_with(client.current_nickname()) {
-                let tokens: Vec<_> = msg.split(' ').collect();
-                if tokens.len() > 2 {
-                    let n = tokens[0].len() + tokens[1].len() + 2;
-                    if let Ok(count) = tokens[1].parse::<u8>() {
-                        for _ in 0..count {
-                            sender.send_privmsg(
-                                message.response_target().unwrap_or(target),
-                                &msg[n..],
-                            )?;
-                        }
-                    }
-                }
-            }
-        }
+        router.handle(&client, &message)?;
     }
 }
@@ -36,6 +36,8 @@ pub enum Capability {
     ServerTime,
     /// [userhost-in-names](http://ircv3.net/specs/extensions/userhost-in-names-3.2.html)
     UserhostInNames,
+    /// [labeled-response](https://ircv3.net/specs/extensions/labeled-response)
+    LabeledResponse,
     /// Custom IRCv3 capability extensions
     Custom(&'static str),
 }
@@ -67,6 +69,7 @@ impl AsRef<str> for Capability {
             Capability::InviteNotify => "invite-notify",
             Capability::ServerTime => "server-time",
             Capability::UserhostInNames => "userhost-in-names",
+            Capability::LabeledResponse => "labeled-response",
             Capability::Custom(s) => s,
         }
     }
@@ -94,6 +97,7 @@ mod test {
         assert_eq!(InviteNotify.as_ref(), "invite-notify");
         assert_eq!(ServerTime.as_ref(), "server-time");
         assert_eq!(UserhostInNames.as_ref(), "userhost-in-names");
+        assert_eq!(LabeledResponse.as_ref(), "labeled-response");
         assert_eq!(Custom("example").as_ref(), "example");
     }
 }
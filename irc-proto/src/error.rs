@@ -30,7 +30,7 @@ impl From<std::io::Error> for ProtocolError {
 }
 
 /// Errors that occur when parsing messages.
-#[derive(Debug, Error)]
+#[derive(Clone, Debug, PartialEq, Error)]
 pub enum MessageParseError {
     /// The message was empty.
     #[error("empty message")]
@@ -58,10 +58,17 @@ pub enum MessageParseError {
         /// The invalid subcommand.
         sub: String,
     },
+
+    /// The message tag key was invalid.
+    #[error("invalid tag key: {}", key)]
+    InvalidTagKey {
+        /// The invalid tag key.
+        key: String,
+    },
 }
 
 /// Errors that occur while parsing mode strings.
-#[derive(Debug, Error)]
+#[derive(Clone, Debug, PartialEq, Error)]
 pub enum ModeParseError {
     /// Invalid modifier used in a mode string (only + and - are valid).
     #[error("invalid mode modifier: {}", modifier)]
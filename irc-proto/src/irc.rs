@@ -1,20 +1,93 @@
 //! Implementation of IRC codec for Tokio.
+use std::collections::HashMap;
+
 use bytes::BytesMut;
+use encoding::EncodingRef;
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::error;
-use crate::line::LineCodec;
-use crate::message::Message;
+use crate::command::Command;
+use crate::error::{self, MessageParseError};
+use crate::line::{encoding_from_label, LineCodec};
+use crate::message::{InvalidLine, Message};
 
 /// An IRC codec built around an inner codec.
 pub struct IrcCodec {
     inner: LineCodec,
+    lenient: bool,
+    /// Why the last message decoded could not be parsed, if it is a fallback.
+    parse_error: Option<MessageParseError>,
+    /// The line that the last message was decoded from.
+    line: Option<String>,
+    target_encodings: HashMap<String, EncodingRef>,
 }
 
 impl IrcCodec {
     /// Creates a new instance of IrcCodec wrapping a LineCodec with the specific encoding.
     pub fn new(label: &str) -> error::Result<IrcCodec> {
-        LineCodec::new(label).map(|codec| IrcCodec { inner: codec })
+        LineCodec::new(label).map(|codec| IrcCodec {
+            inner: codec,
+            lenient: false,
+            parse_error: None,
+            line: None,
+            target_encodings: HashMap::new(),
+        })
+    }
+
+    /// Sets the encoding used to decode lines that are not valid in the codec's encoding.
+    /// See [`LineCodec::with_fallback`].
+    pub fn with_fallback_encoding(mut self, label: &str) -> error::Result<IrcCodec> {
+        self.inner = self.inner.with_fallback(label)?;
+        Ok(self)
+    }
+
+    /// Sets the encoding used for outgoing `PRIVMSG`, `NOTICE`, and `TOPIC` messages to the
+    /// specified target (typically a channel). Targets are compared ignoring ASCII case only, since
+    /// servers that fold more than that (e.g. `rfc1459` casemapping) differ in what they fold.
+    pub fn with_target_encoding(mut self, target: &str, label: &str) -> error::Result<IrcCodec> {
+        self.target_encodings
+            .insert(target.to_ascii_lowercase(), encoding_from_label(label)?);
+        Ok(self)
+    }
+
+    /// Gets the encoding override for the target of the given message, if any.
+    fn target_encoding(&self, msg: &Message) -> Option<EncodingRef> {
+        if self.target_encodings.is_empty() {
+            return None;
+        }
+
+        let target = match msg.command {
+            Command::PRIVMSG(ref target, _)
+            | Command::NOTICE(ref target, _)
+            | Command::TOPIC(ref target, _) => target,
+            _ => return None,
+        };
+
+        self.target_encodings
+            .get(&target.to_ascii_lowercase())
+            .cloned()
+    }
+
+    /// Sets whether lines that fail to parse are decoded with [`Message::parse_lenient`] instead
+    /// of producing an error. Since an error ends a framed stream, lenient parsing keeps the
+    /// stream alive after a malformed line.
+    pub fn with_lenient_parsing(mut self, lenient: bool) -> IrcCodec {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Takes the reason that the last message decoded could not be parsed, if it is the
+    /// [`Command::Raw`] fallback of lenient parsing. Since a framed stream decodes one message at
+    /// a time, this can be checked after each message it yields.
+    pub fn take_parse_error(&mut self) -> Option<MessageParseError> {
+        self.parse_error.take()
+    }
+
+    /// Takes the line that the last message was decoded from, line ending included. This lets
+    /// anyone who knows more about the server than the parser does reinterpret the message, e.g.
+    /// a `MODE` whose modes are specific to the server, for which the parser has to guess which
+    /// modes take arguments.
+    pub fn take_line(&mut self) -> Option<String> {
+        self.line.take()
     }
 
     /// Sanitizes the input string by cutting up to (and including) the first occurence of a line
@@ -38,9 +111,27 @@ impl Decoder for IrcCodec {
     type Error = error::ProtocolError;
 
     fn decode(&mut self, src: &mut BytesMut) -> error::Result<Option<Message>> {
-        self.inner
-            .decode(src)
-            .and_then(|res| res.map_or(Ok(None), |msg| msg.parse::<Message>().map(Some)))
+        let line = match self.inner.decode(src)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let message = if !self.lenient {
+            line.parse::<Message>()?
+        } else {
+            match Message::parse_lenient(&line) {
+                Ok(message) => {
+                    self.parse_error = None;
+                    message
+                }
+                Err(invalid) => {
+                    let InvalidLine { message, cause } = *invalid;
+                    self.parse_error = Some(cause);
+                    message
+                }
+            }
+        };
+        self.line = Some(line);
+        Ok(Some(message))
     }
 }
 
@@ -48,6 +139,81 @@ impl Encoder<Message> for IrcCodec {
     type Error = error::ProtocolError;
 
     fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> error::Result<()> {
-        self.inner.encode(IrcCodec::sanitize(msg.to_string()), dst)
+        match self.target_encoding(&msg) {
+            Some(encoding) => {
+                self.inner
+                    .encode_with(encoding, IrcCodec::sanitize(msg.to_string()), dst)
+            }
+            None => self.inner.encode(IrcCodec::sanitize(msg.to_string()), dst),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::IrcCodec;
+    use crate::command::Command::{Raw, PRIVMSG, TOPIC};
+
+    #[test]
+    fn encode_with_target_encoding() {
+        let mut codec = IrcCodec::new("UTF-8")
+            .unwrap()
+            .with_target_encoding("#Legacy", "windows-1252")
+            .unwrap();
+        let mut dst = BytesMut::new();
+        codec
+            .encode(
+                PRIVMSG("#legacy".to_owned(), "café".to_owned()).into(),
+                &mut dst,
+            )
+            .unwrap();
+        codec
+            .encode(
+                PRIVMSG("#modern".to_owned(), "café".to_owned()).into(),
+                &mut dst,
+            )
+            .unwrap();
+        codec
+            .encode(
+                TOPIC("#LEGACY".to_owned(), Some("café".to_owned())).into(),
+                &mut dst,
+            )
+            .unwrap();
+        assert_eq!(
+            &dst[..],
+            &b"PRIVMSG #legacy caf\xe9\r\nPRIVMSG #modern caf\xc3\xa9\r\nTOPIC #LEGACY caf\xe9\r\n"
+                [..]
+        );
+    }
+
+    #[test]
+    fn decode_lenient() {
+        let mut codec = IrcCodec::new("UTF-8").unwrap().with_lenient_parsing(true);
+        let mut src = BytesMut::from(
+            &b":irc.test.net :garbage\r\n@irc-proto/parse-error=spoofed PRIVMSG #test hi\r\n"[..],
+        );
+
+        let fallback = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(
+            fallback.command,
+            Raw(String::new(), vec![":irc.test.net :garbage".to_owned()])
+        );
+        assert!(codec.take_parse_error().is_some());
+        assert!(codec.take_parse_error().is_none());
+        assert_eq!(
+            codec.take_line().as_deref(),
+            Some(":irc.test.net :garbage\r\n")
+        );
+
+        // A tag can't pass a valid line off as a fallback.
+        let message = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(
+            message.command,
+            PRIVMSG("#test".to_owned(), "hi".to_owned())
+        );
+        assert!(codec.take_parse_error().is_none());
     }
 }
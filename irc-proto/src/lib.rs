@@ -22,7 +22,7 @@ pub use self::colors::FormattedStringExt;
 pub use self::command::{BatchSubCommand, CapSubCommand, Command};
 #[cfg(feature = "tokio")]
 pub use self::irc::IrcCodec;
-pub use self::message::Message;
+pub use self::message::{Message, Tag};
 pub use self::mode::{ChannelMode, Mode, UserMode};
 pub use self::prefix::Prefix;
 pub use self::response::Response;
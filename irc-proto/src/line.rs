@@ -10,34 +10,147 @@ use tokio_util::codec::{Decoder, Encoder};
 use crate::error;
 
 /// A line-based codec parameterized by an encoding.
+///
+/// Each line is decoded with the codec's encoding. If a fallback encoding is set, lines that are
+/// not valid in the codec's encoding are decoded with the fallback instead, so that a single
+/// client using a legacy encoding does not turn into mojibake for everyone else. Since the 7-bit
+/// ISO-2022 encodings (e.g. `iso-2022-jp`) are always valid UTF-8, a line is instead decoded with
+/// such a fallback whenever it contains ISO-2022 escape sequences.
 pub struct LineCodec {
     encoding: EncodingRef,
-    next_index: usize,
+    fallback: Option<EncodingRef>,
+    lines: RawLineCodec,
 }
 
 impl LineCodec {
     /// Creates a new instance of LineCodec from the specified encoding.
     pub fn new(label: &str) -> error::Result<LineCodec> {
-        encoding_from_whatwg_label(label)
-            .map(|enc| LineCodec {
-                encoding: enc,
-                next_index: 0,
-            })
-            .ok_or_else(|| {
+        encoding_from_label(label).map(|enc| LineCodec {
+            encoding: enc,
+            fallback: None,
+            lines: RawLineCodec::new(),
+        })
+    }
+
+    /// Sets the encoding used to decode lines that are not valid in the codec's encoding, e.g.
+    /// `windows-1252` alongside UTF-8.
+    pub fn with_fallback(mut self, label: &str) -> error::Result<LineCodec> {
+        self.fallback = Some(encoding_from_label(label)?);
+        Ok(self)
+    }
+
+    /// Decodes a single line using the codec's encoding, falling back to the fallback encoding
+    /// (if any) when the line is not valid in the codec's encoding. Anyone who needs the raw bytes
+    /// of each line can frame a stream with [`RawLineCodec`] instead, and decode lines with this
+    /// as needed. The client itself only ever deals in decoded lines.
+    pub fn decode_line(&self, line: &[u8]) -> error::Result<String> {
+        if let Some(fallback) = self.fallback {
+            if fallback.name().starts_with("iso-2022") && has_iso_2022_escapes(line) {
+                if let Ok(data) = fallback.decode(line, DecoderTrap::Strict) {
+                    return Ok(data);
+                }
+            }
+            if let Ok(data) = self.encoding.decode(line, DecoderTrap::Strict) {
+                return Ok(data);
+            }
+            return decode_with(fallback, line);
+        }
+
+        decode_with(self.encoding, line)
+    }
+
+    /// Encodes the message using the given encoding rather than the codec's own encoding.
+    pub fn encode_with(
+        &mut self,
+        encoding: EncodingRef,
+        msg: String,
+        dst: &mut BytesMut,
+    ) -> error::Result<()> {
+        // Encode the message using the specified encoding.
+        let data: error::Result<Vec<u8>> =
+            encoding.encode(&msg, EncoderTrap::Replace).map_err(|data| {
                 io::Error::new(
                     io::ErrorKind::InvalidInput,
-                    &format!("Attempted to use unknown codec {}.", label)[..],
+                    &format!("Failed to encode {} as {}.", data, encoding.name())[..],
                 )
                 .into()
-            })
+            });
+
+        // Write the encoded message to the output buffer.
+        dst.extend(&data?);
+
+        Ok(())
     }
 }
 
+/// Looks up an encoding by its WHATWG label.
+pub(crate) fn encoding_from_label(label: &str) -> error::Result<EncodingRef> {
+    encoding_from_whatwg_label(label).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            &format!("Attempted to use unknown codec {}.", label)[..],
+        )
+        .into()
+    })
+}
+
+/// Checks whether a line contains the escape sequences that switch between character sets in the
+/// ISO-2022 encodings, i.e. `ESC $` or `ESC (`.
+fn has_iso_2022_escapes(line: &[u8]) -> bool {
+    line.windows(2)
+        .any(|pair| pair[0] == 0x1b && (pair[1] == b'$' || pair[1] == b'('))
+}
+
+fn decode_with(encoding: EncodingRef, line: &[u8]) -> error::Result<String> {
+    encoding.decode(line, DecoderTrap::Replace).map_err(|data| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            &format!("Failed to decode {} as {}.", data, encoding.name())[..],
+        )
+        .into()
+    })
+}
+
 impl Decoder for LineCodec {
     type Item = String;
     type Error = error::ProtocolError;
 
     fn decode(&mut self, src: &mut BytesMut) -> error::Result<Option<String>> {
+        match self.lines.decode(src)? {
+            // Decode the line using the codec's encoding.
+            Some(line) => self.decode_line(line.as_ref()).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<String> for LineCodec {
+    type Error = error::ProtocolError;
+
+    fn encode(&mut self, msg: String, dst: &mut BytesMut) -> error::Result<()> {
+        let encoding = self.encoding;
+        self.encode_with(encoding, msg, dst)
+    }
+}
+
+/// A line-delimiting codec that leaves each line undecoded, including its line ending.
+#[derive(Default)]
+pub struct RawLineCodec {
+    next_index: usize,
+}
+
+impl RawLineCodec {
+    /// Creates a new instance of RawLineCodec.
+    pub fn new() -> RawLineCodec {
+        RawLineCodec { next_index: 0 }
+    }
+}
+
+impl Decoder for RawLineCodec {
+    type Item = BytesMut;
+    type Error = error::ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> error::Result<Option<BytesMut>> {
         if let Some(offset) = src[self.next_index..].iter().position(|b| *b == b'\n') {
             // Remove the next frame from the buffer.
             let line = src.split_to(self.next_index + offset + 1);
@@ -45,15 +158,7 @@ impl Decoder for LineCodec {
             // Set the search start index back to 0 since we found a newline.
             self.next_index = 0;
 
-            // Decode the line using the codec's encoding.
-            match self.encoding.decode(line.as_ref(), DecoderTrap::Replace) {
-                Ok(data) => Ok(Some(data)),
-                Err(data) => Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    &format!("Failed to decode {} as {}.", data, self.encoding.name())[..],
-                )
-                .into()),
-            }
+            Ok(Some(line))
         } else {
             // Set the search start index to the current length since we know that none of the
             // characters we've already looked at are newlines.
@@ -63,25 +168,58 @@ impl Decoder for LineCodec {
     }
 }
 
-impl Encoder<String> for LineCodec {
-    type Error = error::ProtocolError;
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
 
-    fn encode(&mut self, msg: String, dst: &mut BytesMut) -> error::Result<()> {
-        // Encode the message using the codec's encoding.
-        let data: error::Result<Vec<u8>> = self
-            .encoding
-            .encode(&msg, EncoderTrap::Replace)
-            .map_err(|data| {
-                io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    &format!("Failed to encode {} as {}.", data, self.encoding.name())[..],
-                )
-                .into()
-            });
+    use super::LineCodec;
 
-        // Write the encoded message to the output buffer.
-        dst.extend(&data?);
+    #[test]
+    fn decode_without_fallback() {
+        let mut codec = LineCodec::new("UTF-8").unwrap();
+        let mut src = BytesMut::from(&b"caf\xe9\r\n"[..]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some("caf\u{fffd}\r\n".to_owned())
+        );
+    }
 
-        Ok(())
+    #[test]
+    fn decode_with_fallback() {
+        let mut codec = LineCodec::new("UTF-8")
+            .unwrap()
+            .with_fallback("windows-1252")
+            .unwrap();
+        let mut src = BytesMut::from(&b"caf\xe9\r\ncaf\xc3\xa9\r\n"[..]);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some("café\r\n".to_owned()));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some("café\r\n".to_owned()));
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_with_iso_2022_fallback() {
+        let mut codec = LineCodec::new("UTF-8")
+            .unwrap()
+            .with_fallback("iso-2022-jp")
+            .unwrap();
+        let mut src = BytesMut::from(&b"\x1b$B$3$s$K$A$O\x1b(B\r\n\xe3\x81\x93\r\nhello\r\n"[..]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some("こんにちは\r\n".to_owned())
+        );
+        assert_eq!(codec.decode(&mut src).unwrap(), Some("こ\r\n".to_owned()));
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some("hello\r\n".to_owned())
+        );
+    }
+
+    #[test]
+    fn unknown_fallback() {
+        assert!(LineCodec::new("UTF-8")
+            .unwrap()
+            .with_fallback("not-an-encoding")
+            .is_err());
     }
 }
@@ -112,6 +112,147 @@ impl Message {
             _ => self.source_nickname(),
         }
     }
+
+    /// Parses a message from the given line like `str::parse`, but always produces a message. If
+    /// the line cannot be parsed, the error is an [`InvalidLine`] carrying both the parse error
+    /// and a [`Command::Raw`] fallback. When the line could be split into a command and
+    /// arguments, those are kept as the raw command. Otherwise, the raw command is empty and the
+    /// whole line is its only argument.
+    ///
+    /// # Example
+    /// ```
+    /// # extern crate irc_proto;
+    /// # use irc_proto::{Command, Message};
+    /// # fn main() {
+    /// let invalid = Message::parse_lenient(":irc.example.com :garbage\r\n").unwrap_err();
+    /// assert_eq!(invalid.cause.to_string(), "invalid command");
+    /// assert_eq!(
+    ///     invalid.message.command,
+    ///     Command::Raw(String::new(), vec![":irc.example.com :garbage".to_owned()])
+    /// );
+    /// # }
+    /// ```
+    pub fn parse_lenient(s: &str) -> Result<Message, Box<InvalidLine>> {
+        let cause = match split_message(s) {
+            Ok((tags, prefix, command, args)) => match Command::new(command, args.clone()) {
+                Ok(parsed) => {
+                    return Ok(Message {
+                        tags,
+                        prefix: prefix.map(|p| p.into()),
+                        command: parsed,
+                    })
+                }
+                Err(cause) => {
+                    return Err(Box::new(InvalidLine {
+                        message: Message {
+                            tags,
+                            prefix: prefix.map(|p| p.into()),
+                            command: Command::Raw(
+                                command.to_owned(),
+                                args.into_iter().map(|s| s.to_owned()).collect(),
+                            ),
+                        },
+                        cause,
+                    }));
+                }
+            },
+            Err(cause) => cause,
+        };
+
+        Err(Box::new(InvalidLine {
+            message: Message {
+                tags: None,
+                prefix: None,
+                command: Command::Raw(
+                    String::new(),
+                    vec![s.trim_end_matches(&['\r', '\n'][..]).to_owned()],
+                ),
+            },
+            cause,
+        }))
+    }
+
+    /// Gets the value of the tag with the given key, if the message has it. A tag without a value
+    /// is reported as having an empty one, which IRCv3 considers equivalent.
+    ///
+    /// # Example
+    /// ```
+    /// # extern crate irc_proto;
+    /// # use irc_proto::Message;
+    /// # fn main() {
+    /// let message: Message = "@msgid=abc;+draft/typing=active :ada PRIVMSG #rust :hi\r\n"
+    ///     .parse()
+    ///     .unwrap();
+    /// assert_eq!(message.tag("+draft/typing"), Some("active"));
+    /// assert_eq!(message.tag("label"), None);
+    /// # }
+    /// ```
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .as_ref()?
+            .iter()
+            .find(|tag| tag.0 == key)
+            .map(|tag| tag.1.as_deref().unwrap_or(""))
+    }
+
+    /// Gets the time at which the server received the message from the `server-time` tag.
+    #[cfg(feature = "chrono")]
+    pub fn server_time(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        chrono::DateTime::parse_from_rfc3339(self.tag("time")?)
+            .ok()
+            .map(|time| time.with_timezone(&chrono::Utc))
+    }
+
+    /// Gets the unique ID of the message from the `msgid` tag.
+    pub fn msgid(&self) -> Option<&str> {
+        self.tag("msgid").filter(|id| !id.is_empty())
+    }
+
+    /// Gets the account that the source of the message is logged into from the `account` tag.
+    pub fn account(&self) -> Option<&str> {
+        self.tag("account").filter(|account| !account.is_empty())
+    }
+
+    /// Gets the reference of the batch that the message belongs to from the `batch` tag.
+    pub fn batch_id(&self) -> Option<&str> {
+        self.tag("batch").filter(|batch| !batch.is_empty())
+    }
+
+    /// Gets the label of the command that the message responds to from the `label` tag.
+    pub fn label(&self) -> Option<&str> {
+        self.tag("label").filter(|label| !label.is_empty())
+    }
+
+    /// Gets the ID of the message that this one replies to from the client-only `+draft/reply`
+    /// tag.
+    pub fn reply_to(&self) -> Option<&str> {
+        self.tag(REPLY_TAG).filter(|id| !id.is_empty())
+    }
+
+    /// Adds a tag to the message, replacing any existing tag with the same key.
+    ///
+    /// # Example
+    /// ```
+    /// # extern crate irc_proto;
+    /// # use irc_proto::{Command, Message, Tag};
+    /// # fn main() {
+    /// let message = Message::from(Command::PRIVMSG("#rust".to_owned(), "same".to_owned()))
+    ///     .with_tag(Tag::reply("abc"))
+    ///     .with_tag(Tag::client_only("draft/react", Some("+1")).unwrap());
+    /// assert_eq!(
+    ///     message.to_string(),
+    ///     "@+draft/reply=abc;+draft/react=+1 PRIVMSG #rust same\r\n"
+    /// );
+    /// # }
+    /// ```
+    pub fn with_tag(mut self, tag: Tag) -> Message {
+        let tags = self.tags.get_or_insert_with(Vec::new);
+        match tags.iter_mut().find(|t| t.0 == tag.0) {
+            Some(existing) => *existing = tag,
+            None => tags.push(tag),
+        }
+        self
+    }
 }
 
 impl From<Command> for Message {
@@ -128,93 +269,99 @@ impl FromStr for Message {
     type Err = ProtocolError;
 
     fn from_str(s: &str) -> Result<Message, Self::Err> {
-        if s.is_empty() {
-            return Err(ProtocolError::InvalidMessage {
+        let (tags, prefix, command, args) =
+            split_message(s).map_err(|cause| ProtocolError::InvalidMessage {
                 string: s.to_owned(),
-                cause: MessageParseError::EmptyMessage,
-            });
-        }
+                cause,
+            })?;
 
-        let mut state = s;
-
-        let tags = if state.starts_with('@') {
-            let tags = state.find(' ').map(|i| &state[1..i]);
-            state = state.find(' ').map_or("", |i| &state[i + 1..]);
-            tags.map(|ts| {
-                ts.split(';')
-                    .filter(|s| !s.is_empty())
-                    .map(|s: &str| {
-                        let mut iter = s.splitn(2, '=');
-                        let (fst, snd) = (iter.next(), iter.next());
-                        let snd = snd.map(unescape_tag_value);
-                        Tag(fst.unwrap_or("").to_owned(), snd)
-                    })
-                    .collect::<Vec<_>>()
-            })
-        } else {
-            None
-        };
+        Message::with_tags(tags, prefix, command, args).map_err(|e| ProtocolError::InvalidMessage {
+            string: s.to_owned(),
+            cause: e,
+        })
+    }
+}
 
-        let prefix = if state.starts_with(':') {
-            let prefix = state.find(' ').map(|i| &state[1..i]);
-            state = state.find(' ').map_or("", |i| &state[i + 1..]);
-            prefix
-        } else {
-            None
-        };
+/// The components of a message line before its command has been interpreted.
+type MessageParts<'a> = (Option<Vec<Tag>>, Option<&'a str>, &'a str, Vec<&'a str>);
 
-        let line_ending_len = if state.ends_with("\r\n") {
-            "\r\n"
-        } else if state.ends_with('\r') {
-            "\r"
-        } else if state.ends_with('\n') {
-            "\n"
-        } else {
-            ""
-        }
-        .len();
-
-        let suffix = if state.contains(" :") {
-            let suffix = state
-                .find(" :")
-                .map(|i| &state[i + 2..state.len() - line_ending_len]);
-            state = state.find(" :").map_or("", |i| &state[..i + 1]);
-            suffix
-        } else {
-            state = &state[..state.len() - line_ending_len];
-            None
-        };
+/// Splits a message line into its tags, prefix, command, and arguments.
+fn split_message(s: &str) -> Result<MessageParts<'_>, MessageParseError> {
+    if s.is_empty() {
+        return Err(MessageParseError::EmptyMessage);
+    }
 
-        let command = match state.find(' ').map(|i| &state[..i]) {
-            Some(cmd) => {
-                state = state.find(' ').map_or("", |i| &state[i + 1..]);
-                cmd
-            }
-            // If there's no arguments but the "command" starts with colon, it's not a command.
-            None if state.starts_with(':') => {
-                return Err(ProtocolError::InvalidMessage {
-                    string: s.to_owned(),
-                    cause: MessageParseError::InvalidCommand,
+    let mut state = s;
+
+    let tags = if state.starts_with('@') {
+        let tags = state.find(' ').map(|i| &state[1..i]);
+        state = state.find(' ').map_or("", |i| &state[i + 1..]);
+        tags.map(|ts| {
+            ts.split(';')
+                .filter(|s| !s.is_empty())
+                .map(|s: &str| {
+                    let mut iter = s.splitn(2, '=');
+                    let (fst, snd) = (iter.next(), iter.next());
+                    let snd = snd.map(unescape_tag_value);
+                    Tag(fst.unwrap_or("").to_owned(), snd)
                 })
-            }
-            // If there's no arguments following the command, the rest of the state is the command.
-            None => {
-                let cmd = state;
-                state = "";
-                cmd
-            }
-        };
-
-        let mut args: Vec<_> = state.splitn(14, ' ').filter(|s| !s.is_empty()).collect();
-        if let Some(suffix) = suffix {
-            args.push(suffix);
+                .collect::<Vec<_>>()
+        })
+    } else {
+        None
+    };
+
+    let prefix = if state.starts_with(':') {
+        let prefix = state.find(' ').map(|i| &state[1..i]);
+        state = state.find(' ').map_or("", |i| &state[i + 1..]);
+        prefix
+    } else {
+        None
+    };
+
+    let line_ending_len = if state.ends_with("\r\n") {
+        "\r\n"
+    } else if state.ends_with('\r') {
+        "\r"
+    } else if state.ends_with('\n') {
+        "\n"
+    } else {
+        ""
+    }
+    .len();
+
+    let suffix = if state.contains(" :") {
+        let suffix = state
+            .find(" :")
+            .map(|i| &state[i + 2..state.len() - line_ending_len]);
+        state = state.find(" :").map_or("", |i| &state[..i + 1]);
+        suffix
+    } else {
+        state = &state[..state.len() - line_ending_len];
+        None
+    };
+
+    let command = match state.find(' ').map(|i| &state[..i]) {
+        Some(cmd) => {
+            state = state.find(' ').map_or("", |i| &state[i + 1..]);
+            cmd
         }
+        // If there's no arguments but the "command" starts with colon, it's not a command.
+        None if state.starts_with(':') => return Err(MessageParseError::InvalidCommand),
+        // If there's no arguments following the command, the rest of the state is the command.
+        None => {
+            let cmd = state;
+            state = "";
+            cmd
+        }
+    };
 
-        Message::with_tags(tags, prefix, command, args).map_err(|e| ProtocolError::InvalidMessage {
-            string: s.to_owned(),
-            cause: e,
-        })
+    let mut args: Vec<_> = state.splitn(14, ' ').filter(|s| !s.is_empty()).collect();
+    if let Some(suffix) = suffix {
+        args.push(suffix);
     }
+
+    Ok((tags, prefix, command, args))
 }
 
 impl<'a> From<&'a str> for Message {
@@ -259,6 +406,15 @@ impl Display for Message {
     }
 }
 
+/// A line that [`Message::parse_lenient`] could not parse.
+#[derive(Debug)]
+pub struct InvalidLine {
+    /// The line as a [`Command::Raw`], keeping as much of it as could be split up.
+    pub message: Message,
+    /// Why the line could not be parsed.
+    pub cause: MessageParseError,
+}
+
 /// A message tag as defined by [IRCv3.2](http://ircv3.net/specs/core/message-tags-3.2.html).
 /// It consists of a tag key, and an optional value for the tag. Each message can contain a number
 /// of tags (in the string format, they are separated by semicolons). Tags are used to add extended
@@ -266,6 +422,78 @@ impl Display for Message {
 #[derive(Clone, PartialEq, Debug)]
 pub struct Tag(pub String, pub Option<String>);
 
+/// The key of the client-only tag referring to the message being replied to.
+const REPLY_TAG: &str = "+draft/reply";
+
+impl Tag {
+    /// Creates a tag, checking that the key is valid. A key consists of letters, digits and
+    /// hyphens, and may have a vendor prefix (like `example.com/`). Keys of client-only tags start
+    /// with a `+`, though [`Tag::client_only`] adds it for you.
+    ///
+    /// # Example
+    /// ```
+    /// # extern crate irc_proto;
+    /// # use irc_proto::Tag;
+    /// # fn main() {
+    /// assert!(Tag::new("example.com/foo-bar", Some("baz")).is_ok());
+    /// assert!(Tag::new("foo bar", None::<&str>).is_err());
+    /// # }
+    /// ```
+    pub fn new<K, V>(key: K, value: Option<V>) -> Result<Tag, MessageParseError>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let key = key.into();
+        if !is_valid_tag_key(&key) {
+            return Err(MessageParseError::InvalidTagKey { key });
+        }
+        Ok(Tag(key, value.map(Into::into)))
+    }
+
+    /// Creates a client-only tag, which servers relay to other clients without interpreting it.
+    /// The key is given without its leading `+`.
+    pub fn client_only<K, V>(key: K, value: Option<V>) -> Result<Tag, MessageParseError>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        Tag::new(format!("+{}", key.into()), value)
+    }
+
+    /// Creates a `+draft/reply` tag marking a message as a reply to the message with the given ID.
+    pub fn reply<S: Into<String>>(msgid: S) -> Tag {
+        Tag(REPLY_TAG.to_owned(), Some(msgid.into()))
+    }
+
+    /// Creates a `label` tag, used to match the server's response to the command carrying it.
+    pub fn label<S: Into<String>>(label: S) -> Tag {
+        Tag("label".to_owned(), Some(label.into()))
+    }
+
+    /// Checks whether this is a client-only tag.
+    pub fn is_client_only(&self) -> bool {
+        self.0.starts_with('+')
+    }
+}
+
+/// Checks a tag key against `[ '+' ] [ <vendor> '/' ] <key_name>`, where the vendor is a hostname
+/// and the name consists of letters, digits and hyphens.
+fn is_valid_tag_key(key: &str) -> bool {
+    let key = key.strip_prefix('+').unwrap_or(key);
+    let (vendor, name) = match key.rsplit_once('/') {
+        Some((vendor, name)) => (Some(vendor), name),
+        None => (None, key),
+    };
+    let vendor_valid = vendor.map_or(true, |vendor| {
+        !vendor.is_empty()
+            && vendor
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+    });
+    vendor_valid && !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
 fn escape_tag_value(f: &mut dyn Write, value: &str) -> FmtResult {
     for c in value.chars() {
         match c {
@@ -306,13 +534,14 @@ fn unescape_tag_value(value: &str) -> String {
 mod test {
     use super::{Message, Tag};
     use crate::command::Command::{Raw, PRIVMSG, QUIT};
+    use crate::error::MessageParseError;
 
     #[test]
     fn new() {
         let message = Message {
             tags: None,
             prefix: None,
-            command: PRIVMSG(format!("test"), format!("Testing!")),
+            command: PRIVMSG("test".to_string(), "Testing!".to_string()),
         };
         assert_eq!(
             Message::new(None, "PRIVMSG", vec!["test", "Testing!"]).unwrap(),
@@ -384,13 +613,13 @@ mod test {
         let message = Message {
             tags: None,
             prefix: None,
-            command: PRIVMSG(format!("test"), format!("Testing!")),
+            command: PRIVMSG("test".to_string(), "Testing!".to_string()),
         };
         assert_eq!(&message.to_string()[..], "PRIVMSG test Testing!\r\n");
         let message = Message {
             tags: None,
             prefix: Some("test!test@test".into()),
-            command: PRIVMSG(format!("test"), format!("Still testing!")),
+            command: PRIVMSG("test".to_string(), "Still testing!".to_string()),
         };
         assert_eq!(
             &message.to_string()[..],
@@ -403,7 +632,7 @@ mod test {
         let message = Message {
             tags: None,
             prefix: None,
-            command: PRIVMSG(format!("test"), format!("Testing!")),
+            command: PRIVMSG("test".to_string(), "Testing!".to_string()),
         };
         assert_eq!(
             "PRIVMSG test :Testing!\r\n".parse::<Message>().unwrap(),
@@ -412,7 +641,7 @@ mod test {
         let message = Message {
             tags: None,
             prefix: Some("test!test@test".into()),
-            command: PRIVMSG(format!("test"), format!("Still testing!")),
+            command: PRIVMSG("test".to_string(), "Still testing!".to_string()),
         };
         assert_eq!(
             ":test!test@test PRIVMSG test :Still testing!\r\n"
@@ -422,12 +651,12 @@ mod test {
         );
         let message = Message {
             tags: Some(vec![
-                Tag(format!("aaa"), Some(format!("bbb"))),
-                Tag(format!("ccc"), None),
-                Tag(format!("example.com/ddd"), Some(format!("eee"))),
+                Tag("aaa".to_string(), Some("bbb".to_string())),
+                Tag("ccc".to_string(), None),
+                Tag("example.com/ddd".to_string(), Some("eee".to_string())),
             ]),
             prefix: Some("test!test@test".into()),
-            command: PRIVMSG(format!("test"), format!("Testing with tags!")),
+            command: PRIVMSG("test".to_string(), "Testing with tags!".to_string()),
         };
         assert_eq!(
             "@aaa=bbb;ccc;example.com/ddd=eee :test!test@test PRIVMSG test :Testing with \
@@ -443,7 +672,7 @@ mod test {
         let message = Message {
             tags: None,
             prefix: None,
-            command: PRIVMSG(format!("test"), format!("Testing!")),
+            command: PRIVMSG("test".to_string(), "Testing!".to_string()),
         };
         assert_eq!(
             "PRIVMSG test :Testing!\r".parse::<Message>().unwrap(),
@@ -472,14 +701,14 @@ mod test {
         let message = Message {
             tags: None,
             prefix: None,
-            command: PRIVMSG(format!("test"), format!("Testing!")),
+            command: PRIVMSG("test".to_string(), "Testing!".to_string()),
         };
         let msg: Message = "PRIVMSG test :Testing!\r\n".into();
         assert_eq!(msg, message);
         let message = Message {
             tags: None,
             prefix: Some("test!test@test".into()),
-            command: PRIVMSG(format!("test"), format!("Still testing!")),
+            command: PRIVMSG("test".to_string(), "Still testing!".to_string()),
         };
         let msg: Message = ":test!test@test PRIVMSG test :Still testing!\r\n".into();
         assert_eq!(msg, message);
@@ -493,8 +722,8 @@ mod test {
             tags: None,
             prefix: Some("test!test@test".into()),
             command: Raw(
-                format!("COMMAND"),
-                vec![format!("ARG:test"), format!("Testing!")],
+                "COMMAND".to_string(),
+                vec!["ARG:test".to_string(), "Testing!".to_string()],
             ),
         };
         let msg: Message = ":test!test@test COMMAND ARG:test :Testing!\r\n".into();
@@ -518,6 +747,32 @@ mod test {
         let _: Message = ":invalid :message".into();
     }
 
+    #[test]
+    fn parse_lenient_valid() {
+        let message = Message::parse_lenient(":test!test@test PRIVMSG test :Testing!\r\n");
+        assert_eq!(
+            message.unwrap(),
+            ":test!test@test PRIVMSG test :Testing!\r\n"
+                .parse::<Message>()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_lenient_invalid() {
+        let invalid = Message::parse_lenient("@aaa=bbb :invalid :message\r\n").unwrap_err();
+        assert!(matches!(invalid.cause, MessageParseError::InvalidCommand));
+        assert_eq!(invalid.message.tags, None);
+        assert_eq!(invalid.message.prefix, None);
+        assert_eq!(
+            invalid.message.command,
+            Raw(
+                String::new(),
+                vec!["@aaa=bbb :invalid :message".to_string()]
+            )
+        );
+    }
+
     #[test]
     fn to_message_tags_escapes() {
         let msg = "@tag=\\:\\s\\\\\\r\\n\\a\\ :test PRIVMSG #test :test\r\n"
@@ -565,4 +820,71 @@ mod test {
         let message = "PRIVMSG #test ::test\r\n";
         assert_eq!(msg, message);
     }
+
+    #[test]
+    fn tag_accessors() {
+        let msg: Message = "@time=2011-10-19T16:40:51.620Z;msgid=abc;account=ada;batch=ref;\
+                            label=l1;+draft/reply=xyz;empty :ada PRIVMSG #test :hi\r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(msg.tag("empty"), Some(""));
+        assert_eq!(msg.tag("missing"), None);
+        assert_eq!(msg.msgid(), Some("abc"));
+        assert_eq!(msg.account(), Some("ada"));
+        assert_eq!(msg.batch_id(), Some("ref"));
+        assert_eq!(msg.label(), Some("l1"));
+        assert_eq!(msg.reply_to(), Some("xyz"));
+
+        let msg: Message = ":ada PRIVMSG #test :hi\r\n".parse().unwrap();
+        assert_eq!(msg.msgid(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "chrono")]
+    fn server_time() {
+        let msg: Message = "@time=2011-10-19T16:40:51.620Z :ada PRIVMSG #test :hi\r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            msg.server_time().map(|t| t.timestamp_millis()),
+            Some(1_319_042_451_620)
+        );
+        let msg: Message = "@time=yesterday :ada PRIVMSG #test :hi\r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(msg.server_time(), None);
+    }
+
+    #[test]
+    fn tag_keys() {
+        assert!(Tag::new("msgid", None::<&str>).is_ok());
+        assert!(Tag::new("+draft/reply", Some("abc")).is_ok());
+        assert!(Tag::new("example.com/a-b", Some("c")).is_ok());
+        assert!(Tag::new("", None::<&str>).is_err());
+        assert!(Tag::new("+", None::<&str>).is_err());
+        assert!(Tag::new("/key", None::<&str>).is_err());
+        assert!(Tag::new("vendor/", None::<&str>).is_err());
+        assert!(Tag::new("semi;colon", None::<&str>).is_err());
+        assert!(Tag::new("key=value", None::<&str>).is_err());
+
+        let tag = Tag::client_only("draft/typing", Some("active")).unwrap();
+        assert_eq!(
+            tag,
+            Tag("+draft/typing".to_owned(), Some("active".to_owned()))
+        );
+        assert!(tag.is_client_only());
+        assert!(!Tag::label("l1").is_client_only());
+    }
+
+    #[test]
+    fn with_tag_replaces_existing() {
+        let msg = Message::from(PRIVMSG("#test".to_string(), "test".to_string()))
+            .with_tag(Tag::label("a"))
+            .with_tag(Tag::reply("abc"))
+            .with_tag(Tag::label("b"));
+        assert_eq!(
+            msg.to_string(),
+            "@label=b;+draft/reply=abc PRIVMSG #test test\r\n"
+        );
+    }
 }
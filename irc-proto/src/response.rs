@@ -76,6 +76,10 @@ make_response! {
     RPL_ENDOFWHOIS      = 318,
     /// `319 <nick> :*( ( "@" / "+" ) <channel> " " )` (Source: RFC2812)
     RPL_WHOISCHANNELS   = 319,
+    /// `330 <nick> <account> :is logged in as` (Source: Modern)
+    RPL_WHOISACCOUNT    = 330,
+    /// `671 <nick> :is using a secure connection` (Source: Modern)
+    RPL_WHOISSECURE     = 671,
     /// `314 <nick> <user> <host> * :<real name>` (Source: RFC2812)
     RPL_WHOWASUSER      = 314,
     /// `369 <nick> :End of WHOWAS` (Source: RFC2812)
@@ -90,6 +94,8 @@ make_response! {
     RPL_UNIQOPIS        = 325,
     /// `324 <channel> <mode> <mode params>` (Source: RFC2812)
     RPL_CHANNELMODEIS   = 324,
+    /// `329 <channel> <creation time>` (Source: Modern)
+    RPL_CREATIONTIME    = 329,
     /// `331 <channel> :No topic is set` (Source: RFC2812)
     RPL_NOTOPIC         = 331,
     /// `332 <channel> :<topic>` (Source: RFC2812)
@@ -125,6 +131,9 @@ make_response! {
     /// `353 ( "=" / "*" / "@" ) <channel> :[ "@" / "+" ] <nick> *( " " [ "@" / "+" ] <nick> )`
     /// (Source: RFC2812)
     RPL_NAMREPLY        = 353,
+    /// `354 [token] [channel] [user] [ip] [host] [server] [nick] [flags] [hopcount] [idle]
+    /// [account] [oplevel] [:realname]`, with only the fields requested by WHOX (Source: IRCv3)
+    RPL_WHOSPCRPL       = 354,
     /// `366 <channel> :End of NAMES list` (Source: RFC2812)
     RPL_ENDOFNAMES      = 366,
     /// `364 <mask> <server> :<hopcount> <server info>` (Source: RFC2812)
@@ -297,6 +306,8 @@ make_response! {
     ERR_WILDTOPLEVEL        = 414,
     /// `415 <mask> :Bad Server/host mask` (Source: RFC2812)
     ERR_BADMASK             = 415,
+    /// `416 <command> :Too many matches` (Source: Modern)
+    ERR_TOOMANYMATCHES      = 416,
     /// `421 <command> :Unknown command` (Source: RFC2812)
     ERR_UNKNOWNCOMMAND      = 421,
     /// `422 :MOTD File is missing` (Source: RFC2812)
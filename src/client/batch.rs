@@ -0,0 +1,405 @@
+//! Reassembly of IRCv3 batches from the client's stream.
+//!
+//! With the `batch` capability enabled, servers group related messages (like the quits of a
+//! netsplit, or a `labeled-response`) between a `BATCH +reference` and a `BATCH -reference`, and
+//! tag each message in between with `batch=reference`. [`ClientStream::batched`] turns a stream
+//! of such messages into a stream of [`BatchItems`](BatchItem), where each batch is yielded as a
+//! whole once it ends.
+//!
+//! Batches are buffered until they end, so to bound the memory used by a batch that never does, a
+//! batch that grows past 10,000 messages is given up on: its `BATCH` message and the messages
+//! buffered so far are yielded as they are, and so are its messages from then on.
+//!
+//! # Example
+//! ```no_run
+//! # use irc::client::prelude::*;
+//! use futures::prelude::*;
+//! use irc::client::batch::BatchItem;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> irc::error::Result<()> {
+//! let mut client = Client::new("config.toml").await?;
+//! client.send_cap_req(&[Capability::Batch])?;
+//! client.identify()?;
+//! let mut stream = client.stream()?.batched();
+//!
+//! while let Some(item) = stream.next().await.transpose()? {
+//!     match item {
+//!         BatchItem::Batch(batch) => {
+//!             println!("{:?} batch of {} messages", batch.kind, batch.messages.len())
+//!         }
+//!         BatchItem::Message(message) => print!("{}", message),
+//!         BatchItem::Invalid(message, cause) => eprint!("{} ({})", message, cause),
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::VecDeque,
+    iter,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::{ready, stream::FusedStream, stream::Stream};
+
+use super::ClientStream;
+use crate::{
+    error,
+    proto::{error::MessageParseError, BatchSubCommand, Command, Message},
+};
+
+/// A batch of messages, as sent by the server between a `BATCH +reference` and a
+/// `BATCH -reference`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch {
+    /// The batch's reference tag, without the leading `+` or `-`.
+    pub reference: String,
+    /// The batch's type, e.g. `NETSPLIT`.
+    pub kind: Option<BatchSubCommand>,
+    /// The batch's parameters, which depend on its type.
+    pub params: Vec<String>,
+    /// The `BATCH` message that started the batch.
+    pub start: Message,
+    /// The messages in the batch, in the order they were received. A nested batch appears where
+    /// it ended.
+    pub messages: Vec<BatchItem>,
+}
+
+/// An item yielded by a [`BatchStream`], either a message outside of any batch or a whole batch.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchItem {
+    /// A message outside of any batch.
+    Message(Message),
+    /// A line that could not be parsed, delivered as a [`Command::Raw`] fallback under
+    /// [`InvalidMessagePolicy::Raw`](crate::client::data::InvalidMessagePolicy::Raw), along with
+    /// why it could not be parsed.
+    Invalid(Message, MessageParseError),
+    /// A batch, along with its messages.
+    Batch(Batch),
+}
+
+/// The most messages that a batch is buffered with before it's given up on.
+const MAX_BATCH_LEN: usize = 10_000;
+
+/// A stream of messages from an IRC server in which batches are reassembled, as returned by
+/// [`ClientStream::batched`].
+#[derive(Debug)]
+pub struct BatchStream {
+    stream: ClientStream,
+    /// The batches that have started but not yet ended, in the order they started, along with the
+    /// references of their parent batches.
+    open: Vec<(Option<String>, Batch)>,
+    /// The items ready to be yielded before the underlying stream is polled again.
+    ready: VecDeque<BatchItem>,
+    /// The most messages that a batch is buffered with before it's given up on.
+    max_len: usize,
+    terminated: bool,
+}
+
+impl BatchStream {
+    pub(crate) fn new(stream: ClientStream) -> BatchStream {
+        BatchStream {
+            stream,
+            open: Vec::new(),
+            ready: VecDeque::new(),
+            max_len: MAX_BATCH_LEN,
+            terminated: false,
+        }
+    }
+
+    /// Adds a message to the batch it belongs to, returning the item to yield, if any.
+    fn handle(
+        &mut self,
+        message: Message,
+        parse_error: Option<MessageParseError>,
+    ) -> Option<BatchItem> {
+        let parent = message
+            .batch_id()
+            .filter(|parent| self.position(parent).is_some())
+            .map(|parent| parent.to_owned());
+
+        let reference = match message.command {
+            Command::BATCH(ref reference, ref kind, ref params) => {
+                if let Some(reference) = reference.strip_prefix('+') {
+                    let batch = Batch {
+                        reference: reference.to_owned(),
+                        kind: kind.clone(),
+                        params: params.clone().unwrap_or_default(),
+                        start: message,
+                        messages: Vec::new(),
+                    };
+                    self.open.push((parent, batch));
+                    return None;
+                }
+                reference.strip_prefix('-').map(|r| r.to_owned())
+            }
+            _ => None,
+        };
+
+        let (item, parent) = match reference.and_then(|reference| self.position(&reference)) {
+            Some(n) => {
+                let parent = self.open[n].0.clone();
+                (self.close(n), parent)
+            }
+            None => {
+                let item = match parse_error {
+                    Some(cause) => BatchItem::Invalid(message, cause),
+                    None => BatchItem::Message(message),
+                };
+                (self.add(parent.as_deref(), item), parent)
+            }
+        };
+        if let Some(n) = parent.and_then(|parent| self.position(&parent)) {
+            if self.open[n].1.messages.len() > self.max_len {
+                self.flush(n);
+            }
+        }
+        item
+    }
+
+    /// Gives up on a batch that has grown too long, readying its messages to be yielded as they
+    /// are. Its messages from then on are yielded as they are too, since they no longer belong to
+    /// an open batch.
+    fn flush(&mut self, n: usize) {
+        let (parent, batch) = self.open.remove(n);
+        for (child_parent, _) in &mut self.open {
+            if child_parent.as_deref() == Some(&batch.reference) {
+                *child_parent = parent.clone();
+            }
+        }
+        let items = iter::once(BatchItem::Message(batch.start)).chain(batch.messages);
+        for item in items {
+            if let Some(item) = self.add(parent.as_deref(), item) {
+                self.ready.push_back(item);
+            }
+        }
+    }
+
+    /// Adds an item to the specified batch, or returns it if it isn't part of one.
+    fn add(&mut self, parent: Option<&str>, item: BatchItem) -> Option<BatchItem> {
+        match parent.and_then(|parent| self.position(parent)) {
+            Some(n) => {
+                self.open[n].1.messages.push(item);
+                None
+            }
+            None => Some(item),
+        }
+    }
+
+    /// Ends the specified batch, returning it if it isn't nested in another one.
+    fn close(&mut self, n: usize) -> Option<BatchItem> {
+        let (parent, batch) = self.open.remove(n);
+        self.add(parent.as_deref(), BatchItem::Batch(batch))
+    }
+
+    /// Gets the position of an open batch.
+    fn position(&self, reference: &str) -> Option<usize> {
+        self.open
+            .iter()
+            .position(|(_, batch)| batch.reference == reference)
+    }
+}
+
+impl FusedStream for BatchStream {
+    fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+}
+
+impl Stream for BatchStream {
+    type Item = error::Result<BatchItem>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(item) = self.ready.pop_front() {
+                return Poll::Ready(Some(Ok(item)));
+            }
+            if self.terminated {
+                return Poll::Ready(None);
+            }
+
+            match ready!(Pin::new(&mut self.stream).poll_received(cx)) {
+                Some(Ok(received)) => {
+                    if let Some(item) = self.handle(received.message, received.parse_error) {
+                        return Poll::Ready(Some(Ok(item)));
+                    }
+                }
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => {
+                    // Batches that never ended are yielded as they are, innermost first so that
+                    // they still end up inside their parents.
+                    self.terminated = true;
+                    while !self.open.is_empty() {
+                        let n = self.open.len() - 1;
+                        if let Some(item) = self.close(n) {
+                            self.ready.push_front(item);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BatchItem;
+    use crate::{
+        client::{
+            data::{Config, InvalidMessagePolicy},
+            test::test_config,
+            Client,
+        },
+        proto::{error::MessageParseError, BatchSubCommand},
+    };
+    use anyhow::Result;
+    use futures::prelude::*;
+
+    /// Gets the items that a stream of the specified messages is reassembled into.
+    async fn batched(value: &str) -> Result<Vec<BatchItem>> {
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        Ok(client.stream()?.batched().try_collect().await?)
+    }
+
+    #[tokio::test]
+    async fn nested_batches() -> Result<()> {
+        let value = ":irc.test.net BATCH +outer chathistory #test\r\n\
+                     @batch=outer :alice!a@host PRIVMSG #test :one\r\n\
+                     :irc.test.net NOTICE test :outside\r\n\
+                     @batch=outer :irc.test.net BATCH +inner draft/multiline #test\r\n\
+                     @batch=inner :alice!a@host PRIVMSG #test :two\r\n\
+                     @batch=inner :alice!a@host PRIVMSG #test :three\r\n\
+                     :irc.test.net BATCH -inner\r\n\
+                     :irc.test.net BATCH -outer\r\n";
+        let items = batched(value).await?;
+        assert_eq!(items.len(), 2);
+
+        assert!(matches!(&items[0], BatchItem::Message(m) if m.to_string().contains("outside")));
+        let outer = match &items[1] {
+            BatchItem::Batch(batch) => batch,
+            item => panic!("unexpected item: {:?}", item),
+        };
+        assert_eq!(outer.reference, "outer");
+        assert_eq!(
+            outer.kind,
+            Some(BatchSubCommand::CUSTOM("CHATHISTORY".to_owned()))
+        );
+        assert_eq!(outer.params, vec!["#test"]);
+        assert_eq!(outer.messages.len(), 2);
+        match &outer.messages[1] {
+            BatchItem::Batch(inner) => {
+                assert_eq!(inner.reference, "inner");
+                assert_eq!(inner.params, vec!["#test"]);
+                assert_eq!(inner.messages.len(), 2);
+            }
+            item => panic!("unexpected item: {:?}", item),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_parent() -> Result<()> {
+        let value = "@batch=unknown :irc.test.net NOTICE test :stray\r\n\
+                     @batch=unknown :irc.test.net BATCH +orphan NETSPLIT a.net b.net\r\n\
+                     @batch=orphan :bob!b@host QUIT :a.net b.net\r\n\
+                     :irc.test.net BATCH -orphan\r\n\
+                     :irc.test.net BATCH -unknown\r\n";
+        let items = batched(value).await?;
+        assert_eq!(items.len(), 3);
+        assert!(matches!(&items[0], BatchItem::Message(m) if m.to_string().contains("stray")));
+        assert!(matches!(
+            &items[1],
+            BatchItem::Batch(batch) if batch.reference == "orphan" && batch.messages.len() == 1
+        ));
+        assert!(matches!(&items[2], BatchItem::Message(m) if m.to_string().contains("-unknown")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unterminated_batches() -> Result<()> {
+        let value = ":irc.test.net BATCH +open NETSPLIT a.net b.net\r\n\
+                     @batch=open :bob!b@host QUIT :a.net b.net\r\n\
+                     @batch=open :irc.test.net BATCH +nested example\r\n\
+                     @batch=nested :carol!c@host QUIT :a.net b.net\r\n";
+        let items = batched(value).await?;
+        assert_eq!(items.len(), 1);
+        let open = match &items[0] {
+            BatchItem::Batch(batch) => batch,
+            item => panic!("unexpected item: {:?}", item),
+        };
+        assert_eq!(open.kind, Some(BatchSubCommand::NETSPLIT));
+        assert_eq!(open.messages.len(), 2);
+        assert!(matches!(
+            &open.messages[1],
+            BatchItem::Batch(nested) if nested.reference == "nested" && nested.messages.len() == 1
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversized_batch() -> Result<()> {
+        let value = ":irc.test.net BATCH +big chathistory #test\r\n\
+                     @batch=big :alice!a@host PRIVMSG #test :one\r\n\
+                     @batch=big :irc.test.net BATCH +inner draft/multiline #test\r\n\
+                     @batch=big :alice!a@host PRIVMSG #test :two\r\n\
+                     @batch=big :alice!a@host PRIVMSG #test :three\r\n\
+                     @batch=inner :alice!a@host PRIVMSG #test :four\r\n\
+                     :irc.test.net BATCH -inner\r\n\
+                     :irc.test.net BATCH -big\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        let mut stream = client.stream()?.batched();
+        stream.max_len = 2;
+        let items: Vec<_> = stream.try_collect().await?;
+
+        // The batch is given up on at its third message, after which the nested batch that was
+        // open inside it is reassembled on its own.
+        let lines: Vec<_> = items
+            .iter()
+            .map(|item| match item {
+                BatchItem::Message(message) | BatchItem::Invalid(message, _) => message.to_string(),
+                BatchItem::Batch(batch) => format!("batch {}", batch.reference),
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                ":irc.test.net BATCH +big CHATHISTORY #test\r\n",
+                "@batch=big :alice!a@host PRIVMSG #test one\r\n",
+                "@batch=big :alice!a@host PRIVMSG #test two\r\n",
+                "@batch=big :alice!a@host PRIVMSG #test three\r\n",
+                "batch inner",
+                ":irc.test.net BATCH -big\r\n",
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_lines() -> Result<()> {
+        let value = ":irc.test.net :garbage\r\n:irc.test.net NOTICE test :valid\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            invalid_message_policy: Some(InvalidMessagePolicy::Raw),
+            ..test_config()
+        })
+        .await?;
+        let items: Vec<_> = client.stream()?.batched().try_collect().await?;
+        assert_eq!(items.len(), 2);
+        assert!(matches!(
+            &items[0],
+            BatchItem::Invalid(_, MessageParseError::InvalidCommand)
+        ));
+        assert!(matches!(&items[1], BatchItem::Message(_)));
+        Ok(())
+    }
+}
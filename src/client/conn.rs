@@ -1,20 +1,23 @@
 //! A module providing IRC connections for use by `IrcServer`s.
+use chrono::{DateTime, Utc};
 use futures_util::{sink::Sink, stream::Stream};
 use pin_project::pin_project;
 use std::{
     fmt,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
 };
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{mpsc::UnboundedSender, watch};
 use tokio_util::codec::Framed;
 
 #[cfg(feature = "proxy")]
-use tokio_socks::tcp::Socks5Stream;
+use crate::client::proxy;
 
-#[cfg(feature = "proxy")]
-use crate::client::data::ProxyType;
+#[cfg(feature = "websocket")]
+use crate::client::websocket::WebSocket;
 
 #[cfg(all(feature = "tls-native", not(feature = "tls-rust")))]
 use std::{fs::File, io::Read};
@@ -32,7 +35,6 @@ use std::{
     convert::TryFrom,
     fs::File,
     io::{BufReader, Error, ErrorKind},
-    sync::Arc,
 };
 #[cfg(feature = "tls-rust")]
 use tokio_rustls::client::TlsStream;
@@ -47,14 +49,101 @@ use tokio_rustls::{
 
 use crate::{
     client::{
-        data::Config,
+        data::{Config, InvalidMessagePolicy},
         mock::MockStream,
-        transport::{LogView, Logged, Transport},
+        transport::{Lag, LogView, Logged, Received, Transport},
     },
     error,
     proto::{IrcCodec, Message},
 };
 
+/// A stage in the lifecycle of a connection to an IRC server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ConnectionState {
+    /// Connecting to the server (or to the proxy in front of it).
+    Connecting,
+    /// Performing the TLS handshake with the server.
+    TlsHandshake,
+    /// Connected to the server, but nothing has been sent yet.
+    Connected,
+    /// Negotiating IRCv3 capabilities with `CAP`.
+    NegotiatingCapabilities,
+    /// Authenticating with SASL.
+    Authenticating,
+    /// Waiting for the server to accept our registration.
+    Registering,
+    /// Registered with the server, which has welcomed us.
+    Registered,
+    /// Disconnected from the server, whether because the connection was closed or because it
+    /// failed.
+    Disconnected,
+}
+
+/// The state of a connection, along with when it entered that state and the states it went
+/// through before.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionStatus {
+    /// The current state of the connection.
+    pub state: ConnectionState,
+    /// When the connection entered its current state.
+    pub since: DateTime<Utc>,
+    /// The previous states of the connection, oldest first, along with when it entered each of
+    /// them.
+    pub history: Vec<(ConnectionState, DateTime<Utc>)>,
+}
+
+/// Keeps the status of a connection up to date, notifying watchers of each transition.
+#[derive(Clone, Debug)]
+pub(crate) struct StatusTracker(Arc<watch::Sender<ConnectionStatus>>);
+
+impl StatusTracker {
+    /// Creates a tracker for a connection that is just starting to connect.
+    pub(crate) fn new() -> StatusTracker {
+        let status = ConnectionStatus {
+            state: ConnectionState::Connecting,
+            since: Utc::now(),
+            history: Vec::new(),
+        };
+        StatusTracker(Arc::new(watch::channel(status).0))
+    }
+
+    /// Gets the current state of the connection.
+    pub(crate) fn state(&self) -> ConnectionState {
+        self.0.borrow().state
+    }
+
+    /// Moves the connection to the specified state, unless it is already in it.
+    pub(crate) fn set(&self, state: ConnectionState) {
+        self.0.send_if_modified(|status| {
+            if status.state == state {
+                return false;
+            }
+            log::debug!(
+                "Connection state changed from {:?} to {:?}",
+                status.state,
+                state
+            );
+            let previous = std::mem::replace(&mut status.state, state);
+            let since = std::mem::replace(&mut status.since, Utc::now());
+            status.history.push((previous, since));
+            true
+        });
+    }
+
+    /// Gets a receiver that is notified of each transition.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<ConnectionStatus> {
+        self.0.subscribe()
+    }
+}
+
+/// A stream that an IRC connection can be carried over, as used with
+/// [`Client::from_stream`](crate::client::Client::from_stream). This is implemented for any type
+/// that can be read from and written to asynchronously, like a `UnixStream` or one half of a
+/// `tokio::io::duplex`.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> AsyncStream for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
 /// An IRC connection used internally by `IrcServer`.
 #[pin_project(project = ConnectionProj)]
 pub enum Connection {
@@ -64,6 +153,11 @@ pub enum Connection {
     #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
     Secured(#[pin] Transport<TlsStream<TcpStream>>),
     #[doc(hidden)]
+    #[cfg(feature = "websocket")]
+    WebSocket(#[pin] Transport<WebSocket<Box<dyn AsyncStream>>>),
+    #[doc(hidden)]
+    Custom(#[pin] Transport<Box<dyn AsyncStream>>),
+    #[doc(hidden)]
     Mock(#[pin] Logged<MockStream>),
 }
 
@@ -76,6 +170,9 @@ impl fmt::Debug for Connection {
                 Connection::Unsecured(_) => "Connection::Unsecured(...)",
                 #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
                 Connection::Secured(_) => "Connection::Secured(...)",
+                #[cfg(feature = "websocket")]
+                Connection::WebSocket(_) => "Connection::WebSocket(...)",
+                Connection::Custom(_) => "Connection::Custom(...)",
                 Connection::Mock(_) => "Connection::Mock(...)",
             }
         )
@@ -87,6 +184,40 @@ impl Connection {
     pub(crate) async fn new(
         config: &Config,
         tx: UnboundedSender<Message>,
+        status: &StatusTracker,
+    ) -> error::Result<Connection> {
+        status.set(ConnectionState::Connecting);
+        let conn = Self::connect(config, tx, status).await;
+        status.set(match conn {
+            Ok(_) => ConnectionState::Connected,
+            Err(_) => ConnectionState::Disconnected,
+        });
+        conn
+    }
+
+    /// Creates a new `Connection` over a stream that is already connected to the server.
+    pub(crate) fn from_stream<S>(
+        config: &Config,
+        stream: S,
+        tx: UnboundedSender<Message>,
+    ) -> error::Result<Connection>
+    where
+        S: AsyncStream + 'static,
+    {
+        log::info!("Connecting over a custom stream.");
+        let stream: Box<dyn AsyncStream> = Box::new(stream);
+        let framed = Framed::new(stream, Self::new_codec(config)?);
+        Ok(Connection::Custom(Transport::new(config, framed, tx)))
+    }
+
+    #[cfg_attr(
+        not(any(feature = "tls-native", feature = "tls-rust")),
+        allow(unused_variables)
+    )]
+    async fn connect(
+        config: &Config,
+        tx: UnboundedSender<Message>,
+        status: &StatusTracker,
     ) -> error::Result<Connection> {
         if config.use_mock_connection() {
             log::info!("Connecting via mock to {}.", config.server()?);
@@ -95,12 +226,22 @@ impl Connection {
             )));
         }
 
+        #[cfg(feature = "websocket")]
+        {
+            if config.use_websocket() {
+                log::info!("Connecting via WebSocket to {}.", config.server()?);
+                return Ok(Connection::WebSocket(
+                    Self::new_websocket_transport(config, tx, status).await?,
+                ));
+            }
+        }
+
         #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
         {
             if config.use_tls() {
                 log::info!("Connecting via TLS to {}.", config.server()?);
                 return Ok(Connection::Secured(
-                    Self::new_secured_transport(config, tx).await?,
+                    Self::new_secured_transport(config, tx, status).await?,
                 ));
             }
         }
@@ -111,6 +252,21 @@ impl Connection {
         ))
     }
 
+    fn new_codec(config: &Config) -> error::Result<IrcCodec> {
+        let lenient = config.invalid_message_policy() != InvalidMessagePolicy::Strict;
+        let mut codec = IrcCodec::new(config.encoding())?.with_lenient_parsing(lenient);
+
+        if let Some(fallback) = config.fallback_encoding() {
+            codec = codec.with_fallback_encoding(fallback)?;
+        }
+
+        for (chan, encoding) in &config.channel_encodings {
+            codec = codec.with_target_encoding(chan, encoding)?;
+        }
+
+        Ok(codec)
+    }
+
     #[cfg(not(feature = "proxy"))]
     async fn new_stream(config: &Config) -> error::Result<TcpStream> {
         Ok(TcpStream::connect((config.server()?, config.port())).await?)
@@ -118,35 +274,7 @@ impl Connection {
 
     #[cfg(feature = "proxy")]
     async fn new_stream(config: &Config) -> error::Result<TcpStream> {
-        let server = config.server()?;
-        let port = config.port();
-        let address = (server, port);
-
-        match config.proxy_type() {
-            ProxyType::None => Ok(TcpStream::connect(address).await?),
-            ProxyType::Socks5 => {
-                let proxy_server = config.proxy_server();
-                let proxy_port = config.proxy_port();
-                let proxy = (proxy_server, proxy_port);
-
-                log::info!("Setup proxy {:?}.", proxy);
-
-                let proxy_username = config.proxy_username();
-                let proxy_password = config.proxy_password();
-                if !proxy_username.is_empty() || !proxy_password.is_empty() {
-                    return Ok(Socks5Stream::connect_with_password(
-                        proxy,
-                        address,
-                        proxy_username,
-                        proxy_password,
-                    )
-                    .await?
-                    .into_inner());
-                }
-
-                Ok(Socks5Stream::connect(proxy, address).await?.into_inner())
-            }
-        }
+        proxy::connect(config).await
     }
 
     async fn new_unsecured_transport(
@@ -154,16 +282,28 @@ impl Connection {
         tx: UnboundedSender<Message>,
     ) -> error::Result<Transport<TcpStream>> {
         let stream = Self::new_stream(config).await?;
-        let framed = Framed::new(stream, IrcCodec::new(config.encoding())?);
+        let framed = Framed::new(stream, Self::new_codec(config)?);
 
         Ok(Transport::new(config, framed, tx))
     }
 
-    #[cfg(all(feature = "tls-native", not(feature = "tls-rust")))]
+    #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
     async fn new_secured_transport(
         config: &Config,
         tx: UnboundedSender<Message>,
+        status: &StatusTracker,
     ) -> error::Result<Transport<TlsStream<TcpStream>>> {
+        let stream = Self::new_tls_stream(config, status).await?;
+        let framed = Framed::new(stream, Self::new_codec(config)?);
+
+        Ok(Transport::new(config, framed, tx))
+    }
+
+    #[cfg(all(feature = "tls-native", not(feature = "tls-rust")))]
+    async fn new_tls_stream(
+        config: &Config,
+        status: &StatusTracker,
+    ) -> error::Result<TlsStream<TcpStream>> {
         let mut builder = TlsConnector::builder();
 
         if let Some(cert_path) = config.cert_path() {
@@ -212,17 +352,15 @@ impl Connection {
         let domain = config.server()?;
 
         let stream = Self::new_stream(config).await?;
-        let stream = connector.connect(domain, stream).await?;
-        let framed = Framed::new(stream, IrcCodec::new(config.encoding())?);
-
-        Ok(Transport::new(config, framed, tx))
+        status.set(ConnectionState::TlsHandshake);
+        Ok(connector.connect(domain, stream).await?)
     }
 
     #[cfg(feature = "tls-rust")]
-    async fn new_secured_transport(
+    async fn new_tls_stream(
         config: &Config,
-        tx: UnboundedSender<Message>,
-    ) -> error::Result<Transport<TlsStream<TcpStream>>> {
+        status: &StatusTracker,
+    ) -> error::Result<TlsStream<TcpStream>> {
         struct DangerousAcceptAllVerifier;
 
         impl ServerCertVerifier for DangerousAcceptAllVerifier {
@@ -334,8 +472,32 @@ impl Connection {
         let connector = TlsConnector::from(Arc::new(tls_config));
         let domain = ServerName::try_from(config.server()?)?;
         let stream = Self::new_stream(config).await?;
-        let stream = connector.connect(domain, stream).await?;
-        let framed = Framed::new(stream, IrcCodec::new(config.encoding())?);
+        status.set(ConnectionState::TlsHandshake);
+        Ok(connector.connect(domain, stream).await?)
+    }
+
+    #[cfg(feature = "websocket")]
+    #[cfg_attr(
+        not(any(feature = "tls-native", feature = "tls-rust")),
+        allow(unused_variables)
+    )]
+    async fn new_websocket_transport(
+        config: &Config,
+        tx: UnboundedSender<Message>,
+        status: &StatusTracker,
+    ) -> error::Result<Transport<WebSocket<Box<dyn AsyncStream>>>> {
+        #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
+        let (stream, secure): (Box<dyn AsyncStream>, _) = if config.use_tls() {
+            (Box::new(Self::new_tls_stream(config, status).await?), true)
+        } else {
+            (Box::new(Self::new_stream(config).await?), false)
+        };
+        #[cfg(not(any(feature = "tls-native", feature = "tls-rust")))]
+        let (stream, secure): (Box<dyn AsyncStream>, _) =
+            (Box::new(Self::new_stream(config).await?), false);
+
+        let stream = WebSocket::connect(config, stream, secure).await?;
+        let framed = Framed::new(stream, Self::new_codec(config)?);
 
         Ok(Transport::new(config, framed, tx))
     }
@@ -361,11 +523,25 @@ impl Connection {
             })?;
 
         let stream = MockStream::new(&initial);
-        let framed = Framed::new(stream, IrcCodec::new(config.encoding())?);
+        let framed = Framed::new(stream, Self::new_codec(config)?);
 
         Ok(Transport::new(config, framed, tx))
     }
 
+    /// Gets a receiver that is notified whenever the lag measured from the connection's pings
+    /// changes.
+    pub fn lag(&self) -> watch::Receiver<Lag> {
+        match *self {
+            Connection::Unsecured(ref inner) => inner.lag(),
+            #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
+            Connection::Secured(ref inner) => inner.lag(),
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(ref inner) => inner.lag(),
+            Connection::Custom(ref inner) => inner.lag(),
+            Connection::Mock(ref inner) => inner.lag(),
+        }
+    }
+
     /// Gets a view of the internal logging if and only if this connection is using a mock stream.
     /// Otherwise, this will always return `None`. This is used for unit testing.
     pub fn log_view(&self) -> Option<LogView> {
@@ -376,16 +552,30 @@ impl Connection {
     }
 }
 
+impl Connection {
+    /// Polls for the next message, along with why it could not be parsed if it is a fallback.
+    pub(crate) fn poll_received(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<error::Result<Received>>> {
+        match self.project() {
+            ConnectionProj::Unsecured(inner) => inner.poll_received(cx),
+            #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
+            ConnectionProj::Secured(inner) => inner.poll_received(cx),
+            #[cfg(feature = "websocket")]
+            ConnectionProj::WebSocket(inner) => inner.poll_received(cx),
+            ConnectionProj::Custom(inner) => inner.poll_received(cx),
+            ConnectionProj::Mock(inner) => inner.poll_received(cx),
+        }
+    }
+}
+
 impl Stream for Connection {
     type Item = error::Result<Message>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match self.project() {
-            ConnectionProj::Unsecured(inner) => inner.poll_next(cx),
-            #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
-            ConnectionProj::Secured(inner) => inner.poll_next(cx),
-            ConnectionProj::Mock(inner) => inner.poll_next(cx),
-        }
+        self.poll_received(cx)
+            .map(|received| received.map(|received| received.map(|received| received.message)))
     }
 }
 
@@ -397,6 +587,9 @@ impl Sink<Message> for Connection {
             ConnectionProj::Unsecured(inner) => inner.poll_ready(cx),
             #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
             ConnectionProj::Secured(inner) => inner.poll_ready(cx),
+            #[cfg(feature = "websocket")]
+            ConnectionProj::WebSocket(inner) => inner.poll_ready(cx),
+            ConnectionProj::Custom(inner) => inner.poll_ready(cx),
             ConnectionProj::Mock(inner) => inner.poll_ready(cx),
         }
     }
@@ -406,6 +599,9 @@ impl Sink<Message> for Connection {
             ConnectionProj::Unsecured(inner) => inner.start_send(item),
             #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
             ConnectionProj::Secured(inner) => inner.start_send(item),
+            #[cfg(feature = "websocket")]
+            ConnectionProj::WebSocket(inner) => inner.start_send(item),
+            ConnectionProj::Custom(inner) => inner.start_send(item),
             ConnectionProj::Mock(inner) => inner.start_send(item),
         }
     }
@@ -415,6 +611,9 @@ impl Sink<Message> for Connection {
             ConnectionProj::Unsecured(inner) => inner.poll_flush(cx),
             #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
             ConnectionProj::Secured(inner) => inner.poll_flush(cx),
+            #[cfg(feature = "websocket")]
+            ConnectionProj::WebSocket(inner) => inner.poll_flush(cx),
+            ConnectionProj::Custom(inner) => inner.poll_flush(cx),
             ConnectionProj::Mock(inner) => inner.poll_flush(cx),
         }
     }
@@ -424,7 +623,44 @@ impl Sink<Message> for Connection {
             ConnectionProj::Unsecured(inner) => inner.poll_close(cx),
             #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
             ConnectionProj::Secured(inner) => inner.poll_close(cx),
+            #[cfg(feature = "websocket")]
+            ConnectionProj::WebSocket(inner) => inner.poll_close(cx),
+            ConnectionProj::Custom(inner) => inner.poll_close(cx),
             ConnectionProj::Mock(inner) => inner.poll_close(cx),
         }
     }
 }
+
+/// A `Connection` whose stream yields each message along with why it could not be parsed, so that
+/// the error is not lost when the connection is split.
+#[pin_project]
+#[derive(Debug)]
+pub(crate) struct Receiving(#[pin] pub(crate) Connection);
+
+impl Stream for Receiving {
+    type Item = error::Result<Received>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().0.poll_received(cx)
+    }
+}
+
+impl Sink<Message> for Receiving {
+    type Error = error::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().0.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.project().0.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().0.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().0.poll_close(cx)
+    }
+}
@@ -0,0 +1,538 @@
+//! Data for tracking channel information.
+// Channel state is only kept up to date when the `channel-lists` feature is enabled.
+#![cfg_attr(not(feature = "channel-lists"), allow(dead_code))]
+use std::mem;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::client::data::user::mode_char;
+use crate::client::data::{registry::UserId, PrefixMap, User};
+use crate::proto::{mode::ModeType, ChannelMode, Mode};
+
+/// IRC Channel data.
+#[derive(Clone, Debug)]
+pub struct Channel {
+    /// The channel's name.
+    name: String,
+    /// The users in the channel, as of when this copy of the channel was made.
+    users: Vec<User>,
+    /// The users in the channel, as tracked by the client.
+    members: Vec<Member>,
+    /// The channel's topic, if one is set.
+    topic: Option<String>,
+    /// The user who set the topic, if it's known.
+    topic_setter: Option<String>,
+    /// The time at which the topic was set, if it's known.
+    topic_time: Option<DateTime<Utc>>,
+    /// The channel's modes, not including list modes or user access levels.
+    modes: Vec<ChannelMode>,
+    /// The channel's key, if one is set.
+    key: Option<String>,
+    /// The channel's user limit, if one is set.
+    limit: Option<u32>,
+    /// The time at which the channel was created, if it's known.
+    creation_time: Option<DateTime<Utc>>,
+    /// The channel's ban list.
+    bans: MaskList,
+    /// The channel's ban exception list.
+    exceptions: MaskList,
+    /// The channel's invite exception list.
+    invite_exceptions: MaskList,
+}
+
+impl Channel {
+    /// Creates a new Channel with no known state.
+    pub fn new(name: &str) -> Channel {
+        Channel {
+            name: name.to_owned(),
+            users: Vec::new(),
+            members: Vec::new(),
+            topic: None,
+            topic_setter: None,
+            topic_time: None,
+            modes: Vec::new(),
+            key: None,
+            limit: None,
+            creation_time: None,
+            bans: MaskList::default(),
+            exceptions: MaskList::default(),
+            invite_exceptions: MaskList::default(),
+        }
+    }
+
+    /// Gets the name of the channel.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Gets the users in the channel.
+    pub fn users(&self) -> &[User] {
+        &self.users
+    }
+
+    /// Gets the channel's topic, if one is set.
+    pub fn topic(&self) -> Option<&str> {
+        self.topic.as_deref()
+    }
+
+    /// Gets the user who set the topic, if it's known. Depending on the server, this is either a
+    /// nickname or a full `nick!user@host` mask.
+    pub fn topic_setter(&self) -> Option<&str> {
+        self.topic_setter.as_deref()
+    }
+
+    /// Gets the time at which the topic was set, if it's known.
+    pub fn topic_time(&self) -> Option<DateTime<Utc>> {
+        self.topic_time
+    }
+
+    /// Gets the channel's modes. This does not include list modes (e.g. bans) or the modes used to
+    /// give users access levels, but does include the key and limit modes if they're set.
+    pub fn modes(&self) -> &[ChannelMode] {
+        &self.modes
+    }
+
+    /// Checks whether the specified mode is set on the channel.
+    pub fn has_mode(&self, mode: &ChannelMode) -> bool {
+        self.modes.contains(mode)
+    }
+
+    /// Gets the channel's key, if one is set.
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    /// Gets the channel's user limit, if one is set.
+    pub fn limit(&self) -> Option<u32> {
+        self.limit
+    }
+
+    /// Gets the time at which the channel was created, if it's known.
+    pub fn creation_time(&self) -> Option<DateTime<Utc>> {
+        self.creation_time
+    }
+
+    /// Gets the channel's ban list, or `None` if it hasn't been fetched.
+    pub fn bans(&self) -> Option<&[MaskEntry]> {
+        self.bans.entries.as_deref()
+    }
+
+    /// Gets the channel's ban exception list, or `None` if it hasn't been fetched.
+    pub fn exceptions(&self) -> Option<&[MaskEntry]> {
+        self.exceptions.entries.as_deref()
+    }
+
+    /// Gets the channel's invite exception list, or `None` if it hasn't been fetched.
+    pub fn invite_exceptions(&self) -> Option<&[MaskEntry]> {
+        self.invite_exceptions.entries.as_deref()
+    }
+
+    pub(crate) fn members(&self) -> &[Member] {
+        &self.members
+    }
+
+    pub(crate) fn members_mut(&mut self) -> &mut Vec<Member> {
+        &mut self.members
+    }
+
+    /// Makes a copy of the channel with its users filled in from the given user registry.
+    pub(crate) fn snapshot<F>(&self, lookup: F) -> Channel
+    where
+        F: Fn(&Member) -> Option<User>,
+    {
+        Channel {
+            users: self.members.iter().filter_map(lookup).collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Updates the topic. An empty topic means that the topic was unset.
+    pub(crate) fn set_topic(&mut self, topic: &str) {
+        self.topic = if topic.is_empty() {
+            None
+        } else {
+            Some(topic.to_owned())
+        };
+    }
+
+    /// Updates who set the topic, and when.
+    pub(crate) fn set_topic_setter(&mut self, setter: Option<&str>, time: Option<DateTime<Utc>>) {
+        self.topic_setter = setter.map(|s| s.to_owned());
+        self.topic_time = time;
+    }
+
+    pub(crate) fn set_creation_time(&mut self, time: Option<DateTime<Utc>>) {
+        self.creation_time = time;
+    }
+
+    /// Replaces the channel's modes with the given ones, e.g. from `RPL_CHANNELMODEIS`.
+    pub(crate) fn reset_modes(&mut self, modes: &[Mode<ChannelMode>], prefix_map: &PrefixMap) {
+        self.modes.clear();
+        self.key = None;
+        self.limit = None;
+        for mode in modes {
+            self.update_mode(mode, None, prefix_map);
+        }
+    }
+
+    /// Applies a single mode change to the channel. Modes that give users access levels are left
+    /// to the caller.
+    pub(crate) fn update_mode(
+        &mut self,
+        mode: &Mode<ChannelMode>,
+        setter: Option<&str>,
+        prefix_map: &PrefixMap,
+    ) {
+        let (plus, mode, arg) = match mode {
+            Mode::Plus(mode, arg) => (true, mode, arg.as_deref()),
+            Mode::Minus(mode, arg) => (false, mode, arg.as_deref()),
+            Mode::NoPrefix(_) => return,
+        };
+        if mode_char(mode).and_then(|c| prefix_map.prefix(c)).is_some() {
+            return;
+        }
+
+        match (mode, arg) {
+            (ChannelMode::Ban, Some(mask)) => self.bans.update(plus, mask, setter),
+            (ChannelMode::Exception, Some(mask)) => self.exceptions.update(plus, mask, setter),
+            (ChannelMode::InviteException, Some(mask)) => {
+                self.invite_exceptions.update(plus, mask, setter)
+            }
+            // Without an argument, these are queries for the list rather than changes to it.
+            (ChannelMode::Ban, None)
+            | (ChannelMode::Exception, None)
+            | (ChannelMode::InviteException, None) => (),
+            (ChannelMode::Key, key) => {
+                self.key = if plus {
+                    key.map(|k| k.to_owned())
+                } else {
+                    None
+                }
+            }
+            (ChannelMode::Limit, limit) => {
+                self.limit = if plus {
+                    limit.and_then(|l| l.parse().ok())
+                } else {
+                    None
+                }
+            }
+            _ => (),
+        }
+
+        if matches!(
+            mode,
+            ChannelMode::Ban | ChannelMode::Exception | ChannelMode::InviteException
+        ) {
+            return;
+        }
+
+        if plus {
+            if !self.modes.contains(mode) {
+                self.modes.push(mode.clone());
+            }
+        } else {
+            self.modes.retain(|m| m != mode);
+        }
+    }
+
+    /// Gets the list kept for the specified list mode, if it is one.
+    fn mask_list_mut(&mut self, mode: &ChannelMode) -> Option<&mut MaskList> {
+        match *mode {
+            ChannelMode::Ban => Some(&mut self.bans),
+            ChannelMode::Exception => Some(&mut self.exceptions),
+            ChannelMode::InviteException => Some(&mut self.invite_exceptions),
+            _ => None,
+        }
+    }
+
+    /// Adds an entry from a list reply (e.g. `RPL_BANLIST`) to the list being received.
+    pub(crate) fn add_mask_entry(&mut self, mode: &ChannelMode, entry: MaskEntry) {
+        if let Some(list) = self.mask_list_mut(mode) {
+            list.incoming.push(entry);
+        }
+    }
+
+    /// Finishes receiving a list (e.g. on `RPL_ENDOFBANLIST`), replacing the previous list.
+    pub(crate) fn end_mask_list(&mut self, mode: &ChannelMode) {
+        if let Some(list) = self.mask_list_mut(mode) {
+            list.entries = Some(mem::take(&mut list.incoming));
+        }
+    }
+}
+
+/// A user's membership in a channel, which points into the client's user registry.
+#[derive(Clone, Debug)]
+pub(crate) struct Member {
+    /// The user's identifier in the registry.
+    pub(crate) id: UserId,
+    /// The user's access levels in the channel. Everything else about the user is kept in the
+    /// registry.
+    pub(crate) access: User,
+}
+
+/// One of the lists of masks that a channel keeps, as fetched with
+/// [`Client::fetch_channel_list`](crate::client::Client::fetch_channel_list).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ListMode {
+    /// The ban list (`+b`).
+    Ban,
+    /// The ban exception list (`+e`).
+    Exception,
+    /// The invite exception list (`+I`).
+    InviteException,
+}
+
+impl From<ListMode> for ChannelMode {
+    fn from(list: ListMode) -> ChannelMode {
+        match list {
+            ListMode::Ban => ChannelMode::Ban,
+            ListMode::Exception => ChannelMode::Exception,
+            ListMode::InviteException => ChannelMode::InviteException,
+        }
+    }
+}
+
+/// An entry in a channel's ban, ban exception, or invite exception list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaskEntry {
+    /// The mask matched by this entry.
+    mask: String,
+    /// The user who set this entry, if it's known.
+    set_by: Option<String>,
+    /// The time at which this entry was set, if it's known.
+    set_at: Option<DateTime<Utc>>,
+}
+
+impl MaskEntry {
+    /// Creates a new MaskEntry.
+    pub fn new(mask: &str, set_by: Option<&str>, set_at: Option<DateTime<Utc>>) -> MaskEntry {
+        MaskEntry {
+            mask: mask.to_owned(),
+            set_by: set_by.map(|s| s.to_owned()),
+            set_at,
+        }
+    }
+
+    /// Gets the mask matched by this entry.
+    pub fn mask(&self) -> &str {
+        &self.mask
+    }
+
+    /// Gets the user who set this entry, if it's known.
+    pub fn set_by(&self) -> Option<&str> {
+        self.set_by.as_deref()
+    }
+
+    /// Gets the time at which this entry was set, if it's known.
+    pub fn set_at(&self) -> Option<DateTime<Utc>> {
+        self.set_at
+    }
+}
+
+/// A list of masks that is only known once it has been fetched from the server.
+#[derive(Clone, Debug, Default)]
+struct MaskList {
+    /// The entries in the list, if it has been fetched.
+    entries: Option<Vec<MaskEntry>>,
+    /// The entries received so far while the list is being fetched.
+    incoming: Vec<MaskEntry>,
+}
+
+impl MaskList {
+    /// Applies a mode change to the list, if it has been fetched.
+    fn update(&mut self, plus: bool, mask: &str, setter: Option<&str>) {
+        if let Some(entries) = self.entries.as_mut() {
+            entries.retain(|entry| entry.mask != mask);
+            if plus {
+                entries.push(MaskEntry::new(mask, setter, Some(Utc::now())));
+            }
+        }
+    }
+}
+
+/// Parses channel modes (a mode string followed by its arguments) using the server's PREFIX and
+/// CHANMODES tokens, which say which modes take an argument. Without these, the parser has to
+/// guess for modes specific to the server (e.g. a `Y` prefix), and the arguments it can't place
+/// are dropped or handed to the wrong modes.
+pub(crate) fn parse_modes(
+    pieces: &[&str],
+    prefix_map: &PrefixMap,
+    chanmodes: Option<&str>,
+) -> Vec<Mode<ChannelMode>> {
+    let (first, rest) = match pieces.split_first() {
+        Some(split) => split,
+        None => return Vec::new(),
+    };
+    // These are queries rather than changes, so there are no arguments to place.
+    if !first.starts_with(['+', '-']) {
+        return Mode::as_channel_modes(pieces).unwrap_or_default();
+    }
+
+    // CHANMODES lists modes that always take an argument, that always take an argument, that take
+    // an argument only when set, and that never take an argument.
+    let types: Vec<_> = chanmodes.map_or(vec![], |c| c.split(',').collect());
+    let mut args = rest.iter();
+    let mut plus = true;
+    let mut modes = Vec::new();
+    for c in first.chars() {
+        match c {
+            '+' => plus = true,
+            '-' => plus = false,
+            c => {
+                let mode = ChannelMode::from_char(c);
+                let takes_arg = if prefix_map.prefix(c).is_some() {
+                    true
+                } else {
+                    match types.iter().position(|t| t.contains(c)) {
+                        Some(0) | Some(1) => true,
+                        Some(2) => plus,
+                        Some(_) => false,
+                        None => mode.takes_arg(),
+                    }
+                };
+                let arg = if takes_arg {
+                    args.next().map(|arg| arg.to_string())
+                } else {
+                    None
+                };
+                modes.push(if plus {
+                    Mode::Plus(mode, arg)
+                } else {
+                    Mode::Minus(mode, arg)
+                });
+            }
+        }
+    }
+    modes
+}
+
+/// Parses a timestamp in seconds since the Unix epoch, as sent by the server.
+pub(crate) fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(timestamp.parse().ok()?, 0).single()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_modes, parse_timestamp, Channel, MaskEntry};
+    use crate::client::data::PrefixMap;
+    use crate::proto::ChannelMode as M;
+    use crate::proto::Mode::*;
+
+    #[test]
+    fn update_modes() {
+        let mut chan = Channel::new("#test");
+        chan.update_mode(&Plus(M::Moderated, None), None, &PrefixMap::default());
+        chan.update_mode(
+            &Plus(M::Key, Some("secret".to_owned())),
+            None,
+            &PrefixMap::default(),
+        );
+        chan.update_mode(
+            &Plus(M::Limit, Some("10".to_owned())),
+            None,
+            &PrefixMap::default(),
+        );
+        chan.update_mode(
+            &Plus(M::Oper, Some("test".to_owned())),
+            None,
+            &PrefixMap::default(),
+        );
+        assert_eq!(chan.modes(), &[M::Moderated, M::Key, M::Limit]);
+        assert_eq!(chan.key(), Some("secret"));
+        assert_eq!(chan.limit(), Some(10));
+        chan.update_mode(
+            &Minus(M::Key, Some("secret".to_owned())),
+            None,
+            &PrefixMap::default(),
+        );
+        chan.update_mode(&Minus(M::Moderated, None), None, &PrefixMap::default());
+        assert_eq!(chan.modes(), &[M::Limit]);
+        assert_eq!(chan.key(), None);
+    }
+
+    #[test]
+    fn reset_modes() {
+        let mut chan = Channel::new("#test");
+        chan.update_mode(&Plus(M::Moderated, None), None, &PrefixMap::default());
+        chan.reset_modes(
+            &[
+                Plus(M::NoExternalMessages, None),
+                Plus(M::ProtectedTopic, None),
+            ],
+            &PrefixMap::default(),
+        );
+        assert_eq!(chan.modes(), &[M::NoExternalMessages, M::ProtectedTopic]);
+    }
+
+    #[test]
+    fn mask_lists() {
+        let mut chan = Channel::new("#test");
+        chan.update_mode(
+            &Plus(M::Ban, Some("*!*@spam".to_owned())),
+            None,
+            &PrefixMap::default(),
+        );
+        assert_eq!(chan.bans(), None);
+
+        chan.add_mask_entry(&M::Ban, MaskEntry::new("*!*@evil", Some("op"), None));
+        assert_eq!(chan.bans(), None);
+        chan.end_mask_list(&M::Ban);
+        assert_eq!(chan.bans().map(|bans| bans.len()), Some(1));
+        assert_eq!(chan.exceptions(), None);
+
+        chan.update_mode(
+            &Plus(M::Ban, Some("*!*@spam".to_owned())),
+            Some("op"),
+            &PrefixMap::default(),
+        );
+        chan.update_mode(
+            &Minus(M::Ban, Some("*!*@evil".to_owned())),
+            Some("op"),
+            &PrefixMap::default(),
+        );
+        let bans = chan.bans().unwrap();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].mask(), "*!*@spam");
+        assert_eq!(bans[0].set_by(), Some("op"));
+        assert!(chan.modes().is_empty());
+    }
+
+    #[test]
+    fn parse_with_server_modes() {
+        let prefix_map = PrefixMap::new("(Yov)!@+").unwrap();
+        assert_eq!(
+            parse_modes(
+                &["+Y-l+vf", "alice", "bob"],
+                &prefix_map,
+                Some("beI,kf,l,imnst")
+            ),
+            vec![
+                Plus(M::Unknown('Y'), Some("alice".to_owned())),
+                Minus(M::Limit, None),
+                Plus(M::Voice, Some("bob".to_owned())),
+                Plus(M::Unknown('f'), None),
+            ]
+        );
+        assert_eq!(
+            parse_modes(&["+CYk", "key", "a"], &prefix_map, Some("beI,k,l,Cimnst")),
+            vec![
+                Plus(M::Unknown('C'), None),
+                Plus(M::Unknown('Y'), Some("key".to_owned())),
+                Plus(M::Key, Some("a".to_owned())),
+            ]
+        );
+        assert_eq!(
+            parse_modes(&["b"], &prefix_map, None),
+            vec![NoPrefix(M::Ban)]
+        );
+    }
+
+    #[test]
+    fn parse_timestamps() {
+        assert_eq!(
+            parse_timestamp("1234567890").map(|t| t.timestamp()),
+            Some(1234567890)
+        );
+        assert_eq!(parse_timestamp("not a timestamp"), None);
+    }
+}
@@ -147,10 +147,37 @@ pub struct Config {
     #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
     #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub dangerously_accept_invalid_certs: Option<bool>,
+    /// Whether or not to connect over a WebSocket (`ws://`, or `wss://` with TLS) instead of
+    /// plain TCP, for servers that only expose the IRCv3 WebSocket endpoint.
+    #[cfg(feature = "websocket")]
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub use_websocket: Option<bool>,
+    /// The path of the WebSocket endpoint on the server.
+    #[cfg(feature = "websocket")]
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub websocket_path: Option<String>,
     /// The encoding type used for this connection.
     /// This is typically UTF-8, but could be something else.
     #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub encoding: Option<String>,
+    /// The encoding used to decode lines that are not valid in `encoding`, e.g. `windows-1252`,
+    /// `koi8-r`, or `iso-2022-jp`. Each line is first decoded with `encoding` and only falls back
+    /// to this encoding if that fails, except that lines containing ISO-2022 escape sequences go
+    /// straight to an ISO-2022 fallback, since they would otherwise pass as UTF-8.
+    ///
+    /// The client only ever hands out decoded lines. Anyone who needs the raw bytes of each line
+    /// has to frame the connection with [`RawLineCodec`](crate::proto::line::RawLineCodec)
+    /// themselves.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub fallback_encoding: Option<String>,
+    /// A mapping of channel names to the encodings used for messages sent to them. Channel names
+    /// are compared ignoring ASCII case.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "HashMap::is_empty"))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub channel_encodings: HashMap<String, String>,
+    /// How to handle lines from the server that cannot be parsed as messages.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub invalid_message_policy: Option<InvalidMessagePolicy>,
     /// A list of channels to join on connection.
     #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Vec::is_empty"))]
     #[cfg_attr(feature = "serde", serde(default))]
@@ -196,6 +223,51 @@ pub struct Config {
     #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     #[cfg_attr(feature = "serde", serde(default))]
     pub ghost_sequence: Option<Vec<String>>,
+    /// Whether the client should generate nicknames from its primary nickname once all of the
+    /// alternative nicknames are in use, rather than failing to register.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "is_false"))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub generate_nicks: bool,
+    /// Whether the client should switch back to its primary nickname once it becomes available
+    /// after registering with another one. This uses MONITOR where the server supports it, and
+    /// polls with ISON otherwise.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "is_false"))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub regain_nick: bool,
+    /// The amount of time in seconds between ISON polls, which are used for the primary nickname
+    /// when regaining it and for watched users whose presence can't be tracked with MONITOR.
+    /// `regain_interval` is still accepted as a deprecated name for this setting.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    #[cfg_attr(feature = "serde", serde(alias = "regain_interval"))]
+    pub ison_interval: Option<u32>,
+    /// The amount of time in seconds to wait for the server to answer a query like
+    /// [`Client::whois`](crate::client::Client::whois) before giving up.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub query_timeout: Option<u32>,
+    /// Whether the client stream should leave out the echoes of our own messages when the server
+    /// has enabled `echo-message`. The echoes are still used to confirm deliveries.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "is_false"))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub suppress_echoes: bool,
+    /// The amount of time in seconds to wait for the server to accept our registration in
+    /// [`Client::register`](crate::client::Client::register) before giving up.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub registration_timeout: Option<u32>,
+    /// The amount of time in seconds to wait for the server to close the connection after
+    /// [`Client::quit`](crate::client::Client::quit) sends a `QUIT`, before closing it ourselves.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub quit_timeout: Option<u32>,
+    /// The amount of lag in seconds after which the client considers the connection to be lagging
+    /// (see [`Client::lag`](crate::client::Client::lag)). This should be lower than `ping_timeout`
+    /// in order to warn about the lag before the connection times out.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub lag_warning: Option<u32>,
+    /// The maximum number of messages waiting to be sent, beyond which
+    /// [`Sender::send_async`](crate::client::Sender::send_async) waits and
+    /// [`Sender::try_send`](crate::client::Sender::try_send) fails. The queue is unbounded when
+    /// not specified.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub outgoing_queue_capacity: Option<u32>,
     /// Whether or not to use a fake connection for testing purposes. You probably will never want
     /// to enable this, but it is used in unit testing for the `irc` crate.
     #[cfg_attr(feature = "serde", serde(skip_serializing_if = "is_false"))]
@@ -223,6 +295,22 @@ pub struct Config {
     pub path: Option<PathBuf>,
 }
 
+/// A policy for handling lines from the server that cannot be parsed as messages.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum InvalidMessagePolicy {
+    /// End the stream with an `InvalidMessage` error.
+    #[default]
+    Strict,
+    /// Log the line and skip it.
+    Skip,
+    /// Deliver the line as a `Command::Raw` fallback, whose parse error can be had from
+    /// [`ClientStream::parse_error`](crate::client::ClientStream::parse_error), or from the
+    /// [`BatchItem::Invalid`](crate::client::batch::BatchItem::Invalid) and
+    /// [`Event::Invalid`](crate::client::event::Event::Invalid) items.
+    Raw,
+}
+
 #[cfg(feature = "serde")]
 fn is_false(v: &bool) -> bool {
     !v
@@ -527,6 +615,20 @@ impl Config {
             .unwrap_or(false)
     }
 
+    /// Gets whether or not to connect over a WebSocket.
+    /// This defaults to `false` when not specified.
+    #[cfg(feature = "websocket")]
+    pub fn use_websocket(&self) -> bool {
+        self.use_websocket.as_ref().cloned().unwrap_or(false)
+    }
+
+    /// Gets the path of the WebSocket endpoint on the server.
+    /// This defaults to `/` when not specified.
+    #[cfg(feature = "websocket")]
+    pub fn websocket_path(&self) -> &str {
+        self.websocket_path.as_ref().map_or("/", String::as_str)
+    }
+
     /// Gets the path to the client authentication certificate in DER format if specified.
     #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
     pub fn client_cert_path(&self) -> Option<&str> {
@@ -545,6 +647,17 @@ impl Config {
         self.encoding.as_ref().map_or("UTF-8", |s| s)
     }
 
+    /// Gets the encoding used to decode lines that are not valid in the main encoding, if any.
+    pub fn fallback_encoding(&self) -> Option<&str> {
+        self.fallback_encoding.as_deref()
+    }
+
+    /// Gets the policy for handling lines that cannot be parsed as messages.
+    /// This defaults to `InvalidMessagePolicy::Strict` when not specified.
+    pub fn invalid_message_policy(&self) -> InvalidMessagePolicy {
+        self.invalid_message_policy.unwrap_or_default()
+    }
+
     /// Gets the channels to join upon connection.
     /// This defaults to an empty vector if it's not specified.
     pub fn channels(&self) -> &[String] {
@@ -625,6 +738,67 @@ impl Config {
         self.ghost_sequence.as_deref()
     }
 
+    /// Gets whether or not to generate nicknames once all of the alternative nicknames are in use.
+    /// This defaults to `false` when not specified.
+    pub fn generate_nicks(&self) -> bool {
+        self.generate_nicks
+    }
+
+    /// Gets whether or not to regain the primary nickname once it becomes available.
+    /// This defaults to `false` when not specified.
+    pub fn regain_nick(&self) -> bool {
+        self.regain_nick
+    }
+
+    /// Gets the amount of time in seconds between ISON polls.
+    /// This defaults to 60 seconds when not specified.
+    pub fn ison_interval(&self) -> u32 {
+        self.ison_interval.as_ref().cloned().unwrap_or(60)
+    }
+
+    /// Gets the amount of time in seconds between ISON polls.
+    /// This defaults to 60 seconds when not specified.
+    #[deprecated(note = "renamed to `ison_interval`")]
+    pub fn regain_interval(&self) -> u32 {
+        self.ison_interval()
+    }
+
+    /// Gets the amount of time in seconds to wait for the server to answer a query.
+    /// This defaults to 30 seconds when not specified.
+    pub fn query_timeout(&self) -> u32 {
+        self.query_timeout.as_ref().cloned().unwrap_or(30)
+    }
+
+    /// Gets the amount of time in seconds to wait for the server to accept our registration.
+    /// This defaults to 60 seconds when not specified.
+    pub fn registration_timeout(&self) -> u32 {
+        self.registration_timeout.as_ref().cloned().unwrap_or(60)
+    }
+
+    /// Gets the amount of time in seconds to wait for the server to close the connection after
+    /// quitting. This defaults to 10 seconds when not specified.
+    pub fn quit_timeout(&self) -> u32 {
+        self.quit_timeout.as_ref().cloned().unwrap_or(10)
+    }
+
+    /// Gets the amount of lag in seconds after which the connection is considered to be lagging.
+    /// There is no threshold when not specified.
+    pub fn lag_warning(&self) -> Option<u32> {
+        self.lag_warning
+    }
+
+    /// Gets the maximum number of messages waiting to be sent.
+    /// The queue is unbounded when not specified.
+    pub fn outgoing_queue_capacity(&self) -> Option<u32> {
+        self.outgoing_queue_capacity
+    }
+
+    /// Gets whether or not to leave the echoes of our own messages out of the client stream.
+    /// This defaults to `false` when not specified.
+    pub fn suppress_echoes(&self) -> bool {
+        self.suppress_echoes
+    }
+
     /// Looks up the specified string in the options map.
     pub fn get_option(&self, option: &str) -> Option<&str> {
         self.options.get(option).map(String::as_str)
@@ -721,6 +895,14 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    #[cfg(feature = "toml_config")]
+    fn deprecated_regain_interval() -> Result<()> {
+        let config = Config::load_toml("regain.toml", "regain_interval = 30")?;
+        assert_eq!(config.ison_interval(), 30);
+        Ok(())
+    }
+
     #[test]
     #[cfg(feature = "yaml_config")]
     fn load_from_yaml() -> Result<()> {
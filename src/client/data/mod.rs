@@ -1,11 +1,14 @@
 //! Data related to IRC functionality.
 
-pub use crate::client::data::config::Config;
+pub use crate::client::data::channel::{Channel, ListMode, MaskEntry};
+pub use crate::client::data::config::{Config, InvalidMessagePolicy};
 #[cfg(feature = "proxy")]
 pub use crate::client::data::proxy::ProxyType;
-pub use crate::client::data::user::{AccessLevel, User};
+pub use crate::client::data::user::{AccessLevel, PrefixMap, User};
 
+pub mod channel;
 pub mod config;
 #[cfg(feature = "proxy")]
 pub mod proxy;
+pub(crate) mod registry;
 pub mod user;
@@ -30,4 +30,12 @@ pub enum ProxyType {
     /// Use a SOCKS5 proxy.
     /// DNS queries are also sent via the proxy.
     Socks5,
+
+    /// Use a SOCKS4 proxy, identifying with the proxy username if there is one.
+    /// DNS queries are also sent via the proxy (i.e. SOCKS4a), unless the server is an IP address.
+    Socks4,
+
+    /// Use an HTTP proxy, which tunnels the connection through `CONNECT`.
+    /// Basic authentication is used if there is a proxy username or password.
+    Http,
 }
@@ -0,0 +1,367 @@
+//! A network-wide registry of the channels and users known to the client.
+// The registry is only kept up to date when the `channel-lists` feature is enabled.
+#![cfg_attr(not(feature = "channel-lists"), allow(dead_code))]
+use std::collections::{HashMap, HashSet};
+
+use crate::client::data::channel::Member;
+use crate::client::data::{Channel, PrefixMap, User};
+use crate::proto::{ChannelMode, Mode};
+
+/// An identifier for a user in the registry, which stays the same across nickname changes.
+pub(crate) type UserId = usize;
+
+/// A user in the registry, along with the channels we share with them.
+#[derive(Debug)]
+struct Entry {
+    user: User,
+    channels: HashSet<String>,
+}
+
+/// The channels we're in and the users we share them with. Channel memberships point into a single
+/// table of users, so that changes to a user (e.g. a new nickname) only need to be made once.
+#[derive(Debug, Default)]
+pub(crate) struct Registry {
+    /// The channels we're in, by name.
+    channels: HashMap<String, Channel>,
+    /// The users we know about, by identifier.
+    users: HashMap<UserId, Entry>,
+    /// The identifiers of the users we know about, by lowercase nickname.
+    nicknames: HashMap<String, UserId>,
+    /// The next identifier to hand out.
+    next_id: UserId,
+    /// The fields requested by our outstanding WHOX queries, by query token.
+    whox_fields: HashMap<String, String>,
+}
+
+impl Registry {
+    /// Gets the names of the channels we're in.
+    pub(crate) fn channel_names(&self) -> Vec<String> {
+        self.channels.keys().cloned().collect()
+    }
+
+    /// Gets a copy of the specified channel, including its users.
+    pub(crate) fn channel(&self, chan: &str) -> Option<Channel> {
+        self.channels
+            .get(chan)
+            .map(|channel| channel.snapshot(|member| self.member(member)))
+    }
+
+    /// Gets the users in the specified channel.
+    pub(crate) fn channel_users(&self, chan: &str) -> Option<Vec<User>> {
+        self.channels.get(chan).map(|channel| {
+            channel
+                .members()
+                .iter()
+                .filter_map(|member| self.member(member))
+                .collect()
+        })
+    }
+
+    /// Gets the specified channel for updating it.
+    pub(crate) fn channel_mut(&mut self, chan: &str) -> Option<&mut Channel> {
+        self.channels.get_mut(chan)
+    }
+
+    /// Gets a copy of the specified user.
+    pub(crate) fn user(&self, nickname: &str) -> Option<User> {
+        self.id(nickname)
+            .and_then(|id| self.users.get(&id))
+            .map(|entry| entry.user.clone())
+    }
+
+    /// Updates the specified user, if we know about them.
+    pub(crate) fn update_user<F>(&mut self, nickname: &str, update: F)
+    where
+        F: FnOnce(&mut User),
+    {
+        if let Some(entry) = self.id(nickname).and_then(|id| self.users.get_mut(&id)) {
+            update(&mut entry.user)
+        }
+    }
+
+    /// Starts tracking a channel that we've just joined, forgetting anything we knew about it.
+    pub(crate) fn add_channel(&mut self, chan: &str) {
+        self.remove_channel(chan);
+        self.channels.insert(chan.to_owned(), Channel::new(chan));
+    }
+
+    /// Stops tracking a channel that we've left, along with any users we no longer share a
+    /// channel with.
+    pub(crate) fn remove_channel(&mut self, chan: &str) {
+        if let Some(channel) = self.channels.remove(chan) {
+            for member in channel.members() {
+                self.leave(member.id, chan);
+            }
+        }
+    }
+
+    /// Adds a user to a channel, e.g. because they joined it or were listed in a NAMES reply.
+    /// Channels we aren't tracking yet are created, as NAMES replies may arrive without a JOIN.
+    pub(crate) fn add_member(&mut self, chan: &str, user: User) {
+        let id = self.id(user.get_nickname()).unwrap_or_else(|| {
+            let id = self.next_id;
+            self.next_id += 1;
+            self.nicknames.insert(key(user.get_nickname()), id);
+            self.users.insert(
+                id,
+                Entry {
+                    user: User::new(user.get_nickname()),
+                    channels: HashSet::new(),
+                },
+            );
+            id
+        });
+
+        let entry = self
+            .users
+            .get_mut(&id)
+            .expect("registry entry for nickname");
+        if let Some(username) = user.get_username() {
+            entry.user.set_username(username);
+        }
+        if let Some(hostname) = user.get_hostname() {
+            entry.user.set_hostname(hostname);
+        }
+        entry.channels.insert(chan.to_owned());
+
+        let members = self
+            .channels
+            .entry(chan.to_owned())
+            .or_insert_with(|| Channel::new(chan))
+            .members_mut();
+        match members.iter_mut().find(|member| member.id == id) {
+            Some(member) => member.access = user,
+            None => members.push(Member { id, access: user }),
+        }
+    }
+
+    /// Removes a user from a channel, e.g. because they left it or were kicked.
+    pub(crate) fn remove_member(&mut self, chan: &str, nickname: &str) {
+        let id = match self.id(nickname) {
+            Some(id) => id,
+            None => return,
+        };
+        if let Some(channel) = self.channels.get_mut(chan) {
+            let members = channel.members_mut();
+            if let Some(n) = members.iter().position(|member| member.id == id) {
+                members.swap_remove(n);
+                self.leave(id, chan);
+            }
+        }
+    }
+
+    /// Removes a user from every channel, e.g. because they quit.
+    pub(crate) fn remove_user(&mut self, nickname: &str) {
+        let id = match self.id(nickname) {
+            Some(id) => id,
+            None => return,
+        };
+        if let Some(entry) = self.users.remove(&id) {
+            for chan in &entry.channels {
+                if let Some(channel) = self.channels.get_mut(chan) {
+                    let members = channel.members_mut();
+                    if let Some(n) = members.iter().position(|member| member.id == id) {
+                        members.swap_remove(n);
+                    }
+                }
+            }
+            self.nicknames.remove(&key(entry.user.get_nickname()));
+        }
+    }
+
+    /// Renames a user. Whoever we thought had the new nickname must have left without us
+    /// noticing, so they are forgotten.
+    pub(crate) fn rename_user(&mut self, old_nick: &str, new_nick: &str) {
+        if let Some(id) = self.nicknames.remove(&key(old_nick)) {
+            if self.id(new_nick).is_some_and(|displaced| displaced != id) {
+                self.remove_user(new_nick);
+            }
+            self.nicknames.insert(key(new_nick), id);
+            if let Some(entry) = self.users.get_mut(&id) {
+                entry.user.set_nickname(new_nick);
+            }
+        }
+    }
+
+    /// Applies mode changes to a channel, including the access levels of its members.
+    pub(crate) fn update_modes(
+        &mut self,
+        chan: &str,
+        modes: &[Mode<ChannelMode>],
+        setter: Option<&str>,
+        prefix_map: &PrefixMap,
+    ) {
+        let nicknames = &self.nicknames;
+        if let Some(channel) = self.channels.get_mut(chan) {
+            for mode in modes {
+                channel.update_mode(mode, setter, prefix_map);
+                match *mode {
+                    Mode::Plus(_, Some(ref user)) | Mode::Minus(_, Some(ref user)) => {
+                        let id = nicknames.get(&key(user));
+                        if let Some(member) = channel
+                            .members_mut()
+                            .iter_mut()
+                            .find(|member| Some(&member.id) == id)
+                        {
+                            member.access.update_access_level_with(mode, prefix_map)
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Remembers the fields requested by a WHOX query (e.g. `%tcuhnfar,42`) so that its replies
+    /// can be understood.
+    pub(crate) fn add_whox_query(&mut self, spec: &str) {
+        let (fields, token) = spec.split_once(',').unwrap_or((spec, ""));
+        self.whox_fields
+            .insert(token.to_owned(), fields.trim_start_matches('%').to_owned());
+    }
+
+    /// Updates a user from a `RPL_WHOSPCRPL` (354) reply, given its arguments after our nickname.
+    pub(crate) fn handle_whox_reply(&mut self, args: &[String]) {
+        // If a token was requested, it's always the first field of the reply.
+        let token = args.first().map(|s| &s[..]).unwrap_or("");
+        let fields = match self
+            .whox_fields
+            .get(token)
+            .filter(|fields| fields.contains('t'))
+            .or_else(|| self.whox_fields.get(""))
+        {
+            Some(fields) => fields.clone(),
+            None => return,
+        };
+
+        // Replies contain the requested fields in this order, regardless of the request's order.
+        let values: HashMap<char, &str> = "tcuihsnfdlaor"
+            .chars()
+            .filter(|c| fields.contains(*c))
+            .zip(args.iter().map(|s| &s[..]))
+            .collect();
+        if let Some(nickname) = values.get(&'n') {
+            self.update_user(nickname, |user| {
+                if let Some(username) = values.get(&'u') {
+                    user.set_username(username);
+                }
+                if let Some(hostname) = values.get(&'h') {
+                    user.set_hostname(hostname);
+                }
+                if let Some(flags) = values.get(&'f') {
+                    let message = user.away_message().map(|m| m.to_owned());
+                    user.set_away(flags.starts_with('G'), message.as_deref());
+                }
+                if let Some(account) = values.get(&'a') {
+                    user.set_account(account);
+                }
+                if let Some(realname) = values.get(&'r') {
+                    user.set_realname(realname);
+                }
+            });
+        }
+    }
+
+    /// Gets a user's identifier from their nickname.
+    fn id(&self, nickname: &str) -> Option<UserId> {
+        self.nicknames.get(&key(nickname)).cloned()
+    }
+
+    /// Gets a copy of a channel member, including their access levels in that channel.
+    fn member(&self, member: &Member) -> Option<User> {
+        self.users
+            .get(&member.id)
+            .map(|entry| entry.user.with_access_levels_of(&member.access))
+    }
+
+    /// Records that a user is no longer in a channel, and forgets about them if we no longer share
+    /// any channels with them.
+    fn leave(&mut self, id: UserId, chan: &str) {
+        if let Some(entry) = self.users.get_mut(&id) {
+            entry.channels.remove(chan);
+            if entry.channels.is_empty() {
+                let nickname = key(entry.user.get_nickname());
+                self.users.remove(&id);
+                self.nicknames.remove(&nickname);
+            }
+        }
+    }
+}
+
+/// Gets the key used to look up a nickname, since nicknames are case-insensitive.
+fn key(nickname: &str) -> String {
+    nickname.to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::Registry;
+    use crate::client::data::User;
+
+    #[test]
+    fn rename_and_quit() {
+        let mut registry = Registry::default();
+        registry.add_member("#test", User::new("@alice!a@host"));
+        registry.add_member("#test2", User::new("alice"));
+        registry.rename_user("alice", "Bob");
+        assert_eq!(registry.user("bob"), Some(User::new("Bob!a@host")));
+        let users = registry.channel_users("#test").unwrap();
+        assert_eq!(users[0].get_nickname(), "Bob");
+        assert_eq!(users[0].access_levels().len(), 2);
+        assert_eq!(
+            registry.channel_users("#test2").unwrap()[0].get_nickname(),
+            "Bob"
+        );
+
+        registry.remove_user("BOB");
+        assert_eq!(registry.user("bob"), None);
+        assert!(registry.channel_users("#test").unwrap().is_empty());
+        assert!(registry.channel_users("#test2").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rename_over_stale_user() {
+        let mut registry = Registry::default();
+        registry.add_member("#test", User::new("alice"));
+        registry.add_member("#test", User::new("bob"));
+        registry.add_member("#test2", User::new("bob"));
+        registry.rename_user("alice", "Bob");
+        assert_eq!(registry.user("bob"), Some(User::new("Bob")));
+        let users = registry.channel_users("#test").unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].get_nickname(), "Bob");
+        assert!(registry.channel_users("#test2").unwrap().is_empty());
+        assert_eq!(registry.users.len(), 1);
+    }
+
+    #[test]
+    fn forget_users_without_shared_channels() {
+        let mut registry = Registry::default();
+        registry.add_member("#test", User::new("alice"));
+        registry.add_member("#test2", User::new("alice"));
+        registry.remove_member("#test", "alice");
+        assert!(registry.user("alice").is_some());
+        registry.remove_channel("#test2");
+        assert!(registry.user("alice").is_none());
+    }
+
+    #[test]
+    fn whox_reply() {
+        let mut registry = Registry::default();
+        registry.add_member("#test", User::new("alice"));
+        registry.add_whox_query("%tcuhnfar,42");
+        let args: Vec<_> = [
+            "42", "#test", "a", "host", "alice", "G@", "acct", "Alice A.",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        registry.handle_whox_reply(&args);
+        let user = registry.user("alice").unwrap();
+        assert_eq!(user.get_username(), Some("a"));
+        assert_eq!(user.get_hostname(), Some("host"));
+        assert_eq!(user.account(), Some("acct"));
+        assert_eq!(user.realname(), Some("Alice A."));
+        assert!(user.is_away());
+    }
+}
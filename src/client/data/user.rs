@@ -15,6 +15,16 @@ pub struct User {
     username: Option<String>,
     /// The user's hostname.
     hostname: Option<String>,
+    /// The user's real name.
+    realname: Option<String>,
+    /// The account the user is logged into.
+    account: Option<String>,
+    /// Whether the user is away.
+    away: bool,
+    /// The user's away message.
+    away_message: Option<String>,
+    /// The user's current prefixes (e.g. `@`), ordered from highest to lowest rank.
+    prefixes: String,
     /// The user's highest access level.
     highest_access_level: AccessLevel,
     /// All of the user's current access levels.
@@ -24,8 +34,23 @@ pub struct User {
 impl User {
     /// Creates a new User.
     pub fn new(string: &str) -> User {
-        let ranks: Vec<_> = AccessLevelIterator::new(string).collect();
-        let mut state = &string[ranks.len()..];
+        User::with_prefix_map(string, &PrefixMap::default())
+    }
+
+    /// Creates a new User, recognizing the prefixes in the specified map (e.g. as advertised by the
+    /// server in its PREFIX token).
+    pub fn with_prefix_map(string: &str, prefix_map: &PrefixMap) -> User {
+        let prefix_len = string
+            .find(|c| prefix_map.mode(c).is_none())
+            .unwrap_or(string.len());
+        let mut prefixes: Vec<_> = string[..prefix_len].chars().collect();
+        prefixes.sort_by_key(|p| prefix_map.rank(*p));
+        prefixes.dedup();
+        let ranks: Vec<_> = prefixes
+            .iter()
+            .filter_map(|p| prefix_map.access_level(*p))
+            .collect();
+        let mut state = &string[prefix_len..];
         let nickname = state.find('!').map_or(state, |i| &state[..i]).to_owned();
         state = state.find('!').map_or("", |i| &state[i + 1..]);
         let username = state.find('@').map(|i| state[..i].to_owned());
@@ -34,6 +59,11 @@ impl User {
             nickname,
             username,
             hostname,
+            realname: None,
+            account: None,
+            away: false,
+            away_message: None,
+            prefixes: prefixes.into_iter().collect(),
             access_levels: {
                 let mut ranks = ranks.clone();
                 ranks.push(AccessLevel::Member);
@@ -68,6 +98,83 @@ impl User {
         self.hostname.as_ref().map(|s| &s[..])
     }
 
+    /// Gets the user's real name, if it's known.
+    /// This requires the IRCv3 extensions `extended-join` or `setname`, or a WHO reply.
+    pub fn realname(&self) -> Option<&str> {
+        self.realname.as_deref()
+    }
+
+    /// Gets the account the user is logged into, if they're logged in and it's known.
+    /// This requires the IRCv3 extensions `extended-join`, `account-notify` or `account-tag`, or
+    /// a WHOX reply.
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    /// Checks whether the user is known to be away.
+    /// This requires the IRCv3.1 extension `away-notify`, or a WHO reply.
+    pub fn is_away(&self) -> bool {
+        self.away
+    }
+
+    /// Gets the user's away message, if they're away and it's known.
+    pub fn away_message(&self) -> Option<&str> {
+        self.away_message.as_deref()
+    }
+
+    pub(crate) fn set_nickname(&mut self, nickname: &str) {
+        self.nickname = nickname.to_owned();
+    }
+
+    pub(crate) fn set_username(&mut self, username: &str) {
+        self.username = Some(username.to_owned());
+    }
+
+    pub(crate) fn set_hostname(&mut self, hostname: &str) {
+        self.hostname = Some(hostname.to_owned());
+    }
+
+    pub(crate) fn set_realname(&mut self, realname: &str) {
+        self.realname = Some(realname.to_owned());
+    }
+
+    /// Updates the user's account. The account `*` (or `0` in WHOX replies) means logged out.
+    pub(crate) fn set_account(&mut self, account: &str) {
+        self.account = match account {
+            "*" | "0" | "" => None,
+            account => Some(account.to_owned()),
+        };
+    }
+
+    pub(crate) fn set_away(&mut self, away: bool, message: Option<&str>) {
+        self.away = away;
+        self.away_message = if away {
+            message.map(|m| m.to_owned())
+        } else {
+            None
+        };
+    }
+
+    /// Takes the access levels from another `User`, such as a channel membership.
+    pub(crate) fn with_access_levels_of(&self, other: &User) -> User {
+        User {
+            prefixes: other.prefixes.clone(),
+            highest_access_level: other.highest_access_level,
+            access_levels: other.access_levels.clone(),
+            ..self.clone()
+        }
+    }
+
+    /// Gets the user's current prefixes (e.g. `@+`), ordered from highest to lowest rank.
+    pub fn prefixes(&self) -> &str {
+        &self.prefixes
+    }
+
+    /// Gets the user's highest prefix, if they have any.
+    pub fn highest_prefix(&self) -> Option<char> {
+        self.prefixes.chars().next()
+    }
+
     /// Gets the user's highest access level.
     pub fn highest_access_level(&self) -> AccessLevel {
         self.highest_access_level
@@ -80,18 +187,39 @@ impl User {
 
     /// Updates the user's access level.
     pub fn update_access_level(&mut self, mode: &Mode<ChannelMode>) {
-        match *mode {
-            Mode::Plus(ChannelMode::Founder, _) => self.add_access_level(AccessLevel::Owner),
-            Mode::Minus(ChannelMode::Founder, _) => self.sub_access_level(AccessLevel::Owner),
-            Mode::Plus(ChannelMode::Admin, _) => self.add_access_level(AccessLevel::Admin),
-            Mode::Minus(ChannelMode::Admin, _) => self.sub_access_level(AccessLevel::Admin),
-            Mode::Plus(ChannelMode::Oper, _) => self.add_access_level(AccessLevel::Oper),
-            Mode::Minus(ChannelMode::Oper, _) => self.sub_access_level(AccessLevel::Oper),
-            Mode::Plus(ChannelMode::Halfop, _) => self.add_access_level(AccessLevel::HalfOp),
-            Mode::Minus(ChannelMode::Halfop, _) => self.sub_access_level(AccessLevel::HalfOp),
-            Mode::Plus(ChannelMode::Voice, _) => self.add_access_level(AccessLevel::Voice),
-            Mode::Minus(ChannelMode::Voice, _) => self.sub_access_level(AccessLevel::Voice),
-            _ => {}
+        self.update_access_level_with(mode, &PrefixMap::default())
+    }
+
+    /// Updates the user's access level, using the prefixes in the specified map (e.g. as
+    /// advertised by the server in its PREFIX token).
+    pub fn update_access_level_with(&mut self, mode: &Mode<ChannelMode>, prefix_map: &PrefixMap) {
+        let (plus, mode) = match *mode {
+            Mode::Plus(ref mode, _) => (true, mode),
+            Mode::Minus(ref mode, _) => (false, mode),
+            Mode::NoPrefix(_) => return,
+        };
+        let prefix = match mode_char(mode).and_then(|c| prefix_map.prefix(c)) {
+            Some(prefix) => prefix,
+            None => return,
+        };
+
+        if plus {
+            if !self.prefixes.contains(prefix) {
+                let n = self
+                    .prefixes
+                    .chars()
+                    .take_while(|p| prefix_map.rank(*p) < prefix_map.rank(prefix))
+                    .count();
+                self.prefixes.insert(n, prefix);
+            }
+            if let Some(level) = prefix_map.access_level(prefix) {
+                self.add_access_level(level);
+            }
+        } else {
+            self.prefixes.retain(|p| p != prefix);
+            if let Some(level) = prefix_map.access_level(prefix) {
+                self.sub_access_level(level);
+            }
         }
     }
 
@@ -202,34 +330,84 @@ impl FromStr for AccessLevel {
     }
 }
 
-/// An iterator used to parse access levels from strings.
-struct AccessLevelIterator {
-    value: String,
+/// The channel membership prefixes supported by a server, as advertised in its PREFIX token, e.g.
+/// `(qaohv)~&@%+`. Prefixes are ranked from highest to lowest in the order the server lists them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrefixMap {
+    /// Pairs of channel modes and their prefixes, from highest to lowest rank.
+    prefixes: Vec<(char, char)>,
 }
 
-impl AccessLevelIterator {
-    pub fn new(value: &str) -> AccessLevelIterator {
-        AccessLevelIterator {
-            value: value.to_owned(),
+impl PrefixMap {
+    /// Parses a PREFIX token, such as `(qaohv)~&@%+`. An empty token means no prefixes.
+    pub fn new(token: &str) -> Option<PrefixMap> {
+        if token.is_empty() {
+            return Some(PrefixMap { prefixes: vec![] });
         }
+        let (modes, prefixes) = token.strip_prefix('(')?.split_once(')')?;
+        if modes.chars().count() != prefixes.chars().count() {
+            return None;
+        }
+        Some(PrefixMap {
+            prefixes: modes.chars().zip(prefixes.chars()).collect(),
+        })
+    }
+
+    /// Gets the channel mode that gives the specified prefix, e.g. `o` for `@`.
+    pub fn mode(&self, prefix: char) -> Option<char> {
+        self.prefixes
+            .iter()
+            .find(|(_, p)| *p == prefix)
+            .map(|(m, _)| *m)
+    }
+
+    /// Gets the prefix given by the specified channel mode, e.g. `@` for `o`.
+    pub fn prefix(&self, mode: char) -> Option<char> {
+        self.prefixes
+            .iter()
+            .find(|(m, _)| *m == mode)
+            .map(|(_, p)| *p)
+    }
+
+    /// Gets the rank of the specified prefix, where `0` is the highest rank.
+    pub fn rank(&self, prefix: char) -> Option<usize> {
+        self.prefixes.iter().position(|(_, p)| *p == prefix)
     }
-}
 
-impl Iterator for AccessLevelIterator {
-    type Item = AccessLevel;
-    fn next(&mut self) -> Option<AccessLevel> {
-        let ret = self.value.parse();
-        if !self.value.is_empty() {
-            self.value = self.value.chars().skip(1).collect()
+    /// Compares two prefixes by rank, with higher ranks being greater.
+    pub fn compare(&self, a: char, b: char) -> Option<Ordering> {
+        Some(self.rank(b)?.cmp(&self.rank(a)?))
+    }
+
+    /// Gets the access level corresponding to the specified prefix, if it's one of the common
+    /// ones. This is decided by the channel mode behind the prefix (e.g. `o` for operators).
+    pub fn access_level(&self, prefix: char) -> Option<AccessLevel> {
+        match self.mode(prefix)? {
+            'q' => Some(AccessLevel::Owner),
+            'a' => Some(AccessLevel::Admin),
+            'o' => Some(AccessLevel::Oper),
+            'h' => Some(AccessLevel::HalfOp),
+            'v' => Some(AccessLevel::Voice),
+            _ => None,
         }
-        ret.ok()
     }
 }
 
+impl Default for PrefixMap {
+    fn default() -> PrefixMap {
+        PrefixMap::new("(qaohv)~&@%+").expect("default PREFIX token")
+    }
+}
+
+/// Gets the character for a channel mode.
+pub(crate) fn mode_char(mode: &ChannelMode) -> Option<char> {
+    mode.to_string().chars().next()
+}
+
 #[cfg(test)]
 mod test {
     use super::AccessLevel::*;
-    use super::{AccessLevel, User};
+    use super::{AccessLevel, PrefixMap, User};
     use crate::proto::ChannelMode as M;
     use crate::proto::Mode::*;
 
@@ -244,6 +422,46 @@ mod test {
         assert!("".parse::<AccessLevel>().is_err());
     }
 
+    #[test]
+    fn parse_prefix_map() {
+        let map = PrefixMap::new("(Yqov)!~@+").unwrap();
+        assert_eq!(map.mode('!'), Some('Y'));
+        assert_eq!(map.prefix('o'), Some('@'));
+        assert_eq!(map.rank('!'), Some(0));
+        assert_eq!(map.rank('%'), None);
+        assert_eq!(map.compare('@', '~'), Some(std::cmp::Ordering::Less));
+        assert_eq!(map.access_level('!'), None);
+        assert_eq!(map.access_level('~'), Some(Owner));
+        assert_eq!(PrefixMap::new("").unwrap().rank('@'), None);
+        assert!(PrefixMap::new("(ov)@").is_none());
+        assert!(PrefixMap::new("ov@+").is_none());
+    }
+
+    #[test]
+    fn create_user_with_prefix_map() {
+        let map = PrefixMap::new("(Yov)!@+").unwrap();
+        let user = User::with_prefix_map("+!user", &map);
+        assert_eq!(user.get_nickname(), "user");
+        assert_eq!(user.prefixes(), "!+");
+        assert_eq!(user.highest_prefix(), Some('!'));
+        assert_eq!(user.highest_access_level(), Voice);
+        // Prefixes the server doesn't use are part of the nickname.
+        assert_eq!(User::with_prefix_map("~user", &map).get_nickname(), "~user");
+    }
+
+    #[test]
+    fn update_user_prefixes() {
+        let map = PrefixMap::new("(Yov)!@+").unwrap();
+        let mut user = User::with_prefix_map("+user", &map);
+        user.update_access_level_with(&Plus(M::Unknown('Y'), None), &map);
+        user.update_access_level_with(&Plus(M::Oper, None), &map);
+        assert_eq!(user.prefixes(), "!@+");
+        assert_eq!(user.highest_access_level(), Oper);
+        user.update_access_level_with(&Minus(M::Unknown('Y'), None), &map);
+        user.update_access_level_with(&Minus(M::Voice, None), &map);
+        assert_eq!(user.prefixes(), "@");
+    }
+
     #[test]
     fn create_user() {
         let user = User::new("~owner");
@@ -251,6 +469,11 @@ mod test {
             nickname: "owner".to_string(),
             username: None,
             hostname: None,
+            realname: None,
+            account: None,
+            away: false,
+            away_message: None,
+            prefixes: "~".to_string(),
             highest_access_level: Owner,
             access_levels: vec![Owner, Member],
         };
@@ -266,6 +489,11 @@ mod test {
             nickname: "user".to_string(),
             username: None,
             hostname: None,
+            realname: None,
+            account: None,
+            away: false,
+            away_message: None,
+            prefixes: "~&+".to_string(),
             highest_access_level: Owner,
             access_levels: vec![Owner, Admin, Voice, Member],
         };
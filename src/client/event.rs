@@ -0,0 +1,554 @@
+//! A typed stream of high-level events, built on top of [`ClientStream`].
+//!
+//! Most clients end up handling the same handful of messages, and repeating the same bookkeeping
+//! to do so. The [`EventStream`] returned by [`Client::events`](super::Client::events) does that
+//! bookkeeping once, e.g. working out whether a `JOIN` was our own or whether a `PRIVMSG` was a
+//! CTCP `ACTION` sent directly to us.
+//!
+//! # Example
+//! ```no_run
+//! # use irc::client::prelude::*;
+//! use futures::prelude::*;
+//! use irc::client::event::Event;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> irc::error::Result<()> {
+//! let mut client = Client::new("config.toml").await?;
+//! client.identify()?;
+//! let mut events = client.events()?;
+//!
+//! while let Some(event) = events.next().await {
+//!     match event {
+//!         Event::Joined { channel, user, is_self: false, .. } => {
+//!             client.send_privmsg(&channel, format!("Welcome, {}!", user))?;
+//!         }
+//!         Event::Disconnected { .. } => break,
+//!         _ => (),
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures_util::{ready, stream::FusedStream, stream::Stream};
+
+use super::{ClientState, ClientStream};
+use crate::{
+    error,
+    proto::{
+        error::MessageParseError, message::Tag, ChannelExt, ChannelMode, Command, Message, Mode,
+        Response, UserMode,
+    },
+};
+
+/// A high-level event received from the server. Every event but [`Event::Disconnected`] carries
+/// the raw [`Message`] that caused it.
+#[derive(Debug)]
+pub enum Event {
+    /// The server accepted our registration.
+    Registered {
+        /// The nickname we were registered with.
+        nickname: String,
+        /// The raw message.
+        message: Message,
+    },
+    /// A user joined a channel.
+    Joined {
+        /// The channel that was joined.
+        channel: String,
+        /// The nickname of the user who joined.
+        user: String,
+        /// Whether the user who joined was us.
+        is_self: bool,
+        /// The raw message.
+        message: Message,
+    },
+    /// A user left a channel.
+    Parted {
+        /// The channel that was left.
+        channel: String,
+        /// The nickname of the user who left.
+        user: String,
+        /// The reason given for leaving, if any.
+        reason: Option<String>,
+        /// Whether the user who left was us.
+        is_self: bool,
+        /// The raw message.
+        message: Message,
+    },
+    /// A user was kicked from a channel.
+    Kicked {
+        /// The channel the user was kicked from.
+        channel: String,
+        /// The nickname of the user who was kicked.
+        user: String,
+        /// The user (or server) who did the kicking.
+        by: String,
+        /// The reason given for the kick, if any.
+        reason: Option<String>,
+        /// Whether the user who was kicked was us.
+        is_self: bool,
+        /// The raw message.
+        message: Message,
+    },
+    /// A user disconnected from the server.
+    Quit {
+        /// The nickname of the user who quit.
+        user: String,
+        /// The reason given for quitting, if any.
+        reason: Option<String>,
+        /// The raw message.
+        message: Message,
+    },
+    /// A user changed their nickname.
+    NickChanged {
+        /// The user's old nickname.
+        old: String,
+        /// The user's new nickname.
+        new: String,
+        /// Whether the user was us.
+        is_self: bool,
+        /// The raw message.
+        message: Message,
+    },
+    /// A message was sent to a channel or to us.
+    Message {
+        /// The channel or nickname the message was sent to.
+        target: String,
+        /// The user (or server) who sent the message.
+        sender: String,
+        /// The text of the message, without any CTCP `ACTION` framing.
+        text: String,
+        /// Whether the message is a CTCP `ACTION` (i.e. `/me`).
+        is_action: bool,
+        /// Whether the message was sent directly to us rather than to a channel.
+        is_private: bool,
+        /// Whether this is the server echoing back a message we sent (see
+        /// [`Client::is_echo`](super::Client::is_echo)).
+        is_echo: bool,
+        /// The message's IRCv3 tags, if any.
+        tags: Option<Vec<Tag>>,
+        /// The raw message.
+        message: Message,
+    },
+    /// A CTCP request, other than `ACTION`, was sent to a channel or to us.
+    Ctcp {
+        /// The channel or nickname the request was sent to.
+        target: String,
+        /// The user who sent the request.
+        sender: String,
+        /// The CTCP command, e.g. `VERSION`.
+        command: String,
+        /// The rest of the request, if any.
+        params: Option<String>,
+        /// The raw message.
+        message: Message,
+    },
+    /// A notice was sent to a channel or to us.
+    Notice {
+        /// The channel or nickname the notice was sent to.
+        target: String,
+        /// The user (or server) who sent the notice.
+        sender: String,
+        /// The text of the notice.
+        text: String,
+        /// Whether the notice was sent directly to us rather than to a channel.
+        is_private: bool,
+        /// Whether this is the server echoing back a notice we sent.
+        is_echo: bool,
+        /// The notice's IRCv3 tags, if any.
+        tags: Option<Vec<Tag>>,
+        /// The raw message.
+        message: Message,
+    },
+    /// A channel's topic was changed, or sent to us when joining the channel.
+    TopicChanged {
+        /// The channel whose topic changed.
+        channel: String,
+        /// The new topic, or `None` if the topic was unset.
+        topic: Option<String>,
+        /// The user who changed the topic, if it was just changed.
+        user: Option<String>,
+        /// The raw message.
+        message: Message,
+    },
+    /// The modes of a channel or user were changed.
+    ModeChanged {
+        /// The channel or nickname whose modes changed.
+        target: String,
+        /// The user (or server) who changed the modes.
+        setter: String,
+        /// The changed modes.
+        change: ModeChange,
+        /// The raw message.
+        message: Message,
+    },
+    /// A line that could not be parsed, delivered as a [`Command::Raw`] fallback under
+    /// [`InvalidMessagePolicy::Raw`](super::data::InvalidMessagePolicy::Raw).
+    Invalid {
+        /// Why the line could not be parsed.
+        cause: MessageParseError,
+        /// The raw message.
+        message: Message,
+    },
+    /// Any other message.
+    Other {
+        /// The raw message.
+        message: Message,
+    },
+    /// The connection was closed. This is always the last event.
+    Disconnected {
+        /// The error that closed the connection, if any.
+        error: Option<error::Error>,
+    },
+}
+
+/// The modes changed in an [`Event::ModeChanged`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModeChange {
+    /// The modes of a channel were changed.
+    Channel(Vec<Mode<ChannelMode>>),
+    /// The modes of a user were changed.
+    User(Vec<Mode<UserMode>>),
+}
+
+/// A stream of [`Events`](Event) received from an IRC server via a `Client`.
+///
+/// This is created by [`Client::events`](super::Client::events), and otherwise behaves like
+/// [`ClientStream`], which it wraps.
+#[derive(Debug)]
+pub struct EventStream {
+    state: Arc<ClientState>,
+    stream: ClientStream,
+    disconnected: bool,
+}
+
+impl EventStream {
+    pub(crate) fn new(stream: ClientStream) -> EventStream {
+        EventStream {
+            state: Arc::clone(&stream.state),
+            stream,
+            disconnected: false,
+        }
+    }
+
+    /// Turns a message into an event.
+    fn event(&self, message: Message, parse_error: Option<MessageParseError>) -> Event {
+        if let Some(cause) = parse_error {
+            return Event::Invalid { cause, message };
+        }
+        let current_nickname = self.state.current_nickname();
+        let is_self = |nick: &str| nick.eq_ignore_ascii_case(&current_nickname);
+        let source = source(&message);
+        let is_echo = self.state.is_echo(&message);
+
+        match message.command {
+            Command::Response(Response::RPL_WELCOME, ref args) => Event::Registered {
+                nickname: args.first().cloned().unwrap_or_default(),
+                message,
+            },
+            Command::JOIN(ref channel, _, _) => Event::Joined {
+                channel: channel.clone(),
+                is_self: is_self(&source),
+                user: source,
+                message,
+            },
+            Command::PART(ref channel, ref reason) => Event::Parted {
+                channel: channel.clone(),
+                reason: reason.clone(),
+                is_self: is_self(&source),
+                user: source,
+                message,
+            },
+            Command::KICK(ref channel, ref user, ref reason) => Event::Kicked {
+                channel: channel.clone(),
+                user: user.clone(),
+                by: source,
+                reason: reason.clone(),
+                is_self: is_self(user),
+                message,
+            },
+            Command::QUIT(ref reason) => Event::Quit {
+                user: source,
+                reason: reason.clone(),
+                message,
+            },
+            // Our own nickname has already been updated by the time we see this.
+            Command::NICK(ref new) => Event::NickChanged {
+                old: source,
+                new: new.clone(),
+                is_self: is_self(new),
+                message,
+            },
+            Command::PRIVMSG(ref target, ref body) => {
+                let is_private = !target.is_channel_name();
+                match ctcp(body) {
+                    Some(("ACTION", text)) => Event::Message {
+                        target: target.clone(),
+                        sender: source,
+                        text: text.unwrap_or_default().to_owned(),
+                        is_action: true,
+                        is_private,
+                        is_echo,
+                        tags: message.tags.clone(),
+                        message,
+                    },
+                    Some((command, params)) => Event::Ctcp {
+                        target: target.clone(),
+                        sender: source,
+                        command: command.to_owned(),
+                        params: params.map(|p| p.to_owned()),
+                        message,
+                    },
+                    None => Event::Message {
+                        target: target.clone(),
+                        sender: source,
+                        text: body.clone(),
+                        is_action: false,
+                        is_private,
+                        is_echo,
+                        tags: message.tags.clone(),
+                        message,
+                    },
+                }
+            }
+            Command::NOTICE(ref target, ref text) => Event::Notice {
+                target: target.clone(),
+                sender: source,
+                text: text.clone(),
+                is_private: !target.is_channel_name(),
+                is_echo,
+                tags: message.tags.clone(),
+                message,
+            },
+            Command::TOPIC(ref channel, Some(ref topic)) => Event::TopicChanged {
+                channel: channel.clone(),
+                topic: Some(topic.clone()).filter(|t| !t.is_empty()),
+                user: Some(source),
+                message,
+            },
+            Command::Response(Response::RPL_TOPIC, ref args) if args.len() > 2 => {
+                Event::TopicChanged {
+                    channel: args[1].clone(),
+                    topic: Some(args[2].clone()),
+                    user: None,
+                    message,
+                }
+            }
+            Command::ChannelMODE(ref target, ref modes) => Event::ModeChanged {
+                target: target.clone(),
+                setter: source,
+                change: ModeChange::Channel(modes.clone()),
+                message,
+            },
+            Command::UserMODE(ref target, ref modes) => Event::ModeChanged {
+                target: target.clone(),
+                setter: source,
+                change: ModeChange::User(modes.clone()),
+                message,
+            },
+            _ => Event::Other { message },
+        }
+    }
+}
+
+/// Gets the nickname, or failing that the server name, that a message came from.
+fn source(message: &Message) -> String {
+    match message.source_nickname() {
+        Some(nick) => nick.to_owned(),
+        None => message
+            .prefix
+            .as_ref()
+            .map(|p| p.to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// Splits a CTCP request into its command and parameters, if the body is one.
+fn ctcp(body: &str) -> Option<(&str, Option<&str>)> {
+    let body = body.strip_prefix('\u{001}')?;
+    let body = body.strip_suffix('\u{001}').unwrap_or(body);
+    match body.split_once(' ') {
+        Some((command, params)) => Some((command, Some(params))),
+        None => Some((body, None)),
+    }
+}
+
+impl FusedStream for EventStream {
+    fn is_terminated(&self) -> bool {
+        self.disconnected
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.disconnected {
+            return Poll::Ready(None);
+        }
+
+        match ready!(Pin::new(&mut self.stream).poll_received(cx)) {
+            Some(Ok(received)) => {
+                Poll::Ready(Some(self.event(received.message, received.parse_error)))
+            }
+            Some(Err(error)) => {
+                self.disconnected = true;
+                Poll::Ready(Some(Event::Disconnected { error: Some(error) }))
+            }
+            None => {
+                self.disconnected = true;
+                Poll::Ready(Some(Event::Disconnected { error: None }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Event, ModeChange};
+    use crate::{
+        client::{
+            data::{Config, InvalidMessagePolicy},
+            test::test_config,
+            Client,
+        },
+        proto::{error::MessageParseError, ChannelMode, Mode},
+    };
+    use anyhow::Result;
+    use futures::prelude::*;
+
+    async fn events(value: &str) -> Result<Vec<Event>> {
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        Ok(client.events()?.collect().await)
+    }
+
+    #[tokio::test]
+    async fn membership_events() -> Result<()> {
+        let events = events(
+            ":irc.test.net 001 test :Welcome\r\n:test!t@host JOIN #test\r\n\
+             :alice!a@host JOIN #test\r\n:op!o@host KICK #test alice :bye\r\n\
+             :test!t@host NICK test3\r\n",
+        )
+        .await?;
+        assert!(matches!(&events[0], Event::Registered { nickname, .. } if nickname == "test"));
+        assert!(matches!(&events[1], Event::Joined { user, is_self: true, .. } if user == "test"));
+        assert!(
+            matches!(&events[2], Event::Joined { user, is_self: false, .. } if user == "alice")
+        );
+        assert!(matches!(
+            &events[3],
+            Event::Kicked { user, by, reason: Some(reason), is_self: false, .. }
+                if user == "alice" && by == "op" && reason == "bye"
+        ));
+        assert!(matches!(
+            &events[4],
+            Event::NickChanged { old, new, is_self: true, .. } if old == "test" && new == "test3"
+        ));
+        assert!(matches!(events[5], Event::Disconnected { error: None }));
+        assert_eq!(events.len(), 6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn message_events() -> Result<()> {
+        let events = events(
+            "@time=now :alice!a@host PRIVMSG #test :hi\r\n\
+             :alice!a@host PRIVMSG test :\u{001}ACTION waves\u{001}\r\n\
+             :alice!a@host PRIVMSG test :\u{001}VERSION\u{001}\r\n\
+             :irc.test.net NOTICE test :hello\r\n",
+        )
+        .await?;
+        match &events[0] {
+            Event::Message {
+                target,
+                sender,
+                text,
+                is_action: false,
+                is_private: false,
+                tags: Some(tags),
+                ..
+            } => {
+                assert_eq!(
+                    (&target[..], &sender[..], &text[..]),
+                    ("#test", "alice", "hi")
+                );
+                assert_eq!(tags[0].0, "time");
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert!(matches!(
+            &events[1],
+            Event::Message { text, is_action: true, is_private: true, .. } if text == "waves"
+        ));
+        assert!(matches!(
+            &events[2],
+            Event::Ctcp { command, params: None, .. } if command == "VERSION"
+        ));
+        assert!(matches!(
+            &events[3],
+            Event::Notice { sender, is_private: true, .. } if sender == "irc.test.net"
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn topic_and_mode_events() -> Result<()> {
+        let events = events(
+            ":irc.test.net 332 test #test :Old topic\r\n\
+             :alice!a@host TOPIC #test :\r\n:alice!a@host MODE #test +o bob\r\n",
+        )
+        .await?;
+        assert!(matches!(
+            &events[0],
+            Event::TopicChanged { topic: Some(topic), user: None, .. } if topic == "Old topic"
+        ));
+        assert!(matches!(
+            &events[1],
+            Event::TopicChanged { topic: None, user: Some(user), .. } if user == "alice"
+        ));
+        match &events[2] {
+            Event::ModeChanged { target, change, .. } => {
+                assert_eq!(target, "#test");
+                assert_eq!(
+                    change,
+                    &ModeChange::Channel(vec![Mode::Plus(
+                        ChannelMode::Oper,
+                        Some("bob".to_owned())
+                    )])
+                );
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_events() -> Result<()> {
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(":irc.test.net :garbage\r\n".to_owned()),
+            invalid_message_policy: Some(InvalidMessagePolicy::Raw),
+            ..test_config()
+        })
+        .await?;
+        let events: Vec<_> = client.events()?.collect().await;
+        assert!(matches!(
+            &events[0],
+            Event::Invalid {
+                cause: MessageParseError::InvalidCommand,
+                ..
+            }
+        ));
+        assert!(matches!(&events[1], Event::Disconnected { error: None }));
+        Ok(())
+    }
+}
@@ -38,7 +38,7 @@
 //!
 //! while let Some(message) = stream.next().await.transpose()? {
 //!     if let Command::PRIVMSG(channel, message) = message.command {
-//!         if message.contains(client.current_nickname()) {
+//!         if message.contains(&*client.current_nickname()) {
 //!             client.send_privmsg(&channel, "beep boop").unwrap();
 //!         }
 //!     }
@@ -58,40 +58,75 @@ use futures_util::{
     sink::Sink as _,
     stream::{SplitSink, SplitStream, StreamExt as _},
 };
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
     fmt,
     path::Path,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
 };
-use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::{
+    sync::{
+        mpsc::{self, UnboundedReceiver},
+        watch,
+    },
+    time::{self, Duration, Instant, Interval, Sleep},
+};
 
+#[cfg(feature = "channel-lists")]
+use crate::client::data::{channel::parse_timestamp, MaskEntry};
 use crate::{
     client::{
-        conn::Connection,
-        data::{Config, User},
+        conn::{
+            AsyncStream, Connection, ConnectionState, ConnectionStatus, Receiving, StatusTracker,
+        },
+        data::{
+            channel::parse_modes, registry::Registry, Channel, Config, ListMode, PrefixMap, User,
+        },
+        presence::{PendingIson, Presence},
+        query::{
+            Delivery, EchoGuard, Echoes, HistoryMessage, HistoryRequest, LabelGuard, Labels,
+            ListEntry, Queries, Query, QueryGuard, WhoReply, WhoisInfo,
+        },
+        queue::{Priority, Queue},
+        registration::{Registration, Welcome},
+        shutdown::Shutdown,
+        transport::{Lag, Received},
     },
     error,
     proto::{
+        error::MessageParseError,
+        message::Tag,
         mode::ModeType,
-        CapSubCommand::{END, LS, REQ},
+        CapSubCommand::{ACK, DEL, END, LS, REQ},
         Capability, ChannelMode, Command,
         Command::{
-            ChannelMODE, AUTHENTICATE, CAP, INVITE, JOIN, KICK, KILL, NICK, NICKSERV, NOTICE, OPER,
-            PART, PASS, PONG, PRIVMSG, QUIT, SAMODE, SANICK, TOPIC, USER,
+            ChannelMODE, AUTHENTICATE, CAP, INVITE, ISON, JOIN, KICK, KILL, MONITOR, NICK,
+            NICKSERV, NOTICE, OPER, PART, PASS, PONG, PRIVMSG, QUIT, SAMODE, SANICK, TOPIC, USER,
         },
         Message, Mode, NegotiationVersion, Response,
     },
 };
 
+pub mod batch;
 pub mod conn;
 pub mod data;
+pub mod event;
 mod mock;
 pub mod prelude;
+pub mod presence;
+#[cfg(feature = "proxy")]
+mod proxy;
+pub mod query;
+pub mod queue;
+pub mod registration;
+pub mod router;
+mod shutdown;
 pub mod transport;
+#[cfg(feature = "websocket")]
+mod websocket;
 
 macro_rules! pub_state_base {
     () => {
@@ -365,7 +400,7 @@ macro_rules! pub_sender_base {
         /// Sends a finger request to the specified target.
         /// This requires the CTCP feature to be enabled.
         #[cfg(feature = "ctcp")]
-        pub fn send_finger<S: fmt::Display>(&self, target: S) -> error::Result<()>
+        pub fn send_finger<S>(&self, target: S) -> error::Result<()>
         where
             S: fmt::Display,
         {
@@ -434,9 +469,17 @@ macro_rules! pub_sender_base {
 #[derive(Debug)]
 pub struct ClientStream {
     state: Arc<ClientState>,
-    stream: SplitStream<Connection>,
+    stream: SplitStream<Receiving>,
     // In case the client stream also handles outgoing messages.
     outgoing: Option<Outgoing>,
+    // The interval at which to poll with ISON for nicknames that can't be watched with MONITOR.
+    ison_interval: Option<Interval>,
+    // How long we have left to wait for the server to close the connection once we have quit.
+    quit_timeout: Option<Pin<Box<Sleep>>>,
+    // Whether the stream has ended after quitting.
+    finished: bool,
+    // Why the last message yielded could not be parsed, if it is a fallback.
+    parse_error: Option<MessageParseError>,
 }
 
 impl ClientStream {
@@ -453,26 +496,61 @@ impl ClientStream {
 
         Ok(output)
     }
+
+    /// Gets why the last message yielded could not be parsed, if it is the [`Command::Raw`]
+    /// fallback for an invalid line delivered under
+    /// [`InvalidMessagePolicy::Raw`](data::InvalidMessagePolicy::Raw). The [`batched`] and
+    /// [`events`](Client::events) streams carry the error in their items instead.
+    ///
+    /// [`batched`]: ClientStream::batched
+    pub fn parse_error(&self) -> Option<&MessageParseError> {
+        self.parse_error.as_ref()
+    }
+
+    /// Reassembles batches from the stream, which then yields each batch as a whole once it ends.
+    /// Messages are still handled by the client as they arrive. See [`batch`] for details.
+    pub fn batched(self) -> batch::BatchStream {
+        batch::BatchStream::new(self)
+    }
+
+    /// Ends the stream cleanly after quitting.
+    fn finish(&mut self) -> Poll<Option<error::Result<Received>>> {
+        self.finished = true;
+        self.state.disconnected();
+        Poll::Ready(None)
+    }
 }
 
 impl FusedStream for ClientStream {
     fn is_terminated(&self) -> bool {
-        false
+        self.finished
     }
 }
 
-impl Stream for ClientStream {
-    type Item = Result<Message, error::Error>;
+impl ClientStream {
+    /// Polls for the next message, along with why it could not be parsed if it is the
+    /// [`Command::Raw`] fallback for an invalid line.
+    pub(crate) fn poll_received(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<error::Result<Received>>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        let quitting = self.state.sender.shutdown.lock().is_quitting();
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if let Some(outgoing) = self.as_mut().outgoing.as_mut() {
             match Pin::new(outgoing).poll(cx) {
                 Poll::Ready(Ok(())) => {
+                    self.state.disconnected();
                     // assure that we wake up again to check the incoming stream.
                     cx.waker().wake_by_ref();
                     return Poll::Ready(None);
                 }
+                // Once we have quit, the server is free to close the connection under us.
+                Poll::Ready(Err(_)) if quitting => return self.finish(),
                 Poll::Ready(Err(e)) => {
+                    self.state.disconnected();
                     cx.waker().wake_by_ref();
                     return Poll::Ready(Some(Err(e)));
                 }
@@ -480,14 +558,98 @@ impl Stream for ClientStream {
             }
         }
 
-        match ready!(Pin::new(&mut self.as_mut().stream).poll_next(cx)) {
-            Some(Ok(msg)) => {
-                self.state.handle_message(&msg)?;
-                Poll::Ready(Some(Ok(msg)))
+        if quitting {
+            let timeout = Duration::from_secs(u64::from(self.state.config().quit_timeout()));
+            let quit_timeout = self
+                .quit_timeout
+                .get_or_insert_with(|| Box::pin(time::sleep(timeout)));
+            if quit_timeout.as_mut().poll(cx).is_ready() {
+                log::warn!("Timed out waiting for the server to close the connection.");
+                return self.finish();
+            }
+        }
+
+        if self.ison_interval.is_none() {
+            let period = Duration::from_secs(u64::from(self.state.config().ison_interval()));
+            self.ison_interval = Some(time::interval_at(Instant::now() + period, period));
+        }
+        // The interval is polled until it is pending, so that it wakes us for the next tick.
+        while let Some(Poll::Ready(_)) = self.ison_interval.as_mut().map(|i| i.poll_tick(cx)) {
+            self.state.poll_ison()?;
+        }
+
+        loop {
+            match ready!(Pin::new(&mut self.as_mut().stream).poll_next(cx)) {
+                Some(Ok(mut received)) => {
+                    self.state.reparse_modes(&mut received);
+                    let msg = &received.message;
+                    self.state.handle_message(msg)?;
+                    if let (true, Command::ERROR(_)) = (quitting, &msg.command) {
+                        // The server's answer to our QUIT, after which it closes the connection.
+                        let _ = self.finish();
+                        return Poll::Ready(Some(Ok(received)));
+                    }
+                    if !(self.state.config().suppress_echoes() && self.state.is_echo(msg)) {
+                        return Poll::Ready(Some(Ok(received)));
+                    }
+                }
+                Some(Err(_)) if quitting => return self.finish(),
+                other => {
+                    self.state.disconnected();
+                    return Poll::Ready(other);
+                }
+            }
+        }
+    }
+}
+
+impl Stream for ClientStream {
+    type Item = Result<Message, error::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match ready!(self.as_mut().poll_received(cx)) {
+            Some(Ok(received)) => {
+                self.parse_error = received.parse_error;
+                Poll::Ready(Some(Ok(received.message)))
             }
-            other => Poll::Ready(other),
+            other => Poll::Ready(other.map(|result| result.map(|received| received.message))),
+        }
+    }
+}
+
+/// Splits a raw line into the arguments of its command, leaving out its tags and prefix.
+fn split_args(line: &str) -> Vec<&str> {
+    let mut line = line.trim_end_matches(['\r', '\n']);
+    for marker in ['@', ':'] {
+        if line.starts_with(marker) {
+            line = line.split_once(' ').map_or("", |(_, rest)| rest);
+            line = line.trim_start_matches(' ');
         }
     }
+    let (middle, trailing) = match line.split_once(" :") {
+        Some((middle, trailing)) => (middle, Some(trailing)),
+        None => (line, None),
+    };
+    middle
+        .split(' ')
+        .filter(|arg| !arg.is_empty())
+        .skip(1)
+        .chain(trailing)
+        .collect()
+}
+
+/// The number of nicknames to try generating before giving up on registration.
+const MAX_GENERATED_NICKS: usize = 100;
+
+/// Generates a nickname for the given attempt by appending characters to `base`, truncating it as
+/// needed to stay within `max_len` characters.
+fn generate_nickname(base: &str, attempt: usize, max_len: usize) -> String {
+    let suffix = match attempt {
+        0 => "_".to_owned(),
+        n => n.to_string(),
+    };
+    let base_len = max_len.saturating_sub(suffix.len());
+    base.chars().take(base_len).chain(suffix.chars()).collect()
 }
 
 /// Thread-safe internal state for an IRC server connection.
@@ -496,21 +658,61 @@ struct ClientState {
     sender: Sender,
     /// The configuration used with this connection.
     config: Config,
-    /// A thread-safe map of channels to the list of users in them.
-    chanlists: RwLock<HashMap<String, Vec<User>>>,
+    /// A thread-safe registry of the channels we're in and the users in them.
+    registry: RwLock<Registry>,
     /// A thread-safe index to track the current alternative nickname being used.
     alt_nick_index: RwLock<usize>,
+    /// The nickname currently in use, which also notifies watchers when it changes.
+    current_nickname: watch::Sender<String>,
+    /// Whether the server has accepted our registration (i.e. sent `RPL_WELCOME`).
+    registered: RwLock<bool>,
+    /// The parameters advertised by the server in `RPL_ISUPPORT`.
+    isupport: RwLock<HashMap<String, String>>,
+    /// Whether we are watching the primary nickname with MONITOR in order to regain it.
+    monitoring_nickname: RwLock<bool>,
+    /// The ISONs waiting on a reply, whether sent by us or by the user, in the order they were
+    /// sent.
+    ison_pending: Mutex<VecDeque<PendingIson>>,
+    /// The users whose presence we are tracking.
+    presence: RwLock<Presence>,
+    /// The capabilities that the server has enabled.
+    capabilities: RwLock<HashSet<String>>,
+    /// The queries waiting for the server to answer them.
+    queries: Mutex<Queries>,
+    /// The messages waiting for the server to echo them back.
+    echoes: Mutex<Echoes>,
+    /// The references of the open batches replaying history in answer to `CHATHISTORY` requests,
+    /// along with those of any batches nested inside them.
+    history_batches: Mutex<Vec<String>>,
+    /// The stage of its lifecycle that the connection is in.
+    status: StatusTracker,
+    /// The progress of our registration with the server.
+    registration: Mutex<Registration>,
     /// Default ghost sequence to send if one is required but none is configured.
     default_ghost_sequence: Vec<String>,
 }
 
 impl ClientState {
-    fn new(sender: Sender, config: Config) -> ClientState {
+    fn new(sender: Sender, config: Config, status: StatusTracker) -> ClientState {
+        let (current_nickname, _) =
+            watch::channel(config.nickname().unwrap_or_default().to_owned());
         ClientState {
             sender,
             config,
-            chanlists: RwLock::new(HashMap::new()),
+            registry: RwLock::new(Registry::default()),
             alt_nick_index: RwLock::new(0),
+            current_nickname,
+            registered: RwLock::new(false),
+            isupport: RwLock::new(HashMap::new()),
+            monitoring_nickname: RwLock::new(false),
+            ison_pending: Mutex::new(VecDeque::new()),
+            presence: RwLock::new(Presence::new()),
+            capabilities: RwLock::new(HashSet::new()),
+            queries: Mutex::new(Queries::default()),
+            echoes: Mutex::new(Echoes::default()),
+            history_batches: Mutex::new(Vec::new()),
+            status,
+            registration: Mutex::new(Registration::default()),
             default_ghost_sequence: vec![String::from("GHOST")],
         }
     }
@@ -526,64 +728,309 @@ impl ClientState {
     }
 
     /// Gets the current nickname in use.
-    fn current_nickname(&self) -> &str {
-        let alt_nicks = self.config().alternate_nicknames();
-        let index = self.alt_nick_index.read();
+    fn current_nickname(&self) -> String {
+        self.current_nickname.borrow().clone()
+    }
+
+    /// Updates the current nickname, notifying watchers if it changed.
+    fn set_current_nickname(&self, nickname: &str) {
+        self.current_nickname.send_if_modified(|current| {
+            if current == nickname {
+                false
+            } else {
+                log::debug!("Nickname changed from {} to {}", current, nickname);
+                *current = nickname.to_owned();
+                true
+            }
+        });
+    }
+
+    /// Gets the value of the specified `RPL_ISUPPORT` parameter, if the server advertised it.
+    /// Parameters without a value are reported as an empty string.
+    fn isupport(&self, key: &str) -> Option<String> {
+        self.isupport.read().get(key).cloned()
+    }
+
+    /// Gets the channel membership prefixes supported by the server. Until the server advertises
+    /// its PREFIX token, the common `(qaohv)~&@%+` prefixes are assumed.
+    fn prefix_map(&self) -> PrefixMap {
+        self.isupport("PREFIX")
+            .and_then(|token| PrefixMap::new(&token))
+            .unwrap_or_default()
+    }
+
+    /// Parses a channel `MODE` again from the line it was decoded from, now that the server has
+    /// told us which of its modes take arguments.
+    fn reparse_modes(&self, received: &mut Received) {
+        if let (Command::ChannelMODE(_, modes), Some(line)) =
+            (&mut received.message.command, &received.line)
+        {
+            let args = split_args(line);
+            if let Some(pieces) = args.get(1..) {
+                let chanmodes = self.isupport("CHANMODES");
+                *modes = parse_modes(pieces, &self.prefix_map(), chanmodes.as_deref());
+            }
+        }
+    }
+
+    /// Checks whether we are registered under some nickname other than our primary one and have
+    /// been configured to regain it.
+    fn wants_primary_nickname(&self) -> bool {
+        self.config().regain_nick()
+            && *self.registered.read()
+            && match self.config().nickname() {
+                Ok(nick) => !nick.eq_ignore_ascii_case(&self.current_nickname()),
+                Err(_) => false,
+            }
+    }
 
-        match *index {
-            0 => self
-                .config()
-                .nickname()
-                .expect("current_nickname should not be callable if nickname is not defined."),
-            i => alt_nicks[i - 1].as_str(),
+    /// Polls the server with ISON for the watched nicknames that aren't being watched with MONITOR,
+    /// and for our primary nickname if we want to regain it and the server does not support
+    /// MONITOR. ISONs that were already waiting on their replies at the last poll are given up on,
+    /// so that a lost reply doesn't throw off the matching of replies for good.
+    fn poll_ison(&self) -> error::Result<()> {
+        let mut pending = self.ison_pending.lock();
+        pending.retain(|ison| !ison.stale);
+        for ison in pending.iter_mut() {
+            ison.stale = true;
+        }
+        let mut nicknames = self.presence.read().ison_targets();
+        if self.wants_primary_nickname() && !*self.monitoring_nickname.read() {
+            let nick = self.config().nickname()?;
+            if !nicknames.iter().any(|n| n.eq_ignore_ascii_case(nick)) {
+                nicknames.push(nick.to_owned());
+            }
+        }
+        for chunk in presence::chunk_nicknames(&nicknames) {
+            pending.push_back(PendingIson::new(chunk.clone(), true));
+            // This skips `handle_sent_message`, which would record the poll as the user's.
+            self.sender.send(ISON(chunk))?;
         }
+        Ok(())
+    }
+
+    /// Records that the connection has ended, failing any registration still in progress and
+    /// completing any quit. A server that has started welcoming us has accepted our registration,
+    /// so its welcome is completed with what it sent.
+    fn disconnected(&self) {
+        self.status.set(ConnectionState::Disconnected);
+        let mut registration = self.registration.lock();
+        registration.finish(|| self.isupport.read().clone());
+        registration.fail(|| error::Error::ConnectionClosed {
+            reason: "the connection ended".to_owned(),
+        });
+        drop(registration);
+        self.sender.shutdown.lock().close();
+    }
+
+    /// Checks whether a message is the server echoing back one of our own messages, which it only
+    /// does once it has enabled `echo-message`.
+    fn is_echo(&self, msg: &Message) -> bool {
+        let echoable = match msg.command {
+            PRIVMSG(..) | NOTICE(..) => true,
+            Command::Raw(ref command, _) => command == "TAGMSG",
+            _ => false,
+        };
+        echoable
+            && msg
+                .source_nickname()
+                .is_some_and(|nick| nick.eq_ignore_ascii_case(&self.current_nickname()))
+            && self
+                .capabilities
+                .read()
+                .contains(Capability::EchoMessage.as_ref())
     }
 
     /// Handles sent messages internally for basic client functionality.
     fn handle_sent_message(&self, msg: &Message) -> error::Result<()> {
-        log::trace!("[SENT] {}", msg.to_string());
+        log::trace!("[SENT] {}", msg);
+
+        if !*self.registered.read() {
+            let state = match msg.command {
+                CAP(_, LS, ..) | CAP(_, REQ, ..) => Some(ConnectionState::NegotiatingCapabilities),
+                AUTHENTICATE(_) => Some(ConnectionState::Authenticating),
+                CAP(_, END, ..) => Some(ConnectionState::Registering),
+                // Registration is put on hold while capabilities are being negotiated.
+                NICK(_) | USER(..) if self.status.state() == ConnectionState::Connected => {
+                    Some(ConnectionState::Registering)
+                }
+                _ => None,
+            };
+            if let Some(state) = state {
+                self.status.set(state);
+            }
+        }
 
-        if let PART(ref chan, _) = msg.command {
-            let _ = self.chanlists.write().remove(chan);
+        match msg.command {
+            PART(ref chan, _) => self.registry.write().remove_channel(chan),
+            // WHOX queries can't be represented by `Command::WHO`.
+            Command::Raw(ref cmd, ref args) if cmd.eq_ignore_ascii_case("WHO") => {
+                if let Some(spec) = args.get(1).filter(|spec| spec.starts_with('%')) {
+                    self.registry.write().add_whox_query(spec);
+                }
+            }
+            // Without any nicknames, the server answers with an error rather than `RPL_ISON`.
+            ISON(ref nicknames) if !nicknames.is_empty() => self
+                .ison_pending
+                .lock()
+                .push_back(PendingIson::new(nicknames.clone(), false)),
+            // Servers do not confirm nickname changes before registration, so the nickname we send
+            // is the one in use until the server tells us otherwise.
+            NICK(ref nick) if !*self.registered.read() => self.set_current_nickname(nick),
+            _ => (),
         }
 
         Ok(())
     }
 
+    /// Gets whether a received message is part of a `chathistory` batch, keeping track of the
+    /// batches that are.
+    fn is_history(&self, msg: &Message) -> bool {
+        let mut batches = self.history_batches.lock();
+        let in_history = msg
+            .batch_id()
+            .is_some_and(|batch| batches.iter().any(|b| b == batch));
+        if let Command::BATCH(ref reference, ref kind, _) = msg.command {
+            if let Some(reference) = reference.strip_prefix('+') {
+                let replay = kind
+                    .as_ref()
+                    .is_some_and(|kind| kind.to_str().eq_ignore_ascii_case("CHATHISTORY"));
+                if replay || in_history {
+                    batches.push(reference.to_owned());
+                    return true;
+                }
+            } else if let Some(reference) = reference.strip_prefix('-') {
+                if let Some(n) = batches.iter().position(|b| b == reference) {
+                    batches.remove(n);
+                    return true;
+                }
+            }
+        }
+        in_history
+    }
+
     /// Handles received messages internally for basic client functionality.
     fn handle_message(&self, msg: &Message) -> error::Result<()> {
-        log::trace!("[RECV] {}", msg.to_string());
+        log::trace!("[RECV] {}", msg);
+        self.registration
+            .lock()
+            .handle(msg, || self.isupport.read().clone());
+        self.queries.lock().handle(msg);
+        self.sender.labels.lock().handle(msg);
+        // Replayed history is only meant for whoever asked for it, and must not be mistaken for
+        // what is happening now, e.g. by answering old CTCP requests.
+        if self.is_history(msg) {
+            return Ok(());
+        }
+        self.presence.read().handle_message(msg);
+        if self.is_echo(msg) {
+            self.echoes.lock().handle(msg);
+        }
+        self.handle_user_info(msg);
         match msg.command {
-            JOIN(ref chan, _, _) => self.handle_join(msg.source_nickname().unwrap_or(""), chan),
+            JOIN(..) => self.handle_join(msg),
             PART(ref chan, _) => self.handle_part(msg.source_nickname().unwrap_or(""), chan),
             KICK(ref chan, ref user, _) => self.handle_part(user, chan),
             QUIT(_) => self.handle_quit(msg.source_nickname().unwrap_or("")),
             NICK(ref new_nick) => {
-                self.handle_nick_change(msg.source_nickname().unwrap_or(""), new_nick)
+                let old_nick = msg.source_nickname().unwrap_or("");
+                if old_nick.eq_ignore_ascii_case(&self.current_nickname()) {
+                    self.set_current_nickname(new_nick);
+                    if *self.monitoring_nickname.read() && !self.wants_primary_nickname() {
+                        *self.monitoring_nickname.write() = false;
+                        // The presence tracker may still need the server to watch the nickname.
+                        if !self.presence.read().is_watched(new_nick) {
+                            self.send(MONITOR("-".to_owned(), Some(new_nick.to_owned())))?;
+                        }
+                    }
+                }
+                self.handle_nick_change(old_nick, new_nick)
             }
-            ChannelMODE(ref chan, ref modes) => self.handle_mode(chan, modes),
-            PRIVMSG(ref target, ref body) => {
-                if body.starts_with('\u{001}') {
-                    let tokens: Vec<_> = {
-                        let end = if body.ends_with('\u{001}') && body.len() > 1 {
-                            body.len() - 1
-                        } else {
-                            body.len()
-                        };
-                        body[1..end].split(' ').collect()
+            ChannelMODE(ref chan, ref modes) => {
+                let setter = msg.prefix.as_ref().map(|prefix| prefix.to_string());
+                self.handle_mode(chan, modes, setter.as_deref())
+            }
+            TOPIC(ref chan, Some(ref topic)) => self.handle_topic(chan, topic, Some(msg)),
+            // Our own CTCP requests come back to us with echo-message, and must not be answered.
+            PRIVMSG(ref target, ref body) if body.starts_with('\u{001}') && !self.is_echo(msg) => {
+                let tokens: Vec<_> = {
+                    let end = if body.ends_with('\u{001}') && body.len() > 1 {
+                        body.len() - 1
+                    } else {
+                        body.len()
                     };
-                    if target.starts_with('#') {
-                        self.handle_ctcp(target, &tokens)?
-                    } else if let Some(user) = msg.source_nickname() {
-                        self.handle_ctcp(user, &tokens)?
-                    }
+                    body[1..end].split(' ').collect()
+                };
+                if target.starts_with('#') {
+                    self.handle_ctcp(target, &tokens)?
+                } else if let Some(user) = msg.source_nickname() {
+                    self.handle_ctcp(user, &tokens)?
+                }
+            }
+            Command::Response(Response::RPL_WELCOME, ref args) => {
+                *self.registered.write() = true;
+                self.status.set(ConnectionState::Registered);
+                // The first argument of the welcome message is the nickname we registered with.
+                if let Some(nick) = args.first() {
+                    self.set_current_nickname(nick);
                 }
             }
+            Command::Response(Response::RPL_ISUPPORT, ref args) => self.handle_isupport(args),
+            // Once SASL is done, one way or another, we're back to negotiating capabilities.
+            Command::Response(
+                Response::RPL_SASLSUCCESS
+                | Response::ERR_SASLFAIL
+                | Response::ERR_SASLTOOLONG
+                | Response::ERR_SASLABORT,
+                _,
+            ) if self.status.state() == ConnectionState::Authenticating => {
+                self.status.set(ConnectionState::NegotiatingCapabilities)
+            }
+            Command::ERROR(_) => self.status.set(ConnectionState::Disconnected),
+            CAP(_, ref subcommand @ (ACK | DEL), ref caps, ref more_caps) => {
+                let caps = more_caps.as_ref().or(caps.as_ref());
+                self.handle_capabilities(subcommand == &ACK, caps.map_or("", |c| &c[..]))
+            }
             Command::Response(Response::RPL_NAMREPLY, ref args) => self.handle_namreply(args),
+            Command::Response(Response::RPL_CHANNELMODEIS, ref args) => {
+                self.handle_channel_modes(args)
+            }
+            Command::Response(Response::RPL_CREATIONTIME, ref args) => {
+                self.handle_creation_time(args)
+            }
+            Command::Response(Response::RPL_NOTOPIC, ref args) if args.len() > 1 => {
+                self.handle_topic(&args[1], "", None)
+            }
+            Command::Response(Response::RPL_TOPIC, ref args) if args.len() > 2 => {
+                self.handle_topic(&args[1], &args[2], None)
+            }
+            Command::Response(Response::RPL_TOPICWHOTIME, ref args) => {
+                self.handle_topic_who_time(args)
+            }
+            Command::Response(Response::RPL_BANLIST, ref args) => {
+                self.handle_mask_list(ChannelMode::Ban, args)
+            }
+            Command::Response(Response::RPL_ENDOFBANLIST, ref args) => {
+                self.handle_end_of_mask_list(ChannelMode::Ban, args)
+            }
+            Command::Response(Response::RPL_EXCEPTLIST, ref args) => {
+                self.handle_mask_list(ChannelMode::Exception, args)
+            }
+            Command::Response(Response::RPL_ENDOFEXCEPTLIST, ref args) => {
+                self.handle_end_of_mask_list(ChannelMode::Exception, args)
+            }
+            Command::Response(Response::RPL_INVITELIST, ref args) => {
+                self.handle_mask_list(ChannelMode::InviteException, args)
+            }
+            Command::Response(Response::RPL_ENDOFINVITELIST, ref args) => {
+                self.handle_end_of_mask_list(ChannelMode::InviteException, args)
+            }
             Command::Response(Response::RPL_ENDOFMOTD, _)
             | Command::Response(Response::ERR_NOMOTD, _) => {
                 self.send_nick_password()?;
                 self.send_umodes()?;
+                self.watch_primary_nickname()?;
+                self.connect_presence()?;
 
                 let config_chans = self.config().channels();
                 for chan in config_chans {
@@ -592,24 +1039,62 @@ impl ClientState {
                         None => self.send_join(chan)?,
                     }
                 }
-                let joined_chans = self.chanlists.read();
+                let joined_chans = self.registry.read().channel_names();
                 for chan in joined_chans
-                    .keys()
+                    .iter()
                     .filter(|x| !config_chans.iter().any(|c| c == *x))
                 {
                     self.send_join(chan)?
                 }
             }
+            // After registration, a rejected nickname change simply leaves the current nickname in
+            // place, so there's no need to fall back on an alternative.
             Command::Response(Response::ERR_NICKNAMEINUSE, _)
-            | Command::Response(Response::ERR_ERRONEOUSNICKNAME, _) => {
+            | Command::Response(Response::ERR_ERRONEOUSNICKNAME, _)
+                if !*self.registered.read() =>
+            {
                 let alt_nicks = self.config().alternate_nicknames();
                 let mut index = self.alt_nick_index.write();
 
-                if *index >= alt_nicks.len() {
-                    return Err(error::Error::NoUsableNick);
-                } else {
+                if *index < alt_nicks.len() {
                     self.send(NICK(alt_nicks[*index].to_owned()))?;
-                    *index += 1;
+                } else if let Some(nick) = self.generated_nickname(*index - alt_nicks.len()) {
+                    self.send(NICK(nick))?;
+                } else {
+                    self.registration.lock().fail(|| error::Error::NoUsableNick);
+                    return Err(error::Error::NoUsableNick);
+                }
+                *index += 1;
+            }
+            Command::Response(Response::RPL_MONOFFLINE, ref args) => {
+                if let (Some(targets), Ok(nick)) = (args.get(1), self.config().nickname()) {
+                    if self.wants_primary_nickname()
+                        && targets
+                            .split(',')
+                            .any(|target| target.eq_ignore_ascii_case(nick))
+                    {
+                        self.send(NICK(nick.to_owned()))?;
+                    }
+                }
+            }
+            Command::Response(Response::RPL_ISON, ref args) => {
+                let online = args.get(1).map_or("", |online| &online[..]);
+                // Replies come in the order the ISONs were sent, and those the user sent are
+                // theirs to make sense of.
+                let queried = match self.ison_pending.lock().pop_front() {
+                    Some(ison) if ison.polled => ison.nicknames,
+                    _ => return Ok(()),
+                };
+                self.presence.read().handle_ison(&queried, online);
+                if let Ok(nick) = self.config().nickname() {
+                    if self.wants_primary_nickname()
+                        && queried.iter().any(|n| n.eq_ignore_ascii_case(nick))
+                        && !online
+                            .split_whitespace()
+                            .any(|online| online.eq_ignore_ascii_case(nick))
+                    {
+                        self.send(NICK(nick.to_owned()))?;
+                    }
                 }
             }
             _ => (),
@@ -617,6 +1102,77 @@ impl ClientState {
         Ok(())
     }
 
+    /// Generates a nickname from the primary nickname for the given attempt, if nickname generation
+    /// is enabled and we haven't given up yet.
+    fn generated_nickname(&self, attempt: usize) -> Option<String> {
+        if !self.config().generate_nicks() || attempt >= MAX_GENERATED_NICKS {
+            return None;
+        }
+        let base = self.config().nickname().ok()?;
+        // Registration happens before `RPL_ISUPPORT` is sent, so NICKLEN is usually unknown here.
+        // The RFC limit of nine characters is a safe assumption unless our own nickname is longer.
+        let max_len = self
+            .isupport("NICKLEN")
+            .and_then(|len| len.parse().ok())
+            .unwrap_or_else(|| base.chars().count().max(9));
+        Some(generate_nickname(base, attempt, max_len))
+    }
+
+    /// Starts watching the primary nickname with MONITOR if we are configured to regain it and the
+    /// server supports it. Otherwise, the nickname will be polled with ISON by the `ClientStream`.
+    fn watch_primary_nickname(&self) -> error::Result<()> {
+        if self.wants_primary_nickname() && self.isupport("MONITOR").is_some() {
+            *self.monitoring_nickname.write() = true;
+            self.send(MONITOR(
+                "+".to_owned(),
+                Some(self.config().nickname()?.to_owned()),
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Starts using the presence tracker with this connection, which subscribes to the watched
+    /// nicknames with MONITOR as far as the server allows.
+    fn connect_presence(&self) -> error::Result<()> {
+        // A MONITOR token without a value means that there is no limit.
+        let monitor_limit = self
+            .isupport("MONITOR")
+            .map(|limit| limit.parse().ok().filter(|&n| n > 0).unwrap_or(usize::MAX));
+        self.presence
+            .read()
+            .connect(self.sender.clone(), monitor_limit)
+    }
+
+    fn handle_isupport(&self, args: &[String]) {
+        // The first argument is our nickname, and the last is a human-readable message.
+        if args.len() < 2 {
+            return;
+        }
+        let mut isupport = self.isupport.write();
+        for param in &args[1..args.len() - 1] {
+            if let Some(key) = param.strip_prefix('-') {
+                isupport.remove(key);
+            } else {
+                let (key, value) = param.split_once('=').unwrap_or((param, ""));
+                isupport.insert(key.to_owned(), value.to_owned());
+            }
+        }
+    }
+
+    /// Updates the enabled capabilities from a `CAP ACK` (`enable`) or `CAP DEL`.
+    fn handle_capabilities(&self, enable: bool, caps: &str) {
+        let mut capabilities = self.capabilities.write();
+        for cap in caps.split_whitespace() {
+            match cap.strip_prefix('-') {
+                Some(cap) => capabilities.remove(cap),
+                None if enable => capabilities.insert(cap.to_owned()),
+                None => capabilities.remove(cap),
+            };
+        }
+        self.sender.labels.lock().enabled =
+            capabilities.contains(Capability::LabeledResponse.as_ref());
+    }
+
     fn send_nick_password(&self) -> error::Result<()> {
         if self.config().nick_password().is_empty() {
             Ok(())
@@ -673,14 +1229,32 @@ impl ClientState {
     }
 
     #[cfg(not(feature = "channel-lists"))]
-    fn handle_join(&self, _: &str, _: &str) {}
+    fn handle_join(&self, _: &Message) {}
 
     #[cfg(feature = "channel-lists")]
-    fn handle_join(&self, src: &str, chan: &str) {
-        if let Some(vec) = self.chanlists.write().get_mut(&chan.to_owned()) {
-            if !src.is_empty() {
-                vec.push(User::new(src))
-            }
+    fn handle_join(&self, msg: &Message) {
+        let (chan, account, realname) = match msg.command {
+            JOIN(ref chan, ref account, ref realname) => (chan, account, realname),
+            _ => return,
+        };
+        let (src, prefix) = match (msg.source_nickname(), msg.prefix.as_ref()) {
+            (Some(src), Some(prefix)) if !src.is_empty() => (src, prefix.to_string()),
+            _ => return,
+        };
+
+        let mut registry = self.registry.write();
+        if src.eq_ignore_ascii_case(&self.current_nickname()) {
+            // We'll find ourselves in the NAMES reply that follows.
+            registry.add_channel(chan);
+        } else if registry.channel_mut(chan).is_some() {
+            registry.add_member(chan, User::new(&prefix));
+        }
+        // With extended-join, the key is the user's account and there's also their real name.
+        if let (Some(account), Some(realname)) = (account, realname) {
+            registry.update_user(src, |user| {
+                user.set_account(account);
+                user.set_realname(realname);
+            });
         }
     }
 
@@ -689,13 +1263,11 @@ impl ClientState {
 
     #[cfg(feature = "channel-lists")]
     fn handle_part(&self, src: &str, chan: &str) {
-        if let Some(vec) = self.chanlists.write().get_mut(&chan.to_owned()) {
-            if !src.is_empty() {
-                if let Some(n) = vec.iter().position(|x| x.get_nickname() == src) {
-                    vec.swap_remove(n);
-                }
-            }
+        if src.is_empty() {
+            return;
         }
+
+        self.registry.write().remove_member(chan, src);
     }
 
     #[cfg(not(feature = "channel-lists"))]
@@ -707,11 +1279,7 @@ impl ClientState {
             return;
         }
 
-        for vec in self.chanlists.write().values_mut() {
-            if let Some(p) = vec.iter().position(|x| x.get_nickname() == src) {
-                vec.swap_remove(p);
-            }
-        }
+        self.registry.write().remove_user(src);
     }
 
     #[cfg(not(feature = "channel-lists"))]
@@ -723,33 +1291,104 @@ impl ClientState {
             return;
         }
 
-        for (_, vec) in self.chanlists.write().iter_mut() {
-            if let Some(n) = vec.iter().position(|x| x.get_nickname() == old_nick) {
-                let new_entry = User::new(new_nick);
-                vec[n] = new_entry;
-            }
+        self.registry.write().rename_user(old_nick, new_nick);
+    }
+
+    #[cfg(not(feature = "channel-lists"))]
+    fn handle_mode(&self, _: &str, _: &[Mode<ChannelMode>], _: Option<&str>) {}
+
+    #[cfg(feature = "channel-lists")]
+    fn handle_mode(&self, chan: &str, modes: &[Mode<ChannelMode>], setter: Option<&str>) {
+        self.registry
+            .write()
+            .update_modes(chan, modes, setter, &self.prefix_map());
+    }
+
+    #[cfg(not(feature = "channel-lists"))]
+    fn handle_channel_modes(&self, _: &[String]) {}
+
+    #[cfg(feature = "channel-lists")]
+    fn handle_channel_modes(&self, args: &[String]) {
+        if args.len() < 3 {
+            return;
+        }
+        let pieces: Vec<_> = args[2..].iter().map(|s| &s[..]).collect();
+        let prefix_map = self.prefix_map();
+        let modes = parse_modes(&pieces, &prefix_map, self.isupport("CHANMODES").as_deref());
+        if let Some(channel) = self.registry.write().channel_mut(&args[1]) {
+            channel.reset_modes(&modes, &prefix_map);
         }
     }
 
     #[cfg(not(feature = "channel-lists"))]
-    fn handle_mode(&self, _: &str, _: &[Mode<ChannelMode>]) {}
+    fn handle_topic(&self, _: &str, _: &str, _: Option<&Message>) {}
 
     #[cfg(feature = "channel-lists")]
-    fn handle_mode(&self, chan: &str, modes: &[Mode<ChannelMode>]) {
-        for mode in modes {
-            match *mode {
-                Mode::Plus(_, Some(ref user)) | Mode::Minus(_, Some(ref user)) => {
-                    if let Some(vec) = self.chanlists.write().get_mut(chan) {
-                        if let Some(n) = vec.iter().position(|x| x.get_nickname() == user) {
-                            vec[n].update_access_level(mode)
-                        }
-                    }
-                }
-                _ => (),
+    fn handle_topic(&self, chan: &str, topic: &str, msg: Option<&Message>) {
+        if let Some(channel) = self.registry.write().channel_mut(chan) {
+            channel.set_topic(topic);
+            // A TOPIC message means the topic was just changed by its source.
+            if let Some(msg) = msg {
+                let setter = msg.prefix.as_ref().map(|prefix| prefix.to_string());
+                channel.set_topic_setter(setter.as_deref(), Some(chrono::Utc::now()));
             }
         }
     }
 
+    #[cfg(not(feature = "channel-lists"))]
+    fn handle_topic_who_time(&self, _: &[String]) {}
+
+    #[cfg(feature = "channel-lists")]
+    fn handle_topic_who_time(&self, args: &[String]) {
+        if args.len() < 4 {
+            return;
+        }
+        if let Some(channel) = self.registry.write().channel_mut(&args[1]) {
+            channel.set_topic_setter(Some(&args[2]), parse_timestamp(&args[3]));
+        }
+    }
+
+    #[cfg(not(feature = "channel-lists"))]
+    fn handle_creation_time(&self, _: &[String]) {}
+
+    #[cfg(feature = "channel-lists")]
+    fn handle_creation_time(&self, args: &[String]) {
+        if args.len() < 3 {
+            return;
+        }
+        if let Some(channel) = self.registry.write().channel_mut(&args[1]) {
+            channel.set_creation_time(parse_timestamp(&args[2]));
+        }
+    }
+
+    #[cfg(not(feature = "channel-lists"))]
+    fn handle_mask_list(&self, _: ChannelMode, _: &[String]) {}
+
+    #[cfg(feature = "channel-lists")]
+    fn handle_mask_list(&self, mode: ChannelMode, args: &[String]) {
+        if args.len() < 3 {
+            return;
+        }
+        if let Some(channel) = self.registry.write().channel_mut(&args[1]) {
+            let set_at = args.get(4).and_then(|time| parse_timestamp(time));
+            let entry = MaskEntry::new(&args[2], args.get(3).map(|s| &s[..]), set_at);
+            channel.add_mask_entry(&mode, entry);
+        }
+    }
+
+    #[cfg(not(feature = "channel-lists"))]
+    fn handle_end_of_mask_list(&self, _: ChannelMode, _: &[String]) {}
+
+    #[cfg(feature = "channel-lists")]
+    fn handle_end_of_mask_list(&self, mode: ChannelMode, args: &[String]) {
+        if args.len() < 2 {
+            return;
+        }
+        if let Some(channel) = self.registry.write().channel_mut(&args[1]) {
+            channel.end_mask_list(&mode);
+        }
+    }
+
     #[cfg(not(feature = "channel-lists"))]
     fn handle_namreply(&self, _: &[String]) {}
 
@@ -757,13 +1396,80 @@ impl ClientState {
     fn handle_namreply(&self, args: &[String]) {
         if args.len() == 4 {
             let chan = &args[2];
+            let prefix_map = self.prefix_map();
+            let mut registry = self.registry.write();
+            // With userhost-in-names, each entry is a full `nick!user@host` mask.
             for user in args[3].split(' ') {
-                self.chanlists
-                    .write()
-                    .entry(chan.clone())
-                    .or_insert_with(Vec::new)
-                    .push(User::new(user))
+                registry.add_member(chan, User::with_prefix_map(user, &prefix_map))
+            }
+        }
+    }
+
+    #[cfg(not(feature = "channel-lists"))]
+    fn handle_user_info(&self, _: &Message) {}
+
+    /// Keeps track of users' accounts, away statuses, hosts and real names.
+    #[cfg(feature = "channel-lists")]
+    fn handle_user_info(&self, msg: &Message) {
+        let updates_user = match msg.command {
+            Command::ACCOUNT(_) | Command::AWAY(_) | Command::CHGHOST(..) => true,
+            Command::Raw(ref cmd, _) => cmd.eq_ignore_ascii_case("SETNAME"),
+            Command::Response(resp, _) => matches!(
+                resp,
+                Response::RPL_AWAY | Response::RPL_WHOREPLY | Response::RPL_WHOSPCRPL
+            ),
+            _ => false,
+        };
+        if !updates_user && msg.account().is_none() {
+            return;
+        }
+
+        let mut registry = self.registry.write();
+        let src = msg.source_nickname().unwrap_or("");
+
+        // With account-tag, messages from users carry the account they're logged into.
+        if let Some(account) = msg.account() {
+            registry.update_user(src, |user| user.set_account(account));
+        }
+
+        match msg.command {
+            Command::ACCOUNT(ref account) => {
+                registry.update_user(src, |user| user.set_account(account))
+            }
+            Command::AWAY(ref message) => registry.update_user(src, |user| {
+                user.set_away(message.is_some(), message.as_deref())
+            }),
+            Command::CHGHOST(ref username, ref hostname) => registry.update_user(src, |user| {
+                user.set_username(username);
+                user.set_hostname(hostname);
+            }),
+            Command::Raw(ref cmd, ref args) if cmd.eq_ignore_ascii_case("SETNAME") => {
+                if let Some(realname) = args.last() {
+                    registry.update_user(src, |user| user.set_realname(realname))
+                }
+            }
+            Command::Response(Response::RPL_AWAY, ref args) if args.len() > 2 => {
+                registry.update_user(&args[1], |user| user.set_away(true, Some(&args[2])))
             }
+            // `352 <client> <channel> <user> <host> <server> <nick> <flags> :<hopcount> <realname>`
+            Command::Response(Response::RPL_WHOREPLY, ref args) if args.len() > 7 => registry
+                .update_user(&args[5], |user| {
+                    user.set_username(&args[2]);
+                    user.set_hostname(&args[3]);
+                    if args[6].starts_with('G') {
+                        let message = user.away_message().map(|m| m.to_owned());
+                        user.set_away(true, message.as_deref());
+                    } else {
+                        user.set_away(false, None);
+                    }
+                    if let Some((_, realname)) = args[7].split_once(' ') {
+                        user.set_realname(realname);
+                    }
+                }),
+            Command::Response(Response::RPL_WHOSPCRPL, ref args) if !args.is_empty() => {
+                registry.handle_whox_reply(&args[1..])
+            }
+            _ => (),
         }
     }
 
@@ -812,13 +1518,121 @@ impl ClientState {
 /// Thread-safe sender that can be used with the client.
 #[derive(Debug, Clone)]
 pub struct Sender {
-    tx_outgoing: UnboundedSender<Message>,
+    /// The messages waiting to be sent, which may be bounded.
+    queue: Arc<Queue>,
+    /// The priority to send messages with, instead of their default one.
+    priority: Option<Priority>,
+    /// The labeled messages waiting for their responses.
+    labels: Arc<Mutex<Labels>>,
+    /// The progress of quitting the server.
+    shutdown: Arc<Mutex<Shutdown>>,
 }
 
 impl Sender {
-    /// Send a single message to the unbounded queue.
+    /// Send a single message to the queue. This ignores the
+    /// [capacity](Config::outgoing_queue_capacity) of a bounded queue, so that the client's own
+    /// replies to the server are never held back; see [`send_async`](Sender::send_async) and
+    /// [`try_send`](Sender::try_send) to respect it.
     pub fn send<M: Into<Message>>(&self, msg: M) -> error::Result<()> {
-        Ok(self.tx_outgoing.send(msg.into())?)
+        let msg = msg.into();
+        let priority = self.priority_of(&msg);
+        self.queue.push(msg, priority, false)
+    }
+
+    /// Sends a single message to the queue, waiting for there to be room in it if it is bounded
+    /// and full. Room is only made while the client's stream is being polled.
+    pub async fn send_async<M: Into<Message>>(&self, msg: M) -> error::Result<()> {
+        let msg = msg.into();
+        let priority = self.priority_of(&msg);
+        self.queue.push_async(msg, priority).await
+    }
+
+    /// Sends a single message to the queue, failing with [`QueueFull`](error::Error::QueueFull)
+    /// if it is bounded and full.
+    pub fn try_send<M: Into<Message>>(&self, msg: M) -> error::Result<()> {
+        let msg = msg.into();
+        let priority = self.priority_of(&msg);
+        self.queue.push(msg, priority, true)
+    }
+
+    /// Gets a `Sender` that sends every message with the given [`Priority`], instead of the one
+    /// that it would have by default. See [`queue`] for details.
+    pub fn with_priority(&self, priority: Priority) -> Sender {
+        Sender {
+            priority: Some(priority),
+            ..self.clone()
+        }
+    }
+
+    /// Removes the messages for a target (i.e. a channel or user) that are still waiting in the
+    /// queue to be sent, returning how many there were. Targets are compared ignoring ASCII case.
+    pub fn cancel_queued(&self, target: &str) -> usize {
+        self.queue.cancel(target)
+    }
+
+    /// Gets the number of messages waiting in the queue to be sent.
+    pub fn queue_depth(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Gets the priority to send a message with.
+    fn priority_of(&self, msg: &Message) -> Priority {
+        self.priority.unwrap_or_else(|| Priority::of(msg))
+    }
+
+    /// Sends a message with a unique `label` tag, and waits for the server's response to it. The
+    /// response is either a single message (an `ACK` if there was nothing else to respond with),
+    /// or a `labeled-response` batch, in which case all of the batch's messages are returned,
+    /// starting with the `BATCH` command opening it and ending with the one closing it.
+    ///
+    /// This requires the server to have enabled the `labeled-response` capability (see
+    /// [`Capability::LabeledResponse`]). Like [`Client::whois`], this only completes while the
+    /// client's stream is being polled, and fails if the server takes longer than the configured
+    /// [query timeout](Config::query_timeout) to respond. The response is still yielded by the
+    /// stream.
+    pub async fn send_labeled<M: Into<Message>>(&self, msg: M) -> error::Result<Vec<Message>> {
+        let msg = msg.into();
+        let (label, response, timeout) = {
+            let mut labels = self.labels.lock();
+            if !labels.enabled {
+                return Err(error::Error::LabeledResponseUnsupported);
+            }
+            let (label, response) = labels.add();
+            (label, response, labels.timeout)
+        };
+        let _guard = LabelGuard {
+            labels: &self.labels,
+            label: label.clone(),
+        };
+        self.send(msg.with_tag(Tag::label(label)))?;
+
+        match time::timeout(timeout, response).await {
+            Ok(response) => response.map_err(|_| error::Error::OneShotCanceled),
+            Err(_) => Err(error::Error::QueryTimeout),
+        }
+    }
+
+    /// Quits the server gracefully with a message, which defaults to `Powered by Rust.` if empty.
+    /// This sends a `QUIT` once every other message in the queue has been sent, and waits for the
+    /// server to close the connection, or for the configured [quit timeout](Config::quit_timeout)
+    /// to elapse. The client's stream then ends without an error.
+    ///
+    /// Like [`Client::whois`], this only completes while the client's stream is being polled. If
+    /// the client is already quitting, this only waits for it to finish.
+    pub async fn quit<S: fmt::Display>(&self, msg: S) -> error::Result<()> {
+        let (send, closed) = self.shutdown.lock().quit();
+        if send {
+            let msg = msg.to_string();
+            self.queue.push_quit(
+                QUIT(Some(if msg.is_empty() {
+                    "Powered by Rust.".to_string()
+                } else {
+                    msg
+                }))
+                .into(),
+            )?;
+        }
+        closed.await.map_err(|_| error::Error::OneShotCanceled)
     }
 
     pub_state_base!();
@@ -830,8 +1644,10 @@ impl Sender {
 /// Note: this is essentially the same as a version of [SendAll](https://github.com/rust-lang-nursery/futures-rs/blob/master/futures-util/src/sink/send_all.rs) that owns it's sink and stream.
 #[derive(Debug)]
 pub struct Outgoing {
-    sink: SplitSink<Connection, Message>,
-    stream: UnboundedReceiver<Message>,
+    sink: SplitSink<Receiving, Message>,
+    /// The messages sent by the connection itself, like `PING` and `PONG`, which skip the queue.
+    protocol: UnboundedReceiver<Message>,
+    queue: Arc<Queue>,
     buffered: Option<Message>,
 }
 
@@ -856,11 +1672,16 @@ impl Outgoing {
 impl FusedFuture for Outgoing {
     fn is_terminated(&self) -> bool {
         // NB: outgoing stream never terminates.
-        // TODO: should it terminate if rx_outgoing is terminated?
         false
     }
 }
 
+impl Drop for Outgoing {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
 impl Future for Outgoing {
     type Output = error::Result<()>;
 
@@ -872,19 +1693,19 @@ impl Future for Outgoing {
         }
 
         loop {
-            match this.stream.poll_recv(cx) {
-                Poll::Ready(Some(message)) => ready!(this.try_start_send(cx, message))?,
-                Poll::Ready(None) => {
-                    ready!(Pin::new(&mut this.sink).poll_flush(cx))?;
-                    return Poll::Ready(Ok(()));
-                }
-                Poll::Pending => {
-                    ready!(Pin::new(&mut this.sink).poll_flush(cx))?;
-                    return Poll::Pending;
-                }
-            }
-        }
-    }
+            let message = match this.protocol.poll_recv(cx) {
+                Poll::Ready(Some(message)) => message,
+                _ => match this.queue.poll_pop(cx) {
+                    Poll::Ready(message) => message,
+                    Poll::Pending => {
+                        ready!(Pin::new(&mut this.sink).poll_flush(cx))?;
+                        return Poll::Pending;
+                    }
+                },
+            };
+            ready!(this.try_start_send(cx, message))?
+        }
+    }
 }
 
 /// The canonical implementation of a connection to an IRC server.
@@ -894,9 +1715,11 @@ impl Future for Outgoing {
 pub struct Client {
     /// The internal, thread-safe server state.
     state: Arc<ClientState>,
-    incoming: Option<SplitStream<Connection>>,
+    incoming: Option<SplitStream<Receiving>>,
     outgoing: Option<Outgoing>,
     sender: Sender,
+    /// The lag measured by the connection's pings.
+    lag: watch::Receiver<Lag>,
     #[cfg(test)]
     /// A view of the logs for a mock connection.
     view: Option<self::transport::LogView>,
@@ -925,28 +1748,115 @@ impl Client {
     /// single, shared event loop. It can also be used to take more control over execution and error
     /// handling. Connection will not occur until the event loop is run.
     pub async fn from_config(config: Config) -> error::Result<Client> {
-        let (tx_outgoing, rx_outgoing) = mpsc::unbounded_channel();
-        let conn = Connection::new(&config, tx_outgoing.clone()).await?;
+        Client::connect(config, StatusTracker::new()).await
+    }
 
+    /// Like [`Client::from_config`], but also returns a receiver for the connection's status right
+    /// away, so that the stages before the connection is established (i.e.
+    /// [`Connecting`](ConnectionState::Connecting) and
+    /// [`TlsHandshake`](ConnectionState::TlsHandshake)) can be watched too, along with a failure to
+    /// connect. Once connected, the receiver is like one from [`Client::connection_status`].
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use irc::client::prelude::*;
+    /// # #[tokio::main]
+    /// # async fn main() -> irc::error::Result<()> {
+    /// let (mut status, client) = Client::connect_with_status(Config::load("config.toml")?);
+    /// tokio::spawn(async move {
+    ///     while status.changed().await.is_ok() {
+    ///         println!("{:?}", status.borrow().state);
+    ///     }
+    /// });
+    /// let client = client.await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn connect_with_status(
+        config: Config,
+    ) -> (
+        watch::Receiver<ConnectionStatus>,
+        impl Future<Output = error::Result<Client>>,
+    ) {
+        let status = StatusTracker::new();
+        (status.subscribe(), Client::connect(config, status))
+    }
+
+    async fn connect(config: Config, status: StatusTracker) -> error::Result<Client> {
+        let (tx_protocol, rx_protocol) = mpsc::unbounded_channel();
+        let conn = Connection::new(&config, tx_protocol, &status).await?;
+        Ok(Client::from_connection(config, conn, rx_protocol, status))
+    }
+
+    /// Creates a new `Client` from the specified configuration, communicating with the server over
+    /// a stream that is already connected to it, e.g. a Unix domain socket to a local bouncer, or
+    /// a tunnel of your own. The configuration's connection settings (like the server, port, TLS
+    /// and proxy) go unused, but everything else, like the encoding and pings, applies as usual.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use irc::client::prelude::*;
+    /// # #[tokio::main]
+    /// # async fn main() -> irc::error::Result<()> {
+    /// # #[cfg(unix)]
+    /// # {
+    /// let stream = tokio::net::UnixStream::connect("/run/bouncer.sock").await?;
+    /// let client = Client::from_stream(Config::load("config.toml")?, stream)?;
+    /// client.identify()?;
+    /// # }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_stream<S>(config: Config, stream: S) -> error::Result<Client>
+    where
+        S: AsyncStream + 'static,
+    {
+        let (tx_protocol, rx_protocol) = mpsc::unbounded_channel();
+        let status = StatusTracker::new();
+        let conn = Connection::from_stream(&config, stream, tx_protocol)?;
+        status.set(ConnectionState::Connected);
+        Ok(Client::from_connection(config, conn, rx_protocol, status))
+    }
+
+    fn from_connection(
+        config: Config,
+        conn: Connection,
+        rx_protocol: UnboundedReceiver<Message>,
+        status: StatusTracker,
+    ) -> Client {
         #[cfg(test)]
         let view = conn.log_view();
-
-        let (sink, incoming) = conn.split();
-
-        let sender = Sender { tx_outgoing };
-
-        Ok(Client {
+        let lag = conn.lag();
+
+        let (sink, incoming) = Receiving(conn).split();
+
+        let timeout = Duration::from_secs(u64::from(config.query_timeout()));
+        let queue = Arc::new(Queue::new(
+            config
+                .outgoing_queue_capacity()
+                .map(|capacity| capacity as usize),
+        ));
+        let sender = Sender {
+            queue: Arc::clone(&queue),
+            priority: None,
+            labels: Arc::new(Mutex::new(Labels::new(timeout))),
+            shutdown: Arc::new(Mutex::new(Shutdown::default())),
+        };
+
+        Client {
             sender: sender.clone(),
-            state: Arc::new(ClientState::new(sender, config)),
+            state: Arc::new(ClientState::new(sender, config, status)),
             incoming: Some(incoming),
+            lag,
             outgoing: Some(Outgoing {
                 sink,
-                stream: rx_outgoing,
+                protocol: rx_protocol,
+                queue,
                 buffered: None,
             }),
             #[cfg(test)]
             view,
-        })
+        }
     }
 
     /// Gets the log view from the internal transport. Only used for unit testing.
@@ -992,21 +1902,28 @@ impl Client {
             state: Arc::clone(&self.state),
             stream,
             outgoing: self.outgoing.take(),
+            ison_interval: None,
+            quit_timeout: None,
+            finished: false,
+            parse_error: None,
         })
     }
 
+    /// Gets a stream of typed [`Events`](event::Event) from the `Client`'s connection. This is an
+    /// alternative to [`stream`](Client::stream) for clients that would rather not match on raw
+    /// messages themselves, though every event still carries the message that caused it.
+    ///
+    /// **Note**: This takes the same underlying stream as `stream`, so only one of the two can be
+    /// used, and only once.
+    pub fn events(&mut self) -> error::Result<event::EventStream> {
+        self.stream().map(event::EventStream::new)
+    }
+
     /// Gets a list of currently joined channels. This will be `None` if tracking is disabled
     /// altogether by disabling the `channel-lists` feature.
     #[cfg(feature = "channel-lists")]
     pub fn list_channels(&self) -> Option<Vec<String>> {
-        Some(
-            self.state
-                .chanlists
-                .read()
-                .keys()
-                .map(|k| k.to_owned())
-                .collect(),
-        )
+        Some(self.state.registry.read().channel_names())
     }
 
     /// Always returns `None` since `channel-lists` feature is disabled.
@@ -1036,7 +1953,7 @@ impl Client {
     /// ```
     #[cfg(feature = "channel-lists")]
     pub fn list_users(&self, chan: &str) -> Option<Vec<User>> {
-        self.state.chanlists.read().get(&chan.to_owned()).cloned()
+        self.state.registry.read().channel_users(chan)
     }
 
     /// Always returns `None` since `channel-lists` feature is disabled.
@@ -1045,13 +1962,358 @@ impl Client {
         None
     }
 
+    /// Gets the state of the specified channel, including its topic, modes and users. If the
+    /// specified channel hasn't been joined or the `channel-lists` feature is disabled, this
+    /// function will return `None`.
+    ///
+    /// Ban, exception and invite exception lists are only known once they've been fetched with
+    /// [`Client::fetch_channel_list`].
+    #[cfg(feature = "channel-lists")]
+    pub fn channel(&self, chan: &str) -> Option<Channel> {
+        self.state.registry.read().channel(chan)
+    }
+
+    /// Always returns `None` since `channel-lists` feature is disabled.
+    #[cfg(not(feature = "channel-lists"))]
+    pub fn channel(&self, _: &str) -> Option<Channel> {
+        None
+    }
+
+    /// Gets what is known about the specified user, including their account, away status and real
+    /// name. Users are only tracked while they share a channel with the client, so this will return
+    /// `None` for anyone else or if the `channel-lists` feature is disabled.
+    ///
+    /// The more of the `extended-join`, `account-notify`, `account-tag`, `away-notify`, `chghost`,
+    /// `setname` and `userhost-in-names` capabilities the server supports, the more accurate this
+    /// will be. WHO and WHOX replies are used to fill in the gaps.
+    #[cfg(feature = "channel-lists")]
+    pub fn user(&self, nickname: &str) -> Option<User> {
+        self.state.registry.read().user(nickname)
+    }
+
+    /// Always returns `None` since `channel-lists` feature is disabled.
+    #[cfg(not(feature = "channel-lists"))]
+    pub fn user(&self, _: &str) -> Option<User> {
+        None
+    }
+
+    /// Asks the server for one of the specified channel's lists, i.e. its bans, ban exceptions, or
+    /// invite exceptions. Once the server has replied, the list is available through
+    /// [`Client::channel`] and is kept up to date from then on.
+    pub fn fetch_channel_list(&self, chan: &str, list: ListMode) -> error::Result<()> {
+        self.send(ChannelMODE(
+            chan.to_owned(),
+            vec![Mode::Plus(list.into(), None)],
+        ))
+    }
+
+    /// Gets the channel membership prefixes supported by the server, ranked as the server ranks
+    /// them. Until the server advertises its PREFIX token, the common `(qaohv)~&@%+` prefixes are
+    /// assumed.
+    pub fn prefix_map(&self) -> PrefixMap {
+        self.state.prefix_map()
+    }
+
+    /// Asks the server about the specified user with `WHOIS`.
+    ///
+    /// Like the other queries, this only completes while the client's [stream](Client::stream) is
+    /// being polled, and fails if the server takes longer than the configured
+    /// [query timeout](Config::query_timeout) to answer. The messages answering the query are
+    /// still yielded by the stream.
+    pub async fn whois(&self, nickname: &str) -> error::Result<WhoisInfo> {
+        let query = Query::Whois(nickname.to_owned());
+        let replies = self
+            .query(query, Command::WHOIS(None, nickname.to_owned()))
+            .await?;
+        query::parse_whois(nickname, &replies)
+    }
+
+    /// Asks the server for the users matching the specified mask (e.g. a channel) with `WHO`.
+    ///
+    /// If the server supports WHOX, the query is sent with a token so that its replies can't be
+    /// confused with those of other `WHO` queries.
+    pub async fn who(&self, mask: &str) -> error::Result<Vec<WhoReply>> {
+        let (query, command) = if self.state.isupport("WHOX").is_some() {
+            let token = self.state.queries.lock().whox_token();
+            let spec = format!("{},{}", query::WHOX_FIELDS, token);
+            let command = Command::Raw("WHO".to_owned(), vec![mask.to_owned(), spec]);
+            (Query::Who(mask.to_owned(), Some(token)), command)
+        } else {
+            let command = Command::WHO(Some(mask.to_owned()), None);
+            (Query::Who(mask.to_owned(), None), command)
+        };
+        let replies = self.query(query, command).await?;
+        query::parse_who(&replies)
+    }
+
+    /// Asks the server for the users in the specified channel with `NAMES`.
+    pub async fn names(&self, chan: &str) -> error::Result<Vec<User>> {
+        let query = Query::Names(chan.to_owned());
+        let replies = self
+            .query(query, Command::NAMES(Some(chan.to_owned()), None))
+            .await?;
+        query::parse_names(&replies, &self.prefix_map())
+    }
+
+    /// Asks the server for its channels with `LIST`, optionally filtered (e.g. by a comma-separated
+    /// list of channels, or by a server-specific filter like `>10`).
+    pub async fn list(&self, filter: Option<&str>) -> error::Result<Vec<ListEntry>> {
+        let command = Command::LIST(filter.map(|f| f.to_owned()), None);
+        let replies = self.query(Query::List, command).await?;
+        query::parse_list(&replies)
+    }
+
+    /// Asks the server for the modes of the specified channel.
+    pub async fn channel_modes(&self, chan: &str) -> error::Result<Vec<Mode<ChannelMode>>> {
+        let query = Query::ChannelModes(chan.to_owned());
+        let replies = self
+            .query(query, ChannelMODE(chan.to_owned(), Vec::new()))
+            .await?;
+        let chanmodes = self.state.isupport("CHANMODES");
+        query::parse_channel_modes(&replies, &self.prefix_map(), chanmodes.as_deref())
+    }
+
+    /// Asks the server for the topic of the specified channel, which is `None` if no topic is set.
+    pub async fn topic(&self, chan: &str) -> error::Result<Option<String>> {
+        let query = Query::Topic(chan.to_owned());
+        let replies = self.query(query, TOPIC(chan.to_owned(), None)).await?;
+        query::parse_topic(&replies)
+    }
+
+    /// Asks the server for history with `CHATHISTORY`, getting at most `limit` messages (or fewer
+    /// if the server has a lower limit).
+    ///
+    /// This requires the server to have enabled the `draft/chathistory` (or `chathistory`)
+    /// capability, as well as `batch` and preferably `server-time` and `message-tags`. The
+    /// replayed messages are still yielded by the stream, but the client doesn't act on them, e.g.
+    /// by answering old CTCP requests or tracking old nickname changes.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use irc::client::prelude::*;
+    /// use irc::client::query::HistoryRequest;
+    ///
+    /// # async fn example(client: &Client) -> irc::error::Result<()> {
+    /// let request = HistoryRequest::Latest {
+    ///     target: "#rust".to_owned(),
+    ///     after: None,
+    /// };
+    /// for entry in client.chathistory(request, 50).await? {
+    ///     print!("[{:?}] {}", entry.time, entry.message);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn chathistory(
+        &self,
+        request: HistoryRequest,
+        limit: usize,
+    ) -> error::Result<Vec<HistoryMessage>> {
+        let supported = {
+            let capabilities = self.state.capabilities.read();
+            capabilities.contains("draft/chathistory") || capabilities.contains("chathistory")
+        };
+        if !supported {
+            return Err(error::Error::ChatHistoryUnsupported);
+        }
+
+        // The server's limit on messages per request, where 0 means that there is no limit.
+        let limit = match self
+            .state
+            .isupport("CHATHISTORY")
+            .and_then(|l| l.parse().ok())
+        {
+            Some(max) if max > 0 => limit.min(max),
+            _ => limit,
+        };
+        let replies = self.query(request.query(), request.command(limit)).await?;
+        query::parse_history(&replies)
+    }
+
+    /// Sends a message to the specified target, and waits for the server to confirm its delivery by
+    /// echoing it back. This requires the server to have enabled the `echo-message` capability (see
+    /// [`Capability::EchoMessage`]). Unlike [`send_privmsg`](Client::send_privmsg), the message is
+    /// never split into several, so it fails with [`Error::LineBreak`](error::Error::LineBreak) if
+    /// the target or the message contains a line break.
+    ///
+    /// When the server supports `labeled-response`, the echo is found by its label. Otherwise, it
+    /// is the first echo of a message with the same target and text, or failing that, since
+    /// servers may alter the text (e.g. by stripping formatting), the first echo to that target. Like
+    /// [`whois`](Client::whois), this only completes while the client's stream is being polled,
+    /// and fails if the server takes longer than the configured
+    /// [query timeout](Config::query_timeout) to respond.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use irc::client::prelude::*;
+    /// # async fn example(client: &Client) -> irc::error::Result<()> {
+    /// let delivery = client.send_privmsg_confirmed("#rust", "Hello!").await?;
+    /// println!("Delivered as {:?} at {:?}", delivery.msgid, delivery.time);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_privmsg_confirmed<S1, S2>(
+        &self,
+        target: S1,
+        message: S2,
+    ) -> error::Result<Delivery>
+    where
+        S1: fmt::Display,
+        S2: fmt::Display,
+    {
+        if !self
+            .state
+            .capabilities
+            .read()
+            .contains(Capability::EchoMessage.as_ref())
+        {
+            return Err(error::Error::EchoMessageUnsupported);
+        }
+        let (target, message) = (target.to_string(), message.to_string());
+        if target.contains(['\r', '\n']) || message.contains(['\r', '\n']) {
+            return Err(error::Error::LineBreak);
+        }
+
+        let labeled = self.sender.labels.lock().enabled;
+        if labeled {
+            let replies = self.sender.send_labeled(PRIVMSG(target, message)).await?;
+            return query::parse_delivery(&replies);
+        }
+
+        let (id, echo) = self.state.echoes.lock().add(&target, &message);
+        let _guard = EchoGuard {
+            echoes: &self.state.echoes,
+            id,
+        };
+        self.send(PRIVMSG(target, message))?;
+
+        let timeout = Duration::from_secs(u64::from(self.state.config().query_timeout()));
+        match time::timeout(timeout, echo).await {
+            Ok(echo) => echo
+                .map(Delivery::new)
+                .map_err(|_| error::Error::OneShotCanceled),
+            Err(_) => Err(error::Error::QueryTimeout),
+        }
+    }
+
+    /// Checks whether a message is the server echoing back one of our own `PRIVMSG`s, `NOTICE`s or
+    /// `TAGMSG`s, which it does once it has enabled `echo-message`. Bots will usually want to
+    /// ignore these, or set [`suppress_echoes`](Config::suppress_echoes) to leave them out of the
+    /// stream altogether.
+    pub fn is_echo(&self, message: &Message) -> bool {
+        self.state.is_echo(message)
+    }
+
+    /// Gets the tracker for the presence of other users on this connection. See [`presence`] for
+    /// details.
+    pub fn presence(&self) -> Presence {
+        self.state.presence.read().clone()
+    }
+
+    /// Replaces this client's presence tracker with another one, typically the tracker of a
+    /// previous connection after reconnecting. Its watched nicknames are subscribed to once the
+    /// client is registered, or right away if it already is.
+    pub fn use_presence(&self, presence: Presence) -> error::Result<()> {
+        *self.state.presence.write() = presence;
+        if *self.state.registered.read() {
+            self.state.connect_presence()?;
+        }
+        Ok(())
+    }
+
+    /// Sends a query's command and waits for the server to answer it. When the server supports
+    /// `labeled-response`, the answer is found by its label rather than by its numerics.
+    async fn query(&self, query: Query, command: Command) -> error::Result<Vec<Message>> {
+        let labeled = self.sender.labels.lock().enabled;
+        if labeled {
+            return self.sender.send_labeled(command).await;
+        }
+
+        let (id, replies) = self.state.queries.lock().add(query);
+        let _guard = QueryGuard {
+            queries: &self.state.queries,
+            id,
+        };
+        self.send(command)?;
+
+        let timeout = Duration::from_secs(u64::from(self.state.config().query_timeout()));
+        match time::timeout(timeout, replies).await {
+            Ok(replies) => replies.map_err(|_| error::Error::OneShotCanceled),
+            Err(_) => Err(error::Error::QueryTimeout),
+        }
+    }
+
     /// Gets the current nickname in use. This may be the primary username set in the configuration,
-    /// or it could be any of the alternative nicknames listed as well. As a result, this is the
-    /// preferred way to refer to the client's nickname.
-    pub fn current_nickname(&self) -> &str {
+    /// any of the alternative nicknames listed as well, or a nickname the client was renamed to
+    /// afterwards (by us, by services, or by an operator). As a result, this is the preferred way
+    /// to refer to the client's nickname.
+    pub fn current_nickname(&self) -> String {
         self.state.current_nickname()
     }
 
+    /// Gets a receiver that is notified whenever the connection moves to another stage of its
+    /// lifecycle, e.g. from negotiating capabilities to registering. Each status carries when the
+    /// connection entered its state, along with the history of the states before it. The client
+    /// only exists once connected, so use [`Client::connect_with_status`] to watch the connection
+    /// being established.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use irc::client::prelude::*;
+    /// use irc::client::conn::ConnectionState;
+    ///
+    /// # async fn example(client: &Client) {
+    /// let status = client.connection_status();
+    /// let healthy = status.borrow().state == ConnectionState::Registered;
+    /// # }
+    /// ```
+    pub fn connection_status(&self) -> watch::Receiver<ConnectionStatus> {
+        self.state.status.subscribe()
+    }
+
+    /// Gets a receiver that is notified whenever the lag to the server changes. The lag is measured
+    /// from the `PING`s the client sends every [`ping_time`](Config::ping_time) seconds once it is
+    /// registered, and is flagged as [`lagging`](Lag::lagging) once it exceeds the configured
+    /// [`lag_warning`](Config::lag_warning), which gives a chance to react before the connection
+    /// times out.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use irc::client::prelude::*;
+    /// # async fn example(client: &Client) {
+    /// let mut lag = client.lag();
+    /// while lag.changed().await.is_ok() {
+    ///     let lag = *lag.borrow();
+    ///     if lag.lagging {
+    ///         eprintln!("Lagging! Last round trip took {:?}", lag.last);
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn lag(&self) -> watch::Receiver<Lag> {
+        self.lag.clone()
+    }
+
+    /// Gets the number of messages waiting in the outgoing queue to be sent, which can be used to
+    /// shed load before the queue grows too long. See [`Sender::send_async`] for waiting on a
+    /// bounded queue instead.
+    pub fn queue_depth(&self) -> usize {
+        self.sender.queue_depth()
+    }
+
+    /// Removes the messages for a target (i.e. a channel or user) that are still waiting in the
+    /// outgoing queue to be sent, returning how many there were. This can be used to drop output
+    /// that has gone stale. Targets are compared ignoring ASCII case.
+    pub fn cancel_queued(&self, target: &str) -> usize {
+        self.sender.cancel_queued(target)
+    }
+
+    /// Gets a receiver that is notified whenever the client's nickname changes. The nickname is
+    /// tracked from `RPL_WELCOME` and from `NICK` messages whose source is the client itself.
+    pub fn watch_nickname(&self) -> watch::Receiver<String> {
+        self.state.current_nickname.subscribe()
+    }
+
     /// Sends a [`Command`] as this `Client`. This is the
     /// core primitive for sending messages to the server.
     ///
@@ -1085,6 +2347,92 @@ impl Client {
         Ok(())
     }
 
+    /// Identifies with the server like [`identify`](Client::identify), and waits for the server to
+    /// accept the registration. This resolves with the information the server sends in welcoming
+    /// us, or fails with a specific error if the server rejects our password
+    /// ([`PasswordMismatch`](error::Error::PasswordMismatch)), bans us
+    /// ([`Banned`](error::Error::Banned)), rejects all of our nicknames
+    /// ([`NoUsableNick`](error::Error::NoUsableNick)), fails SASL authentication
+    /// ([`SaslFailed`](error::Error::SaslFailed)), or closes the connection
+    /// ([`ConnectionClosed`](error::Error::ConnectionClosed)). It also fails if the server takes
+    /// longer than the configured [registration timeout](Config::registration_timeout).
+    ///
+    /// The welcome is complete once the server moves on to something else, usually its MOTD. A
+    /// server that goes quiet in the middle of welcoming us is given a second before the welcome is
+    /// taken to be complete.
+    ///
+    /// Identification ends capability negotiation, so any capabilities (and SASL authentication)
+    /// should be dealt with beforehand. If the client is already identifying, this only waits for
+    /// registration to complete. Like [`whois`](Client::whois), this only completes while the
+    /// client's stream is being polled.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use irc::client::prelude::*;
+    /// use futures::prelude::*;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> irc::error::Result<()> {
+    /// let mut client = Client::new("config.toml").await?;
+    /// let mut stream = client.stream()?;
+    /// let (welcome, _) = futures::join!(client.register(), async {
+    ///     while let Some(_) = stream.next().await.transpose()? {}
+    ///     Ok::<_, irc::error::Error>(())
+    /// });
+    /// println!("Registered as {}", welcome?.nickname);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn register(&self) -> error::Result<Welcome> {
+        let mut welcome = self.state.registration.lock().wait();
+        if matches!(
+            self.state.status.state(),
+            ConnectionState::Connected
+                | ConnectionState::NegotiatingCapabilities
+                | ConnectionState::Authenticating
+        ) {
+            self.identify()?;
+        }
+
+        let timeout = Duration::from_secs(u64::from(self.config().registration_timeout()));
+        let deadline = Instant::now() + timeout;
+        loop {
+            let check = deadline.min(Instant::now() + registration::WELCOME_SETTLE);
+            match time::timeout_at(check, &mut welcome).await {
+                Ok(welcome) => return welcome.map_err(|_| error::Error::OneShotCanceled)?,
+                Err(_) if Instant::now() >= deadline => {
+                    return Err(error::Error::RegistrationTimeout)
+                }
+                Err(_) => self
+                    .state
+                    .registration
+                    .lock()
+                    .settle(|| self.state.isupport.read().clone()),
+            }
+        }
+    }
+
+    /// Quits the server gracefully with a message, waiting for the connection to close before the
+    /// client's stream ends without an error. See [`Sender::quit`] for details, which can also be
+    /// used to quit from elsewhere.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use irc::client::prelude::*;
+    /// # #[tokio::main]
+    /// # async fn main() -> irc::error::Result<()> {
+    /// let mut client = Client::new("config.toml").await?;
+    /// let stream = client.stream()?;
+    /// let (quit, messages) = tokio::join!(client.quit("Goodbye!"), stream.collect());
+    /// quit?;
+    /// messages?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn quit<S: fmt::Display>(&self, msg: S) -> error::Result<()> {
+        self.sender.quit(msg).await
+    }
+
     pub_state_base!();
     pub_sender_base!();
 }
@@ -1093,15 +2441,15 @@ impl Client {
 mod test {
     use std::{collections::HashMap, default::Default, thread, time::Duration};
 
-    use super::Client;
+    use super::{queue::Priority, Client};
     #[cfg(feature = "channel-lists")]
-    use crate::client::data::User;
+    use crate::client::data::{AccessLevel, ListMode, User};
     use crate::{
-        client::data::Config,
+        client::data::{Config, InvalidMessagePolicy},
         error::Error,
         proto::{
-            command::Command::{Raw, PRIVMSG},
-            ChannelMode, IrcCodec, Mode,
+            command::Command::{ChannelMODE, Raw, ERROR, NICK, PRIVMSG},
+            Capability, ChannelMode, IrcCodec, Mode,
         },
     };
     use anyhow::Result;
@@ -1292,6 +2640,344 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn generated_nicknames() -> Result<()> {
+        let value = ":irc.test.net 433 * test :Nickname is already in use.\r\n\
+                     :irc.test.net 433 * test2 :Nickname is already in use.\r\n\
+                     :irc.test.net 433 * test_ :Nickname is already in use.\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            generate_nicks: true,
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+        assert_eq!(client.current_nickname(), "test1");
+        assert_eq!(
+            &get_client_value(client)[..],
+            "NICK test2\r\nNICK test_\r\nNICK test1\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn generate_nickname_within_nicklen() {
+        assert_eq!(super::generate_nickname("test", 0, 9), "test_");
+        assert_eq!(super::generate_nickname("test", 12, 9), "test12");
+        assert_eq!(super::generate_nickname("longnickname", 0, 9), "longnick_");
+        assert_eq!(super::generate_nickname("longnickname", 42, 9), "longnic42");
+    }
+
+    #[tokio::test]
+    async fn regain_nickname_with_monitor() -> Result<()> {
+        let value = ":irc.test.net 001 test2 :Welcome to the network!\r\n\
+                     :irc.test.net 005 test2 MONITOR=100 NICKLEN=30 :are supported by this server\r\n\
+                     :irc.test.net 376 test2 :End of /MOTD command.\r\n\
+                     :irc.test.net 731 test2 :test\r\n\
+                     :test2!test@test NICK test\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            regain_nick: true,
+            channels: vec![],
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+        assert_eq!(client.current_nickname(), "test");
+        assert_eq!(
+            &get_client_value(client)[..],
+            "MONITOR + test\r\nNICK test\r\nMONITOR - test\r\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_regain_with_primary_nickname() -> Result<()> {
+        let value = ":irc.test.net 001 test :Welcome to the network!\r\n\
+                     :irc.test.net 005 test MONITOR=100 :are supported by this server\r\n\
+                     :irc.test.net 376 test :End of /MOTD command.\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            regain_nick: true,
+            channels: vec![],
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+        assert_eq!(&get_client_value(client)[..], "");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connection_lifecycle() -> Result<()> {
+        use crate::client::conn::ConnectionState::*;
+
+        let value = ":irc.test.net 001 test :Welcome to the network!\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        let mut status = client.connection_status();
+        assert_eq!(status.borrow_and_update().state, Connected);
+
+        client.send_cap_req(&[Capability::Sasl])?;
+        client.send_sasl_plain()?;
+        assert_eq!(status.borrow_and_update().state, Authenticating);
+        client
+            .state
+            .handle_message(&":irc.test.net 903 test :SASL authentication successful".parse()?)?;
+        assert_eq!(status.borrow().state, NegotiatingCapabilities);
+        client.identify()?;
+        client.stream()?.collect().await?;
+
+        let status = status.borrow();
+        assert_eq!(status.state, Disconnected);
+        let history: Vec<_> = status.history.iter().map(|(state, _)| *state).collect();
+        assert_eq!(
+            history,
+            vec![
+                Connecting,
+                Connected,
+                NegotiatingCapabilities,
+                Authenticating,
+                NegotiatingCapabilities,
+                Registering,
+                Registered
+            ]
+        );
+        assert!(status.history.windows(2).all(|w| w[0].1 <= w[1].1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn status_before_connecting() -> Result<()> {
+        use crate::client::conn::ConnectionState::*;
+
+        let (mut status, client) = Client::connect_with_status(test_config());
+        assert_eq!(status.borrow_and_update().state, Connecting);
+        let _client = client.await?;
+        assert!(status.has_changed()?);
+        assert_eq!(status.borrow().state, Connected);
+
+        // A failure to connect can be seen as well.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let (status, client) = Client::connect_with_status(Config {
+            server: Some("127.0.0.1".to_owned()),
+            port: Some(port),
+            #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
+            use_tls: Some(false),
+            ..Config::default()
+        });
+        assert!(client.await.is_err());
+        let status = status.borrow();
+        assert_eq!(status.state, Disconnected);
+        assert_eq!(status.history[0].0, Connecting);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quit() -> Result<()> {
+        let value = ":irc.test.net NOTICE test :before\r\n\
+                     ERROR :Closing link: (test@host) [Quit: See you]\r\n\
+                     :irc.test.net NOTICE test :after\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.send_privmsg("#test", "Bye all")?;
+        let stream = client.stream()?;
+        let (quit, messages) = tokio::join!(client.quit("See you"), stream.collect());
+        quit?;
+        let messages = messages?;
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[1].command, ERROR(_)));
+
+        // Quitting again once the connection has ended completes immediately.
+        client.sender().quit("Again").await?;
+        assert_eq!(
+            &get_client_value(client)[..],
+            "PRIVMSG #test :Bye all\r\nQUIT :See you\r\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn from_stream() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (stream, mut server) = tokio::io::duplex(1024);
+        let mut client = Client::from_stream(
+            Config {
+                nickname: Some("test".to_owned()),
+                ..Config::default()
+            },
+            stream,
+        )?;
+        assert_eq!(
+            client.connection_status().borrow().state,
+            crate::client::conn::ConnectionState::Connected
+        );
+        server
+            .write_all(b":irc.test.net 001 test :Welcome to the network!\r\n")
+            .await?;
+        server.shutdown().await?;
+        client.send_privmsg("#test", "Hi there!")?;
+        let messages = client.stream()?.collect().await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(client.current_nickname(), "test");
+
+        let mut sent = String::new();
+        server.read_to_string(&mut sent).await?;
+        assert_eq!(sent, "PRIVMSG #test :Hi there!\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queue_priorities() -> Result<()> {
+        let mut client = Client::from_config(test_config()).await?;
+        let bulk = client.sender().with_priority(Priority::Bulk);
+        for n in 0..3 {
+            bulk.send_privmsg("#logs", n)?;
+        }
+        bulk.send_privmsg("#other", "bulk")?;
+        client.send_privmsg("#help", "hi")?;
+        assert_eq!(client.cancel_queued("#LOGS"), 3);
+        assert_eq!(client.queue_depth(), 2);
+        client.stream()?.collect().await?;
+        assert_eq!(
+            &get_client_value(client)[..],
+            "PRIVMSG #help hi\r\nPRIVMSG #other bulk\r\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bounded_queue() -> Result<()> {
+        let value = ":irc.test.net NOTICE test :Welcome\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            outgoing_queue_capacity: Some(2),
+            ..test_config()
+        })
+        .await?;
+        let sender = client.sender();
+        sender.try_send(PRIVMSG("#test".to_owned(), "one".to_owned()))?;
+        sender
+            .send_async(PRIVMSG("#test".to_owned(), "two".to_owned()))
+            .await?;
+        assert!(matches!(
+            sender.try_send(PRIVMSG("#test".to_owned(), "three".to_owned())),
+            Err(Error::QueueFull)
+        ));
+        assert_eq!(client.queue_depth(), 2);
+
+        let mut send = Box::pin(sender.send_async(PRIVMSG("#test".to_owned(), "three".to_owned())));
+        assert!(futures::poll!(&mut send).is_pending());
+        let mut stream = client.stream()?;
+        stream.next().await.transpose()?;
+        send.await?;
+        assert_eq!(client.queue_depth(), 1);
+
+        stream.collect().await?;
+        assert_eq!(client.queue_depth(), 0);
+        assert_eq!(
+            &get_client_value(client)[..],
+            "PRIVMSG #test one\r\nPRIVMSG #test two\r\nPRIVMSG #test three\r\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn current_nickname_after_alt_nick() -> Result<()> {
+        let value = ":irc.test.net 433 * test :Nickname is already in use.\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+        assert_eq!(client.current_nickname(), "test2");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn current_nickname_from_welcome() -> Result<()> {
+        let value = ":irc.test.net 001 test_ :Welcome to the network!\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+        assert_eq!(client.current_nickname(), "test_");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn current_nickname_after_forced_change() -> Result<()> {
+        let value = ":irc.test.net 001 test :Welcome to the network!\r\n\
+                     :test!test@test NICK Guest1234\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        let mut nickname = client.watch_nickname();
+        client.stream()?.collect().await?;
+        assert!(nickname.has_changed().unwrap());
+        assert_eq!(&*nickname.borrow_and_update(), "Guest1234");
+        assert_eq!(client.current_nickname(), "Guest1234");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn current_nickname_after_change_in_other_case() -> Result<()> {
+        let value = ":irc.test.net 001 test :Welcome to the network!\r\n\
+                     :TEST!test@test NICK test3\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+        assert_eq!(client.current_nickname(), "test3");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn current_nickname_ignores_other_users() -> Result<()> {
+        let value = ":irc.test.net 001 test :Welcome to the network!\r\n\
+                     :other!other@test NICK test3\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+        assert_eq!(client.current_nickname(), "test");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nickname_in_use_after_registration() -> Result<()> {
+        let value = ":irc.test.net 001 test :Welcome to the network!\r\n\
+                     :irc.test.net 433 test test3 :Nickname is already in use.\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.send(NICK("test3".to_owned()))?;
+        client.stream()?.collect().await?;
+        assert_eq!(client.current_nickname(), "test");
+        assert_eq!(&get_client_value(client)[..], "NICK test3\r\n");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn ran_out_of_nicknames() -> Result<()> {
         let value = ":irc.test.net 433 * test :Nickname is already in use.\r\n\
@@ -1309,6 +2995,74 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn invalid_message_strict() -> Result<()> {
+        let value = ":irc.test.net :garbage\r\n\
+                     :test!test@test PRIVMSG test :Hi!\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        let res = client.stream()?.try_collect::<Vec<_>>().await;
+        if let Err(Error::InvalidMessage { .. }) = res {
+        } else {
+            panic!("expected an error for an invalid message under the strict policy")
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_message_skip() -> Result<()> {
+        let value = ":irc.test.net :garbage\r\n\
+                     @irc-proto/parse-error=spoofed :test!test@test PRIVMSG test :Hi!\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            invalid_message_policy: Some(InvalidMessagePolicy::Skip),
+            ..test_config()
+        })
+        .await?;
+        let messages = client.stream()?.collect().await?;
+        // No tag can get a valid message skipped.
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].command,
+            PRIVMSG("test".to_owned(), "Hi!".to_owned())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_message_raw() -> Result<()> {
+        let value = ":irc.test.net :garbage\r\n\
+                     @irc-proto/parse-error=spoofed :test!test@test PRIVMSG test :Hi!\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            invalid_message_policy: Some(InvalidMessagePolicy::Raw),
+            ..test_config()
+        })
+        .await?;
+        let mut stream = client.stream()?;
+
+        let fallback = stream.next().await.transpose()?.unwrap();
+        assert_eq!(
+            fallback.command,
+            Raw(String::new(), vec![":irc.test.net :garbage".to_owned()])
+        );
+        assert_eq!(
+            stream.parse_error().map(|e| e.to_string()).as_deref(),
+            Some("invalid command")
+        );
+
+        let message = stream.next().await.transpose()?.unwrap();
+        assert_eq!(
+            message.command,
+            PRIVMSG("test".to_owned(), "Hi!".to_owned())
+        );
+        assert!(stream.parse_error().is_none());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn send() -> Result<()> {
         let mut client = Client::from_config(test_config()).await?;
@@ -1427,7 +3181,7 @@ mod test {
                 User::new("test"),
                 User::new("~owner"),
                 User::new("&admin"),
-                User::new("test2"),
+                User::new("test2!test@test"),
             ]
         );
         Ok(())
@@ -1498,6 +3252,204 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    #[cfg(feature = "channel-lists")]
+    async fn channel_state_tracking() -> Result<()> {
+        let value = ":test!test@test JOIN #test\r\n\
+                     :irc.test.net 332 test #test :Welcome to #test!\r\n\
+                     :irc.test.net 333 test #test op!op@test 1234567890\r\n\
+                     :irc.test.net 353 test = #test :test @op\r\n\
+                     :irc.test.net 324 test #test +ntk secret\r\n\
+                     :irc.test.net 329 test #test 1234567000\r\n\
+                     :op!op@test MODE #test +l-k 10 secret\r\n\
+                     :op!op@test TOPIC #test :New topic\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+        let chan = client.channel("#test").unwrap();
+        assert_eq!(chan.name(), "#test");
+        assert_eq!(chan.topic(), Some("New topic"));
+        assert_eq!(chan.topic_setter(), Some("op!op@test"));
+        assert_eq!(
+            chan.modes(),
+            &[
+                ChannelMode::NoExternalMessages,
+                ChannelMode::ProtectedTopic,
+                ChannelMode::Limit
+            ]
+        );
+        assert_eq!(chan.key(), None);
+        assert_eq!(chan.limit(), Some(10));
+        assert_eq!(
+            chan.creation_time().map(|t| t.timestamp()),
+            Some(1234567000)
+        );
+        assert_eq!(chan.users(), &[User::new("test"), User::new("@op")]);
+        assert_eq!(chan.bans(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "channel-lists")]
+    async fn channel_topic_who_time() -> Result<()> {
+        let value = ":test!test@test JOIN #test\r\n\
+                     :irc.test.net 332 test #test :Welcome to #test!\r\n\
+                     :irc.test.net 333 test #test op 1234567890\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+        let chan = client.channel("#test").unwrap();
+        assert_eq!(chan.topic(), Some("Welcome to #test!"));
+        assert_eq!(chan.topic_setter(), Some("op"));
+        assert_eq!(chan.topic_time().map(|t| t.timestamp()), Some(1234567890));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "channel-lists")]
+    async fn fetch_channel_ban_list() -> Result<()> {
+        let value = ":test!test@test JOIN #test\r\n\
+                     :irc.test.net 367 test #test *!*@evil op 1234567890\r\n\
+                     :irc.test.net 367 test #test *!*@spam\r\n\
+                     :irc.test.net 368 test #test :End of channel ban list\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.fetch_channel_list("#test", ListMode::Ban)?;
+        client.stream()?.collect().await?;
+        let chan = client.channel("#test").unwrap();
+        let bans = chan.bans().unwrap();
+        assert_eq!(bans.len(), 2);
+        assert_eq!(bans[0].mask(), "*!*@evil");
+        assert_eq!(bans[0].set_by(), Some("op"));
+        assert_eq!(bans[0].set_at().map(|t| t.timestamp()), Some(1234567890));
+        assert_eq!(bans[1].mask(), "*!*@spam");
+        assert_eq!(bans[1].set_by(), None);
+        assert_eq!(chan.exceptions(), None);
+        assert_eq!(&get_client_value(client)[..], "MODE #test +b\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "channel-lists")]
+    async fn user_registry_tracking() -> Result<()> {
+        let value = ":irc.test.net 353 test = #test :test @owner!o@host\r\n\
+                     :alice!a@host JOIN #test alice_acct :Alice A.\r\n\
+                     :owner!o@host AWAY :Gone fishing\r\n\
+                     :owner!o@host CHGHOST o2 new.host\r\n\
+                     @account=owner_acct :owner!o2@new.host PRIVMSG #test :Hi!\r\n\
+                     :alice!a@host ACCOUNT *\r\n\
+                     :alice!a@host SETNAME :Alice B.\r\n\
+                     :owner!o2@new.host NICK boss\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+
+        let alice = client.user("alice").unwrap();
+        assert_eq!(alice.account(), None);
+        assert_eq!(alice.realname(), Some("Alice B."));
+        assert!(!alice.is_away());
+
+        assert!(client.user("owner").is_none());
+        let boss = client.user("boss").unwrap();
+        assert_eq!(boss.get_username(), Some("o2"));
+        assert_eq!(boss.get_hostname(), Some("new.host"));
+        assert_eq!(boss.account(), Some("owner_acct"));
+        assert!(boss.is_away());
+        assert_eq!(boss.away_message(), Some("Gone fishing"));
+
+        // Access levels are kept per channel across nickname changes.
+        let users = client.list_users("#test").unwrap();
+        assert_eq!(users[1].get_nickname(), "boss");
+        assert_eq!(users[1].highest_access_level(), AccessLevel::Oper);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "channel-lists")]
+    async fn user_registry_who_reply() -> Result<()> {
+        let value = ":irc.test.net 353 test = #test :test alice\r\n\
+                     :irc.test.net 352 test #test a host irc.test.net alice G :0 Alice A.\r\n\
+                     :irc.test.net 352 test #test b host irc.test.net bob H :0 Bob\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+        let alice = client.user("alice").unwrap();
+        assert_eq!(alice.get_username(), Some("a"));
+        assert_eq!(alice.realname(), Some("Alice A."));
+        assert!(alice.is_away());
+        // We don't share a channel with bob.
+        assert!(client.user("bob").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "channel-lists")]
+    async fn user_tracking_custom_prefixes() -> Result<()> {
+        let value = ":irc.test.net 005 test PREFIX=(Yqov)!~@+ CHANMODES=beI,k,l,imnst :are supported by this server\r\n\
+                     :irc.test.net 353 test = #test :test !alice @+bob\r\n\
+                     :alice!a@host MODE #test +Y-l bob\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+        let users = client.list_users("#test").unwrap();
+        assert_eq!(users[1].get_nickname(), "alice");
+        assert_eq!(users[1].prefixes(), "!");
+        assert_eq!(users[2].get_nickname(), "bob");
+        assert_eq!(users[2].prefixes(), "!@+");
+        assert_eq!(users[2].highest_access_level(), AccessLevel::Oper);
+        assert_eq!(client.prefix_map().rank('!'), Some(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "channel-lists")]
+    async fn server_specific_mode_args() -> Result<()> {
+        let value = ":irc.test.net 005 test PREFIX=(Yov)!@+ CHANMODES=beI,k,l,Cimnst :are supported by this server\r\n\
+                     :test!test@test JOIN #test\r\n\
+                     :irc.test.net 353 test = #test :test key\r\n\
+                     :op!o@host MODE #test +CYk key a\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        let messages = client.stream()?.collect().await?;
+        assert_eq!(
+            messages[3].command,
+            ChannelMODE(
+                "#test".to_owned(),
+                vec![
+                    Mode::Plus(ChannelMode::Unknown('C'), None),
+                    Mode::Plus(ChannelMode::Unknown('Y'), Some("key".to_owned())),
+                    Mode::Plus(ChannelMode::Key, Some("a".to_owned())),
+                ]
+            )
+        );
+        assert_eq!(client.channel("#test").unwrap().key(), Some("a"));
+        let users = client.list_users("#test").unwrap();
+        assert_eq!(users[1].get_nickname(), "key");
+        assert_eq!(users[1].prefixes(), "!");
+        Ok(())
+    }
+
     #[tokio::test]
     #[cfg(not(feature = "channel-lists"))]
     async fn no_user_tracking() -> Result<()> {
@@ -1563,6 +3515,40 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    #[cfg(feature = "ctcp")]
+    async fn echoed_ctcp_is_not_answered() -> Result<()> {
+        let value = ":irc.test.net CAP * ACK :echo-message\r\n\
+                     :test!test@test PRIVMSG test :\u{001}VERSION\u{001}\r\n\
+                     :test!test@test PRIVMSG #test :\u{001}PING 1\u{001}\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+        assert_eq!(&get_client_value(client)[..], "");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn history_is_not_handled() -> Result<()> {
+        let value = ":irc.test.net BATCH +h chathistory test\r\n\
+                     @batch=h :test!test@test PRIVMSG test :\u{001}VERSION\u{001}\r\n\
+                     @batch=h :test!test@test NICK :renamed\r\n\
+                     :irc.test.net BATCH -h\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.stream()?.collect().await?;
+        assert_eq!(client.current_nickname(), "test");
+        assert!(client.state.history_batches.lock().is_empty());
+        assert_eq!(&get_client_value(client)[..], "");
+        Ok(())
+    }
+
     #[tokio::test]
     #[cfg(feature = "ctcp")]
     async fn source_response() -> Result<()> {
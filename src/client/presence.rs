@@ -0,0 +1,565 @@
+//! Tracking whether other users are online.
+//!
+//! A [`Presence`] tracker holds a list of nicknames to watch, and reports when they come online or
+//! go offline. Where the server supports it, the nicknames are watched with `MONITOR`, so the
+//! server notifies us of any changes. Nicknames beyond the server's `MONITOR` limit, or all of
+//! them on servers without `MONITOR`, are polled with `ISON` at the configured
+//! [interval](crate::client::data::Config::ison_interval) instead. Since `ISON` replies don't say
+//! which `ISON` they answer, any `ISON` of your own has to go through
+//! [`Client::send`](crate::client::Client::send) rather than a [`Sender`], so that its reply isn't
+//! taken for the answer to a poll.
+//!
+//! The tracker is a handle that outlives the [`Client`](crate::client::Client) it was used with,
+//! so that after a reconnect it can be handed to the new client with
+//! [`Client::use_presence`](crate::client::Client::use_presence), which resubscribes to every
+//! watched nickname once the new connection is registered.
+//!
+//! # Example
+//! ```no_run
+//! # use irc::client::prelude::*;
+//! use futures::prelude::*;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> irc::error::Result<()> {
+//! let mut client = Client::new("config.toml").await?;
+//! let presence = client.presence();
+//! presence.watch(&["alice", "bob"])?;
+//! let mut updates = presence.subscribe();
+//!
+//! client.identify()?;
+//! let mut stream = client.stream()?;
+//! tokio::spawn(async move {
+//!     while let Ok(update) = updates.recv().await {
+//!         let status = if update.online { "online" } else { "offline" };
+//!         println!("{} is {}", update.nickname, status);
+//!     }
+//! });
+//! while let Some(_) = stream.next().await.transpose()? {}
+//! # Ok(())
+//! # }
+//! ```
+use std::{collections::BTreeMap, sync::Arc};
+
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+
+use super::Sender;
+use crate::{
+    error,
+    proto::{Command, Message, Response},
+};
+
+/// The number of updates a slow subscriber can fall behind by before missing some.
+const UPDATE_CAPACITY: usize = 64;
+
+/// The maximum length of the nicknames sent in a single `MONITOR` or `ISON` command, which keeps
+/// the line comfortably within the 512 byte limit.
+const MAX_TARGETS_LEN: usize = 400;
+
+/// A change in whether a watched user is online.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PresenceUpdate {
+    /// The nickname of the user, as it was passed to [`Presence::watch`].
+    pub nickname: String,
+    /// Whether the user is now online.
+    pub online: bool,
+}
+
+/// A list of nicknames being watched, and whether each of them is online.
+///
+/// This is a cheap handle to shared state, so clones refer to the same tracker. A client's tracker
+/// is available from [`Client::presence`](crate::client::Client::presence).
+#[derive(Clone, Debug)]
+pub struct Presence {
+    inner: Arc<Mutex<Inner>>,
+    updates: broadcast::Sender<PresenceUpdate>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// The watched nicknames, keyed by their lowercase form.
+    watched: BTreeMap<String, Watched>,
+    /// The connection the tracker is in use with, once it has registered.
+    connection: Option<Connection>,
+}
+
+#[derive(Debug)]
+struct Watched {
+    nickname: String,
+    /// Whether the user is online, or `None` if we haven't heard yet.
+    online: Option<bool>,
+    /// Whether the server is watching the nickname for us with `MONITOR`.
+    monitored: bool,
+}
+
+#[derive(Debug)]
+struct Connection {
+    sender: Sender,
+    /// The number of nicknames that the server will `MONITOR` for us, or `None` if the server
+    /// doesn't support `MONITOR` at all.
+    monitor_limit: Option<usize>,
+}
+
+impl Default for Presence {
+    fn default() -> Presence {
+        Presence::new()
+    }
+}
+
+impl Presence {
+    /// Creates a new tracker that isn't watching anyone yet.
+    pub fn new() -> Presence {
+        Presence {
+            inner: Arc::new(Mutex::new(Inner::default())),
+            updates: broadcast::channel(UPDATE_CAPACITY).0,
+        }
+    }
+
+    /// Starts watching the specified nicknames. Nicknames that are already being watched are
+    /// ignored.
+    pub fn watch<S: AsRef<str>>(&self, nicknames: &[S]) -> error::Result<()> {
+        let mut inner = self.inner.lock();
+        for nickname in nicknames {
+            let nickname = nickname.as_ref();
+            inner
+                .watched
+                .entry(nickname.to_ascii_lowercase())
+                .or_insert_with(|| Watched {
+                    nickname: nickname.to_owned(),
+                    online: None,
+                    monitored: false,
+                });
+        }
+        inner.subscribe()
+    }
+
+    /// Stops watching the specified nicknames.
+    pub fn unwatch<S: AsRef<str>>(&self, nicknames: &[S]) -> error::Result<()> {
+        let mut inner = self.inner.lock();
+        let mut monitored = Vec::new();
+        for nickname in nicknames {
+            if let Some(watched) = inner
+                .watched
+                .remove(&nickname.as_ref().to_ascii_lowercase())
+            {
+                if watched.monitored {
+                    monitored.push(watched.nickname);
+                }
+            }
+        }
+        match inner.connection {
+            Some(ref connection) if !monitored.is_empty() => connection.monitor("-", &monitored),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks whether the specified nickname is being watched.
+    pub fn is_watched(&self, nickname: &str) -> bool {
+        self.inner
+            .lock()
+            .watched
+            .contains_key(&nickname.to_ascii_lowercase())
+    }
+
+    /// Checks whether a watched user is online. This is `None` if the nickname isn't being watched,
+    /// or if the server hasn't told us yet. After a disconnect, this is the last known status until
+    /// the tracker is used with a new connection.
+    pub fn is_online(&self, nickname: &str) -> Option<bool> {
+        self.inner
+            .lock()
+            .watched
+            .get(&nickname.to_ascii_lowercase())
+            .and_then(|watched| watched.online)
+    }
+
+    /// Gets the nicknames being watched.
+    pub fn watched(&self) -> Vec<String> {
+        let inner = self.inner.lock();
+        inner.watched.values().map(|w| w.nickname.clone()).collect()
+    }
+
+    /// Gets the watched nicknames that are known to be online.
+    pub fn online(&self) -> Vec<String> {
+        let inner = self.inner.lock();
+        inner
+            .watched
+            .values()
+            .filter(|w| w.online == Some(true))
+            .map(|w| w.nickname.clone())
+            .collect()
+    }
+
+    /// Subscribes to updates about watched users coming online or going offline. An update is only
+    /// sent when a user's status changes, or when it is first learned. A receiver that falls too
+    /// far behind misses the oldest updates, which it is told about with
+    /// [`RecvError::Lagged`](tokio::sync::broadcast::error::RecvError::Lagged).
+    pub fn subscribe(&self) -> broadcast::Receiver<PresenceUpdate> {
+        self.updates.subscribe()
+    }
+
+    /// Starts using the tracker with a newly registered connection, subscribing to every watched
+    /// nickname. `monitor_limit` is the number of nicknames the server will `MONITOR`, if any.
+    pub(crate) fn connect(
+        &self,
+        sender: Sender,
+        monitor_limit: Option<usize>,
+    ) -> error::Result<()> {
+        let mut inner = self.inner.lock();
+        for watched in inner.watched.values_mut() {
+            watched.monitored = false;
+        }
+        inner.connection = Some(Connection {
+            sender,
+            monitor_limit,
+        });
+        inner.subscribe()
+    }
+
+    /// Gets the watched nicknames that need to be polled with `ISON`, because they aren't being
+    /// watched with `MONITOR`.
+    pub(crate) fn ison_targets(&self) -> Vec<String> {
+        let inner = self.inner.lock();
+        if inner.connection.is_none() {
+            return Vec::new();
+        }
+        inner
+            .watched
+            .values()
+            .filter(|w| !w.monitored)
+            .map(|w| w.nickname.clone())
+            .collect()
+    }
+
+    /// Handles the reply to an `ISON` poll for the specified nicknames.
+    pub(crate) fn handle_ison(&self, queried: &[String], online: &str) {
+        let online: Vec<_> = online.split_whitespace().collect();
+        let mut inner = self.inner.lock();
+        for nickname in queried {
+            let is_online = online.iter().any(|n| n.eq_ignore_ascii_case(nickname));
+            if let Some(update) = inner.update(nickname, is_online) {
+                let _ = self.updates.send(update);
+            }
+        }
+    }
+
+    /// Handles `MONITOR` replies from the server.
+    pub(crate) fn handle_message(&self, msg: &Message) {
+        let (online, targets) = match msg.command {
+            Command::Response(Response::RPL_MONONLINE, ref args) => (true, args.get(1)),
+            Command::Response(Response::RPL_MONOFFLINE, ref args) => (false, args.get(1)),
+            Command::Response(Response::ERR_MONLISTFULL, ref args) => {
+                if let Some(targets) = args.get(2) {
+                    self.inner.lock().handle_list_full(targets);
+                }
+                return;
+            }
+            _ => return,
+        };
+
+        let mut inner = self.inner.lock();
+        for target in targets.iter().flat_map(|targets| targets.split(',')) {
+            // Online targets are sent as full hostmasks.
+            let nickname = target.split('!').next().unwrap_or(target);
+            if let Some(update) = inner.update(nickname, online) {
+                let _ = self.updates.send(update);
+            }
+        }
+    }
+}
+
+impl Inner {
+    /// Subscribes to the watched nicknames with `MONITOR` as far as the server's limit allows. The
+    /// rest are left to be polled with `ISON`.
+    fn subscribe(&mut self) -> error::Result<()> {
+        let connection = match self.connection {
+            Some(ref connection) => connection,
+            None => return Ok(()),
+        };
+        let limit = match connection.monitor_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let monitored = self.watched.values().filter(|w| w.monitored).count();
+        let mut nicknames = Vec::new();
+        for watched in self
+            .watched
+            .values_mut()
+            .filter(|w| !w.monitored)
+            .take(limit.saturating_sub(monitored))
+        {
+            watched.monitored = true;
+            nicknames.push(watched.nickname.clone());
+        }
+        connection.monitor("+", &nicknames)
+    }
+
+    /// Handles `ERR_MONLISTFULL`, falling back on `ISON` for the nicknames that the server refused
+    /// to monitor, and lowering our idea of the server's limit so we don't try again.
+    fn handle_list_full(&mut self, targets: &str) {
+        for target in targets.split(',') {
+            if let Some(watched) = self.watched.get_mut(&target.to_ascii_lowercase()) {
+                watched.monitored = false;
+            }
+        }
+        let monitored = self.watched.values().filter(|w| w.monitored).count();
+        if let Some(ref mut connection) = self.connection {
+            connection.monitor_limit = Some(monitored);
+        }
+    }
+
+    /// Records whether a watched user is online, returning an update if that's news.
+    fn update(&mut self, nickname: &str, online: bool) -> Option<PresenceUpdate> {
+        let watched = self.watched.get_mut(&nickname.to_ascii_lowercase())?;
+        if watched.online == Some(online) {
+            return None;
+        }
+        watched.online = Some(online);
+        Some(PresenceUpdate {
+            nickname: watched.nickname.clone(),
+            online,
+        })
+    }
+}
+
+impl Connection {
+    /// Adds (`+`) or removes (`-`) nicknames from the server's `MONITOR` list.
+    fn monitor(&self, action: &str, nicknames: &[String]) -> error::Result<()> {
+        for chunk in chunk_nicknames(nicknames) {
+            self.sender
+                .send(Command::MONITOR(action.to_owned(), Some(chunk.join(","))))?;
+        }
+        Ok(())
+    }
+}
+
+/// An `ISON` that was sent and is waiting on its reply. Replies don't say which `ISON` they answer,
+/// so they are matched to these in the order they were sent.
+#[derive(Debug)]
+pub(crate) struct PendingIson {
+    /// The nicknames that were asked about.
+    pub(crate) nicknames: Vec<String>,
+    /// Whether we sent it to poll for presence, rather than the user sending it.
+    pub(crate) polled: bool,
+    /// Whether it was already waiting on its reply at the last poll.
+    pub(crate) stale: bool,
+}
+
+impl PendingIson {
+    pub(crate) fn new(nicknames: Vec<String>, polled: bool) -> PendingIson {
+        PendingIson {
+            nicknames,
+            polled,
+            stale: false,
+        }
+    }
+}
+
+/// Splits a list of nicknames into chunks that each fit in a single `MONITOR` or `ISON` command.
+pub(crate) fn chunk_nicknames(nicknames: &[String]) -> Vec<Vec<String>> {
+    let mut chunks: Vec<Vec<String>> = Vec::new();
+    let mut len = 0;
+    for nickname in nicknames {
+        match chunks.last_mut() {
+            Some(chunk) if len + nickname.len() < MAX_TARGETS_LEN => chunk.push(nickname.clone()),
+            _ => {
+                chunks.push(vec![nickname.clone()]);
+                len = 0;
+            }
+        }
+        len += nickname.len() + 1;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod test {
+    use super::{chunk_nicknames, PresenceUpdate};
+    use crate::{
+        client::{
+            data::Config,
+            test::{get_client_value, test_config},
+            Client,
+        },
+        proto::Command,
+    };
+    use anyhow::Result;
+    use std::time::Duration;
+
+    fn update(nickname: &str, online: bool) -> PresenceUpdate {
+        PresenceUpdate {
+            nickname: nickname.to_owned(),
+            online,
+        }
+    }
+
+    #[test]
+    fn chunks_fit_in_a_line() {
+        let nicknames: Vec<_> = (0..100).map(|n| format!("nickname{:02}", n)).collect();
+        let chunks = chunk_nicknames(&nicknames);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.join(",").len() < 400));
+        assert_eq!(chunks.concat(), nicknames);
+    }
+
+    #[tokio::test]
+    async fn monitor_beyond_limit() -> Result<()> {
+        let mut client = Client::from_config(Config {
+            channels: vec![],
+            ..test_config()
+        })
+        .await?;
+        let presence = client.presence();
+        let mut updates = presence.subscribe();
+        presence.watch(&["alice", "bob", "carol"])?;
+        for line in [
+            ":irc.test.net 001 test :Welcome to the network!\r\n",
+            ":irc.test.net 005 test MONITOR=2 :are supported by this server\r\n",
+            ":irc.test.net 376 test :End of /MOTD command.\r\n",
+            ":irc.test.net 730 test :Alice!a@host\r\n",
+            ":irc.test.net 731 test :bob\r\n",
+            ":irc.test.net 730 test :alice!a@host\r\n",
+        ] {
+            client.state.handle_message(&line.parse()?)?;
+        }
+        assert_eq!(updates.try_recv()?, update("alice", true));
+        assert_eq!(updates.try_recv()?, update("bob", false));
+        assert!(updates.try_recv().is_err());
+        assert_eq!(presence.online(), vec!["alice"]);
+        assert_eq!(presence.is_online("carol"), None);
+
+        // carol didn't fit in the server's MONITOR list.
+        client.state.poll_ison()?;
+        client
+            .state
+            .handle_message(&":irc.test.net 303 test :carol\r\n".parse()?)?;
+        assert_eq!(updates.try_recv()?, update("carol", true));
+
+        presence.unwatch(&["bob", "carol"])?;
+        assert_eq!(presence.watched(), vec!["alice"]);
+        client.stream()?.collect().await?;
+        assert_eq!(
+            &get_client_value(client)[..],
+            "MONITOR + alice,bob\r\nISON carol\r\nMONITOR - bob\r\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ison_without_monitor() -> Result<()> {
+        let mut client = Client::from_config(Config {
+            channels: vec![],
+            ..test_config()
+        })
+        .await?;
+        let presence = client.presence();
+        presence.watch(&["alice", "bob"])?;
+        for line in [
+            ":irc.test.net 001 test :Welcome to the network!\r\n",
+            ":irc.test.net 376 test :End of /MOTD command.\r\n",
+        ] {
+            client.state.handle_message(&line.parse()?)?;
+        }
+        // Our poll is sent between two ISONs of the user's, whose replies are left alone.
+        client.send(Command::ISON(vec!["alice".to_owned()]))?;
+        client.state.poll_ison()?;
+        client.send(Command::ISON(vec!["carol".to_owned()]))?;
+        client
+            .state
+            .handle_message(&":irc.test.net 303 test :alice\r\n".parse()?)?;
+        assert_eq!(presence.is_online("alice"), None);
+        client
+            .state
+            .handle_message(&":irc.test.net 303 test :\r\n".parse()?)?;
+        assert_eq!(presence.is_online("alice"), Some(false));
+        assert_eq!(presence.is_online("bob"), Some(false));
+        client
+            .state
+            .handle_message(&":irc.test.net 303 test :carol\r\n".parse()?)?;
+        assert!(client.state.ison_pending.lock().is_empty());
+
+        // The reply to the next poll is lost, so it is given up on two polls later.
+        client.state.poll_ison()?;
+        client.state.poll_ison()?;
+        client.state.poll_ison()?;
+        client
+            .state
+            .handle_message(&":irc.test.net 303 test :BOB\r\n".parse()?)?;
+        assert_eq!(presence.is_online("alice"), Some(false));
+        assert_eq!(presence.is_online("bob"), Some(true));
+        assert_eq!(client.state.ison_pending.lock().len(), 1);
+        client.stream()?.collect().await?;
+        assert_eq!(
+            &get_client_value(client)[..],
+            "ISON alice\r\nISON alice bob\r\nISON carol\r\n\
+             ISON alice bob\r\nISON alice bob\r\nISON alice bob\r\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ison_without_traffic() -> Result<()> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (stream, server) = tokio::io::duplex(1024);
+        let mut client = Client::from_stream(
+            Config {
+                nickname: Some("test".to_owned()),
+                ison_interval: Some(1),
+                ..Config::default()
+            },
+            stream,
+        )?;
+        let presence = client.presence();
+        let stream = tokio::spawn(client.stream()?.collect());
+
+        let mut server = BufReader::new(server);
+        server
+            .write_all(
+                b":irc.test.net 001 test :Welcome to the network!\r\n\
+                  :irc.test.net 376 test :End of /MOTD command.\r\n",
+            )
+            .await?;
+        // The first tick finds nothing to poll, and the server stays quiet from then on.
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        presence.watch(&["alice"])?;
+        let mut line = String::new();
+        while line.is_empty() || line.starts_with("PING") {
+            line.clear();
+            tokio::time::timeout(Duration::from_secs(5), server.read_line(&mut line)).await??;
+        }
+        assert_eq!(line, "ISON alice\r\n");
+
+        drop(server);
+        let _ = stream.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resubscribe_after_reconnect() -> Result<()> {
+        let presence = Client::from_config(test_config()).await?.presence();
+        presence.watch(&["alice", "bob"])?;
+
+        let mut client = Client::from_config(Config {
+            channels: vec![],
+            ..test_config()
+        })
+        .await?;
+        client.use_presence(presence.clone())?;
+        for line in [
+            ":irc.test.net 001 test :Welcome to the network!\r\n",
+            ":irc.test.net 005 test MONITOR=100 :are supported by this server\r\n",
+            ":irc.test.net 376 test :End of /MOTD command.\r\n",
+            ":irc.test.net 734 test 100 bob :Monitor list is full.\r\n",
+        ] {
+            client.state.handle_message(&line.parse()?)?;
+        }
+        client.state.poll_ison()?;
+        client.stream()?.collect().await?;
+        assert_eq!(
+            &get_client_value(client)[..],
+            "MONITOR + alice,bob\r\nISON bob\r\n"
+        );
+        Ok(())
+    }
+}
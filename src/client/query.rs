@@ -0,0 +1,1295 @@
+//! Queries whose replies are gathered from the server's numerics, like `WHOIS`.
+//!
+//! A query is registered with the client before its command is sent, and from then on collects the
+//! numerics that answer it as they pass through the client's stream, until the numeric ending the
+//! reply arrives. Servers answer commands in the order they receive them, so concurrent queries of
+//! the same kind are answered first come, first served. Collected messages are still yielded by
+//! the stream as usual.
+//!
+//! When the server has enabled `labeled-response`, queries are sent with
+//! [`Sender::send_labeled`](crate::client::Sender::send_labeled) instead, and their replies are
+//! found by their label.
+use std::{collections::HashMap, fmt, time::Duration};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use parking_lot::Mutex;
+use tokio::sync::oneshot;
+
+use crate::{
+    client::data::{
+        channel::{parse_modes, parse_timestamp},
+        PrefixMap, User,
+    },
+    error,
+    proto::{ChannelMode, Command, Message, Mode, Response},
+};
+
+/// Information about a user, as returned by [`Client::whois`](crate::client::Client::whois).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WhoisInfo {
+    /// The user's nickname.
+    pub nickname: String,
+    /// The user's username.
+    pub username: Option<String>,
+    /// The user's hostname.
+    pub hostname: Option<String>,
+    /// The user's real name.
+    pub realname: Option<String>,
+    /// The server the user is connected to.
+    pub server: Option<String>,
+    /// The description of the server the user is connected to.
+    pub server_info: Option<String>,
+    /// Whether the user is an IRC operator.
+    pub is_operator: bool,
+    /// How long the user has been idle.
+    pub idle: Option<Duration>,
+    /// When the user connected.
+    pub signon: Option<DateTime<Utc>>,
+    /// The channels the user is in, including their prefixes (e.g. `@#rust`).
+    pub channels: Vec<String>,
+    /// The services account the user is logged in to.
+    pub account: Option<String>,
+    /// The user's away message, if they're away.
+    pub away_message: Option<String>,
+    /// Whether the user is connected securely (i.e. with TLS).
+    pub is_secure: bool,
+}
+
+/// A user matching a `WHO` query, as returned by [`Client::who`](crate::client::Client::who).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WhoReply {
+    /// A channel that the user is in, if any.
+    pub channel: Option<String>,
+    /// The user's nickname.
+    pub nickname: String,
+    /// The user's username.
+    pub username: String,
+    /// The user's hostname.
+    pub hostname: String,
+    /// The server the user is connected to.
+    pub server: String,
+    /// The user's flags, e.g. `H@` for an operator of `channel` who isn't away.
+    pub flags: String,
+    /// The number of hops between us and the user's server.
+    pub hopcount: u32,
+    /// The user's real name.
+    pub realname: String,
+}
+
+impl WhoReply {
+    /// Gets whether the user is away.
+    pub fn is_away(&self) -> bool {
+        self.flags.starts_with('G')
+    }
+}
+
+/// A channel listed by [`Client::list`](crate::client::Client::list).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ListEntry {
+    /// The channel's name.
+    pub channel: String,
+    /// The number of users in the channel.
+    pub users: u32,
+    /// The channel's topic.
+    pub topic: String,
+}
+
+/// A reference to a point in a conversation's history, used by [`HistoryRequest`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessageRef {
+    /// The message with the specified ID, from its `msgid` tag.
+    MsgId(String),
+    /// The specified time.
+    Timestamp(DateTime<Utc>),
+}
+
+impl fmt::Display for MessageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageRef::MsgId(id) => write!(f, "msgid={}", id),
+            MessageRef::Timestamp(time) => write!(f, "timestamp={}", format_time(time)),
+        }
+    }
+}
+
+/// A request for history, as sent by [`Client::chathistory`](crate::client::Client::chathistory).
+#[derive(Clone, Debug, PartialEq)]
+pub enum HistoryRequest {
+    /// The latest messages sent to a target, optionally only those after a reference.
+    Latest {
+        /// The channel or nickname whose history to get.
+        target: String,
+        /// The reference after which to get messages, if any.
+        after: Option<MessageRef>,
+    },
+    /// The messages sent to a target before a reference.
+    Before {
+        /// The channel or nickname whose history to get.
+        target: String,
+        /// The reference before which to get messages.
+        reference: MessageRef,
+    },
+    /// The messages sent to a target after a reference.
+    After {
+        /// The channel or nickname whose history to get.
+        target: String,
+        /// The reference after which to get messages.
+        reference: MessageRef,
+    },
+    /// The messages sent to a target around a reference.
+    Around {
+        /// The channel or nickname whose history to get.
+        target: String,
+        /// The reference around which to get messages.
+        reference: MessageRef,
+    },
+    /// The messages sent to a target between two references.
+    Between {
+        /// The channel or nickname whose history to get.
+        target: String,
+        /// One end of the range of messages to get.
+        start: MessageRef,
+        /// The other end of the range of messages to get.
+        end: MessageRef,
+    },
+    /// The channels and users that we've had conversations with between two times, each given
+    /// as a `CHATHISTORY TARGETS` message with the time of its latest message.
+    Targets {
+        /// One end of the range of times.
+        start: DateTime<Utc>,
+        /// The other end of the range of times.
+        end: DateTime<Utc>,
+    },
+}
+
+impl HistoryRequest {
+    /// Gets the target that the request is about, if any.
+    fn target(&self) -> Option<&str> {
+        match self {
+            HistoryRequest::Latest { target, .. }
+            | HistoryRequest::Before { target, .. }
+            | HistoryRequest::After { target, .. }
+            | HistoryRequest::Around { target, .. }
+            | HistoryRequest::Between { target, .. } => Some(target),
+            HistoryRequest::Targets { .. } => None,
+        }
+    }
+
+    /// Gets the query for the request.
+    pub(crate) fn query(&self) -> Query {
+        Query::History(self.target().map(|target| target.to_owned()))
+    }
+
+    /// Gets the `CHATHISTORY` command for the request.
+    pub(crate) fn command(&self, limit: usize) -> Command {
+        let mut args = match self {
+            HistoryRequest::Latest { target, after } => vec![
+                "LATEST".to_owned(),
+                target.clone(),
+                after
+                    .as_ref()
+                    .map_or_else(|| "*".to_owned(), |r| r.to_string()),
+            ],
+            HistoryRequest::Before { target, reference } => {
+                vec!["BEFORE".to_owned(), target.clone(), reference.to_string()]
+            }
+            HistoryRequest::After { target, reference } => {
+                vec!["AFTER".to_owned(), target.clone(), reference.to_string()]
+            }
+            HistoryRequest::Around { target, reference } => {
+                vec!["AROUND".to_owned(), target.clone(), reference.to_string()]
+            }
+            HistoryRequest::Between { target, start, end } => vec![
+                "BETWEEN".to_owned(),
+                target.clone(),
+                start.to_string(),
+                end.to_string(),
+            ],
+            HistoryRequest::Targets { start, end } => vec![
+                "TARGETS".to_owned(),
+                MessageRef::Timestamp(*start).to_string(),
+                MessageRef::Timestamp(*end).to_string(),
+            ],
+        };
+        args.push(limit.to_string());
+        Command::Raw("CHATHISTORY".to_owned(), args)
+    }
+}
+
+/// A message from a conversation's history, as returned by
+/// [`Client::chathistory`](crate::client::Client::chathistory).
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryMessage {
+    /// When the message was sent, from its `server-time` tag.
+    pub time: Option<DateTime<Utc>>,
+    /// The message's ID, from its `msgid` tag, which can be used to page through history.
+    pub msgid: Option<String>,
+    /// The message.
+    pub message: Message,
+}
+
+/// The server's confirmation that it delivered a message, as returned by
+/// [`Client::send_privmsg_confirmed`](crate::client::Client::send_privmsg_confirmed).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delivery {
+    /// The ID that the server assigned to the message, from its `msgid` tag.
+    pub msgid: Option<String>,
+    /// When the server received the message, from its `server-time` tag.
+    pub time: Option<DateTime<Utc>>,
+    /// The message, as the server echoed it back to us.
+    pub message: Message,
+}
+
+impl Delivery {
+    pub(crate) fn new(message: Message) -> Delivery {
+        Delivery {
+            msgid: message.msgid().map(|id| id.to_owned()),
+            time: message.server_time(),
+            message,
+        }
+    }
+}
+
+/// A kind of query, along with the target that it's about.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Query {
+    Whois(String),
+    /// A `WHO` query about the specified mask, sent as a WHOX query with the specified token if the
+    /// server supports it.
+    Who(String, Option<String>),
+    Names(String),
+    List,
+    ChannelModes(String),
+    Topic(String),
+    /// A `CHATHISTORY` request about the specified target, or a `CHATHISTORY TARGETS` request.
+    History(Option<String>),
+}
+
+/// Whether a message is part of a query's reply, starts the batch containing it, or ends it.
+#[derive(Clone, Debug, PartialEq)]
+enum Step {
+    Reply,
+    Batch(String),
+    End,
+}
+
+impl Query {
+    /// Gets whether a message belongs to this query's reply, given the replies collected so far.
+    fn step(&self, msg: &Message, replies: &[Message]) -> Option<Step> {
+        match (self, &msg.command) {
+            (_, Command::Response(response, args)) => self.numeric_step(*response, args, replies),
+            (Query::History(target), Command::BATCH(reference, Some(kind), params)) => {
+                let reference = reference.strip_prefix('+')?;
+                let target_matches = match (target, params.as_ref().and_then(|p| p.first())) {
+                    (Some(target), Some(param)) => param.eq_ignore_ascii_case(target),
+                    _ => false,
+                };
+                match kind.to_str() {
+                    "CHATHISTORY" if target_matches => Some(Step::Batch(reference.to_owned())),
+                    "DRAFT/CHATHISTORY-TARGETS" if target.is_none() => {
+                        Some(Step::Batch(reference.to_owned()))
+                    }
+                    _ => None,
+                }
+            }
+            (Query::History(_), Command::Raw(command, args))
+                if command == "FAIL" && args.first().is_some_and(|c| c == "CHATHISTORY") =>
+            {
+                Some(Step::End)
+            }
+            _ => None,
+        }
+    }
+
+    /// Gets whether a numeric belongs to this query's reply.
+    fn numeric_step(
+        &self,
+        response: Response,
+        args: &[String],
+        replies: &[Message],
+    ) -> Option<Step> {
+        use Response::*;
+
+        let arg =
+            |n: usize, target: &str| args.get(n).is_some_and(|a| a.eq_ignore_ascii_case(target));
+        match (self, response) {
+            (Query::Whois(nick), RPL_ENDOFWHOIS) if arg(1, nick) => Some(Step::End),
+            (
+                Query::Whois(nick),
+                RPL_WHOISUSER | RPL_WHOISSERVER | RPL_WHOISOPERATOR | RPL_WHOISIDLE
+                | RPL_WHOISCHANNELS | RPL_WHOISACCOUNT | RPL_WHOISSECURE | ERR_NOSUCHNICK
+                | ERR_NOSUCHSERVER,
+            ) if arg(1, nick) => Some(Step::Reply),
+            // `RPL_AWAY` also answers messages sent to away users, so it only belongs to a reply
+            // that has already started.
+            (Query::Whois(nick), RPL_AWAY)
+                if arg(1, nick)
+                    && replies.iter().any(|reply| {
+                        matches!(reply.command, Command::Response(RPL_WHOISUSER, _))
+                    }) =>
+            {
+                Some(Step::Reply)
+            }
+            (Query::Who(_, Some(token)), RPL_WHOSPCRPL) if arg(1, token) => Some(Step::Reply),
+            // Without WHOX, replies don't include the mask, so they're matched against it instead.
+            (Query::Who(mask, None), RPL_WHOREPLY) if who_reply_matches(mask, args) => {
+                Some(Step::Reply)
+            }
+            (Query::Who(mask, _), RPL_ENDOFWHO) if arg(1, mask) => Some(Step::End),
+            (Query::Names(chan), RPL_NAMREPLY) if arg(2, chan) => Some(Step::Reply),
+            (Query::Names(chan), RPL_ENDOFNAMES) if arg(1, chan) => Some(Step::End),
+            (Query::List, RPL_LISTSTART | RPL_LIST) => Some(Step::Reply),
+            (Query::List, RPL_LISTEND) => Some(Step::End),
+            (Query::List, ERR_TOOMANYMATCHES | ERR_UNKNOWNCOMMAND) if arg(1, "LIST") => {
+                Some(Step::End)
+            }
+            (
+                Query::ChannelModes(chan),
+                RPL_CHANNELMODEIS | ERR_NOSUCHCHANNEL | ERR_NOTONCHANNEL,
+            ) if arg(1, chan) => Some(Step::End),
+            (
+                Query::Topic(chan),
+                RPL_TOPIC | RPL_NOTOPIC | ERR_NOSUCHCHANNEL | ERR_NOTONCHANNEL,
+            ) if arg(1, chan) => Some(Step::End),
+            _ => None,
+        }
+    }
+}
+
+/// Gets whether a `RPL_WHOREPLY` could answer a `WHO` query about the specified mask, i.e. whether
+/// the mask is its channel or matches the user's nickname, username, hostname, server or real name.
+fn who_reply_matches(mask: &str, args: &[String]) -> bool {
+    if args.len() < 8 {
+        return false;
+    }
+    let realname = args[7].split_once(' ').map_or("", |(_, realname)| realname);
+    mask == "0"
+        || args[1].eq_ignore_ascii_case(mask)
+        || [&args[5], &args[2], &args[3], &args[4]]
+            .iter()
+            .any(|field| wildcard_matches(mask, field))
+        || wildcard_matches(mask, realname)
+        || wildcard_matches(mask, &format!("{}!{}@{}", args[5], args[2], args[3]))
+}
+
+/// Gets whether a string matches a mask, in which `*` stands for any number of characters and `?`
+/// for any single character, ignoring ASCII case.
+fn wildcard_matches(mask: &str, s: &str) -> bool {
+    let (mask, s) = (mask.as_bytes(), s.as_bytes());
+    let (mut m, mut i) = (0, 0);
+    // Where to resume after the last `*`, if the characters it skipped need to be extended.
+    let mut backtrack = None;
+    while i < s.len() {
+        match mask.get(m) {
+            Some(b'*') => {
+                backtrack = Some((m, i));
+                m += 1;
+            }
+            Some(&c) if c == b'?' || c.eq_ignore_ascii_case(&s[i]) => {
+                m += 1;
+                i += 1;
+            }
+            _ => match backtrack {
+                Some((star, skipped)) => {
+                    m = star + 1;
+                    i = skipped + 1;
+                    backtrack = Some((star, skipped + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    mask[m..].iter().all(|&c| c == b'*')
+}
+
+/// A query waiting for the rest of its reply.
+#[derive(Debug)]
+struct Pending {
+    id: u64,
+    query: Query,
+    /// The references of the batch carrying the reply, once it has started, followed by those of
+    /// any batches nested inside it.
+    batches: Vec<String>,
+    replies: Vec<Message>,
+    tx: oneshot::Sender<Vec<Message>>,
+}
+
+/// The queries waiting for their replies, in the order they were sent.
+#[derive(Debug, Default)]
+pub(crate) struct Queries {
+    next_id: u64,
+    next_token: u16,
+    pending: Vec<Pending>,
+}
+
+impl Queries {
+    /// Starts waiting for the reply to a query, returning an identifier for the query along with a
+    /// receiver for its reply.
+    pub(crate) fn add(&mut self, query: Query) -> (u64, oneshot::Receiver<Vec<Message>>) {
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.push(Pending {
+            id,
+            query,
+            batches: Vec::new(),
+            replies: Vec::new(),
+            tx,
+        });
+        (id, rx)
+    }
+
+    /// Gets a token to send with a WHOX query so that its replies can be told apart from those of
+    /// other queries. Tokens are at most three digits long.
+    pub(crate) fn whox_token(&mut self) -> String {
+        self.next_token = self.next_token % 999 + 1;
+        self.next_token.to_string()
+    }
+
+    /// Stops waiting for the reply to a query, e.g. because it timed out.
+    pub(crate) fn remove(&mut self, id: u64) {
+        self.pending.retain(|pending| pending.id != id);
+    }
+
+    /// Adds a message to the reply of the oldest query that it belongs to, if any.
+    pub(crate) fn handle(&mut self, msg: &Message) {
+        // Messages in a batch carrying a query's reply belong to that query.
+        let end = match msg.command {
+            Command::BATCH(ref reference, ..) => reference.strip_prefix('-'),
+            _ => None,
+        };
+        if let Some(batch) = end.or_else(|| msg.batch_id()) {
+            let found = self
+                .pending
+                .iter()
+                .position(|pending| pending.batches.iter().any(|b| b == batch));
+            if let Some(n) = found {
+                if end.is_some() && self.pending[n].batches[0] == batch {
+                    self.complete(n, msg);
+                } else {
+                    if let Command::BATCH(ref reference, ..) = msg.command {
+                        if let Some(reference) = reference.strip_prefix('+') {
+                            self.pending[n].batches.push(reference.to_owned());
+                        }
+                    }
+                    self.pending[n].replies.push(msg.clone());
+                }
+                return;
+            }
+        }
+
+        let found = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, pending)| pending.batches.is_empty())
+            .find_map(|(n, pending)| {
+                pending
+                    .query
+                    .step(msg, &pending.replies)
+                    .map(|step| (n, step))
+            });
+        match found {
+            Some((n, Step::Reply)) => self.pending[n].replies.push(msg.clone()),
+            Some((n, Step::Batch(reference))) => {
+                self.pending[n].batches.push(reference);
+                self.pending[n].replies.push(msg.clone());
+            }
+            Some((n, Step::End)) => self.complete(n, msg),
+            None => (),
+        }
+    }
+
+    /// Completes a query's reply with its final message.
+    fn complete(&mut self, n: usize, msg: &Message) {
+        let mut pending = self.pending.remove(n);
+        pending.replies.push(msg.clone());
+        // The receiver is gone if the query was cancelled, in which case no one cares.
+        let _ = pending.tx.send(pending.replies);
+    }
+}
+
+/// Stops waiting for a query when dropped, so that cancelled and timed out queries don't steal the
+/// replies of later ones.
+pub(crate) struct QueryGuard<'a> {
+    pub(crate) queries: &'a Mutex<Queries>,
+    pub(crate) id: u64,
+}
+
+impl Drop for QueryGuard<'_> {
+    fn drop(&mut self) {
+        self.queries.lock().remove(self.id);
+    }
+}
+
+/// A labeled message waiting for its response.
+#[derive(Debug)]
+struct PendingLabel {
+    /// The references of the `labeled-response` batch carrying the response, once it has started,
+    /// followed by those of any batches nested inside it.
+    batches: Vec<String>,
+    replies: Vec<Message>,
+    tx: oneshot::Sender<Vec<Message>>,
+}
+
+/// The messages sent with a `label` tag that are waiting for their responses, by label.
+#[derive(Debug)]
+pub(crate) struct Labels {
+    /// Whether the server has enabled the `labeled-response` capability.
+    pub(crate) enabled: bool,
+    /// How long to wait for a response.
+    pub(crate) timeout: Duration,
+    next_label: u64,
+    pending: HashMap<String, PendingLabel>,
+}
+
+impl Labels {
+    pub(crate) fn new(timeout: Duration) -> Labels {
+        Labels {
+            enabled: false,
+            timeout,
+            next_label: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Starts waiting for the response to a labeled message, returning the label to send it with
+    /// along with a receiver for the response.
+    pub(crate) fn add(&mut self) -> (String, oneshot::Receiver<Vec<Message>>) {
+        let (tx, rx) = oneshot::channel();
+        let label = format!("irc{}", self.next_label);
+        self.next_label += 1;
+        self.pending.insert(
+            label.clone(),
+            PendingLabel {
+                batches: Vec::new(),
+                replies: Vec::new(),
+                tx,
+            },
+        );
+        (label, rx)
+    }
+
+    /// Stops waiting for the response to a labeled message.
+    pub(crate) fn remove(&mut self, label: &str) {
+        self.pending.remove(label);
+    }
+
+    /// Adds a message to the response it belongs to, if any.
+    pub(crate) fn handle(&mut self, msg: &Message) {
+        let start = match msg.command {
+            Command::BATCH(ref reference, ..) => reference.strip_prefix('+'),
+            _ => None,
+        };
+
+        if let Some(label) = msg.label() {
+            match (start, self.pending.get_mut(label)) {
+                // A response consisting of several messages comes as a batch.
+                (Some(reference), Some(pending)) => {
+                    pending.batches.push(reference.to_owned());
+                    pending.replies.push(msg.clone());
+                }
+                // Anything else is the whole response, including an `ACK` for no response.
+                (None, Some(_)) => self.complete(label, msg),
+                (_, None) => (),
+            }
+            return;
+        }
+
+        let end = match msg.command {
+            Command::BATCH(ref reference, ..) => reference.strip_prefix('-'),
+            _ => None,
+        };
+        let batch = match end.or_else(|| msg.batch_id()) {
+            Some(batch) => batch,
+            None => return,
+        };
+        let label = match self
+            .pending
+            .iter_mut()
+            .find(|(_, pending)| pending.batches.iter().any(|b| b == batch))
+        {
+            Some((_, pending)) if end.is_none() || pending.batches[0] != batch => {
+                if let Some(reference) = start {
+                    pending.batches.push(reference.to_owned());
+                }
+                pending.replies.push(msg.clone());
+                return;
+            }
+            Some((label, _)) => label.clone(),
+            None => return,
+        };
+        self.complete(&label, msg);
+    }
+
+    /// Completes a response with its final message.
+    fn complete(&mut self, label: &str, msg: &Message) {
+        if let Some(mut pending) = self.pending.remove(label) {
+            pending.replies.push(msg.clone());
+            // The receiver is gone if the message's sender stopped waiting, so no one cares.
+            let _ = pending.tx.send(pending.replies);
+        }
+    }
+}
+
+/// Stops waiting for the response to a labeled message when dropped.
+pub(crate) struct LabelGuard<'a> {
+    pub(crate) labels: &'a Mutex<Labels>,
+    pub(crate) label: String,
+}
+
+impl Drop for LabelGuard<'_> {
+    fn drop(&mut self) {
+        self.labels.lock().remove(&self.label);
+    }
+}
+
+/// A message waiting for the server to echo it back.
+#[derive(Debug)]
+struct PendingEcho {
+    id: u64,
+    target: String,
+    text: String,
+    tx: oneshot::Sender<Message>,
+}
+
+/// The messages sent while `echo-message` is enabled that are waiting for their echoes, in the
+/// order they were sent. Without a label to go by, an echo belongs to the oldest message with the
+/// same target and text.
+#[derive(Debug, Default)]
+pub(crate) struct Echoes {
+    next_id: u64,
+    pending: Vec<PendingEcho>,
+}
+
+impl Echoes {
+    /// Starts waiting for the echo of a message, returning its ID along with a receiver for the
+    /// echo.
+    pub(crate) fn add(&mut self, target: &str, text: &str) -> (u64, oneshot::Receiver<Message>) {
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.push(PendingEcho {
+            id,
+            target: target.to_owned(),
+            text: text.to_owned(),
+            tx,
+        });
+        (id, rx)
+    }
+
+    /// Stops waiting for the echo of a message.
+    pub(crate) fn remove(&mut self, id: u64) {
+        self.pending.retain(|pending| pending.id != id);
+    }
+
+    /// Hands one of our echoed messages to the oldest message waiting for it, if any. Servers may
+    /// alter the text of messages, so an echo that doesn't match any text goes to the oldest
+    /// message sent to the same target.
+    pub(crate) fn handle(&mut self, msg: &Message) {
+        let (target, text) = match msg.command {
+            Command::PRIVMSG(ref target, ref text) => (target, text),
+            _ => return,
+        };
+        let to_target = |p: &PendingEcho| p.target.eq_ignore_ascii_case(target);
+        if let Some(n) = self
+            .pending
+            .iter()
+            .position(|p| to_target(p) && p.text == *text)
+            .or_else(|| self.pending.iter().position(to_target))
+        {
+            // The receiver is gone if the message was cancelled, in which case no one cares.
+            let _ = self.pending.remove(n).tx.send(msg.clone());
+        }
+    }
+}
+
+/// Stops waiting for an echo when dropped.
+pub(crate) struct EchoGuard<'a> {
+    pub(crate) echoes: &'a Mutex<Echoes>,
+    pub(crate) id: u64,
+}
+
+impl Drop for EchoGuard<'_> {
+    fn drop(&mut self) {
+        self.echoes.lock().remove(self.id);
+    }
+}
+
+/// Gets the numeric and arguments of a reply message.
+fn numeric(msg: &Message) -> Option<(Response, &[String])> {
+    match msg.command {
+        Command::Response(response, ref args) => Some((response, args)),
+        _ => None,
+    }
+}
+
+/// The fields requested by WHOX queries sent by [`Client::who`](crate::client::Client::who), in the
+/// order that servers reply with them: the token, channel, username, hostname, server, nickname,
+/// flags, hop count and real name.
+pub(crate) const WHOX_FIELDS: &str = "%tcuhsnfdr";
+
+/// Fails if any of the replies to a query is an error numeric.
+fn check_errors(replies: &[Message]) -> error::Result<()> {
+    match replies
+        .iter()
+        .filter_map(numeric)
+        .find(|(response, _)| response.is_error())
+    {
+        Some((code, args)) => Err(error::Error::QueryFailed {
+            code,
+            message: args.last().cloned().unwrap_or_default(),
+        }),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn parse_whois(nickname: &str, replies: &[Message]) -> error::Result<WhoisInfo> {
+    check_errors(replies)?;
+    let mut info = WhoisInfo {
+        nickname: nickname.to_owned(),
+        ..WhoisInfo::default()
+    };
+    for (response, args) in replies.iter().filter_map(numeric) {
+        let arg = |n: usize| args.get(n).cloned();
+        match response {
+            Response::RPL_WHOISUSER => {
+                info.nickname = arg(1).unwrap_or(info.nickname);
+                info.username = arg(2);
+                info.hostname = arg(3);
+                info.realname = arg(5);
+            }
+            Response::RPL_WHOISSERVER => {
+                info.server = arg(2);
+                info.server_info = arg(3);
+            }
+            Response::RPL_WHOISOPERATOR => info.is_operator = true,
+            Response::RPL_WHOISIDLE => {
+                info.idle = arg(2)
+                    .and_then(|idle| idle.parse().ok())
+                    .map(Duration::from_secs);
+                // The signon time is a common extension, but not part of RFC 2812.
+                info.signon = args
+                    .get(3)
+                    .filter(|_| args.len() > 4)
+                    .and_then(|t| parse_timestamp(t));
+            }
+            Response::RPL_WHOISCHANNELS => info.channels.extend(
+                args.last()
+                    .into_iter()
+                    .flat_map(|channels| channels.split_whitespace())
+                    .map(|chan| chan.to_owned()),
+            ),
+            Response::RPL_WHOISACCOUNT => info.account = arg(2),
+            Response::RPL_WHOISSECURE => info.is_secure = true,
+            Response::RPL_AWAY => info.away_message = arg(2),
+            _ => (),
+        }
+    }
+    Ok(info)
+}
+
+pub(crate) fn parse_who(replies: &[Message]) -> error::Result<Vec<WhoReply>> {
+    check_errors(replies)?;
+    Ok(replies
+        .iter()
+        .filter_map(numeric)
+        .filter_map(|(response, args)| {
+            let (chan, fields, hopcount, realname) = match response {
+                Response::RPL_WHOREPLY if args.len() > 7 => {
+                    let (hopcount, realname) = args[7].split_once(' ').unwrap_or((&args[7], ""));
+                    (&args[1], &args[2..7], hopcount, realname)
+                }
+                // The fields of WHOX replies are those requested by `WHOX_FIELDS`, after the token.
+                Response::RPL_WHOSPCRPL if args.len() > 9 => {
+                    (&args[2], &args[3..8], &args[8][..], &args[9][..])
+                }
+                _ => return None,
+            };
+            Some(WhoReply {
+                channel: Some(chan.clone()).filter(|chan| chan != "*"),
+                username: fields[0].clone(),
+                hostname: fields[1].clone(),
+                server: fields[2].clone(),
+                nickname: fields[3].clone(),
+                flags: fields[4].clone(),
+                hopcount: hopcount.parse().unwrap_or(0),
+                realname: realname.to_owned(),
+            })
+        })
+        .collect())
+}
+
+pub(crate) fn parse_names(replies: &[Message], prefix_map: &PrefixMap) -> error::Result<Vec<User>> {
+    check_errors(replies)?;
+    Ok(replies
+        .iter()
+        .filter_map(numeric)
+        .filter(|(response, args)| *response == Response::RPL_NAMREPLY && args.len() > 3)
+        .flat_map(|(_, args)| args[3].split_whitespace())
+        .map(|name| User::with_prefix_map(name, prefix_map))
+        .collect())
+}
+
+pub(crate) fn parse_list(replies: &[Message]) -> error::Result<Vec<ListEntry>> {
+    check_errors(replies)?;
+    Ok(replies
+        .iter()
+        .filter_map(numeric)
+        .filter(|(response, args)| *response == Response::RPL_LIST && args.len() > 2)
+        .map(|(_, args)| ListEntry {
+            channel: args[1].clone(),
+            users: args[2].parse().unwrap_or(0),
+            topic: args.get(3).cloned().unwrap_or_default(),
+        })
+        .collect())
+}
+
+pub(crate) fn parse_channel_modes(
+    replies: &[Message],
+    prefix_map: &PrefixMap,
+    chanmodes: Option<&str>,
+) -> error::Result<Vec<Mode<ChannelMode>>> {
+    check_errors(replies)?;
+    let args = match replies.iter().rev().find_map(numeric) {
+        Some((Response::RPL_CHANNELMODEIS, args)) if args.len() > 2 => args,
+        _ => return Ok(Vec::new()),
+    };
+    let pieces: Vec<_> = args[2..].iter().map(|s| &s[..]).collect();
+    Ok(parse_modes(&pieces, prefix_map, chanmodes))
+}
+
+pub(crate) fn parse_history(replies: &[Message]) -> error::Result<Vec<HistoryMessage>> {
+    let mut history = Vec::new();
+    for msg in replies {
+        match msg.command {
+            Command::Raw(ref command, ref args) if command == "FAIL" => {
+                return Err(error::Error::CommandFailed {
+                    command: args.first().cloned().unwrap_or_default(),
+                    code: args.get(1).cloned().unwrap_or_default(),
+                    description: args.last().cloned().unwrap_or_default(),
+                })
+            }
+            // The batches themselves, and the `ACK` for an empty labeled response, aren't history.
+            Command::BATCH(..) => (),
+            Command::Raw(ref command, _) if command == "ACK" => (),
+            _ => history.push(HistoryMessage {
+                time: msg.server_time(),
+                msgid: msg.msgid().map(|id| id.to_owned()),
+                message: msg.clone(),
+            }),
+        }
+    }
+    Ok(history)
+}
+
+/// Formats a timestamp like `server-time` does.
+fn format_time(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Finds the echo of a message in its labeled response.
+pub(crate) fn parse_delivery(replies: &[Message]) -> error::Result<Delivery> {
+    check_errors(replies)?;
+    replies
+        .iter()
+        .find(|msg| matches!(msg.command, Command::PRIVMSG(..)))
+        .map(|msg| Delivery::new(msg.clone()))
+        .ok_or(error::Error::NotEchoed)
+}
+
+pub(crate) fn parse_topic(replies: &[Message]) -> error::Result<Option<String>> {
+    check_errors(replies)?;
+    Ok(replies
+        .iter()
+        .filter_map(numeric)
+        .find(|(response, _)| *response == Response::RPL_TOPIC)
+        .and_then(|(_, args)| args.get(2).cloned()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::{
+        parse_list, parse_who, parse_whois, wildcard_matches, Echoes, HistoryRequest, Labels,
+        MessageRef, Queries, Query,
+    };
+    use crate::{
+        client::{data::Config, test::test_config, Client},
+        error::Error,
+        proto::{ChannelMode, Command, Message, Mode},
+    };
+    use anyhow::Result;
+
+    fn messages(lines: &[&str]) -> Vec<Message> {
+        lines.iter().map(|line| line.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn concurrent_queries() {
+        let mut queries = Queries::default();
+        let (_, mut alice) = queries.add(Query::Whois("alice".to_owned()));
+        let (_, mut bob) = queries.add(Query::Whois("bob".to_owned()));
+        let (id, _) = queries.add(Query::Who("#test".to_owned(), None));
+        queries.remove(id);
+
+        for msg in messages(&[
+            ":irc.test.net 311 test bob b host * :Bob",
+            ":irc.test.net 311 test Alice a host * :Alice",
+            ":irc.test.net 352 test #test a host irc.test.net alice H :0 Alice",
+            ":irc.test.net 318 test Alice :End of /WHOIS list.",
+        ]) {
+            queries.handle(&msg);
+        }
+        assert_eq!(alice.try_recv().unwrap().len(), 2);
+        assert!(bob.try_recv().is_err());
+        assert_eq!(queries.pending.len(), 1);
+    }
+
+    #[test]
+    fn whois() {
+        let replies = messages(&[
+            ":irc.test.net 311 test alice a host * :Alice A.",
+            ":irc.test.net 312 test alice irc.test.net :Test server",
+            ":irc.test.net 317 test alice 42 1234567890 :seconds idle, signon time",
+            ":irc.test.net 319 test alice :@#test +#test2",
+            ":irc.test.net 330 test alice acct :is logged in as",
+            ":irc.test.net 301 test alice :Gone fishing",
+            ":irc.test.net 318 test alice :End of /WHOIS list.",
+        ]);
+        let info = parse_whois("alice", &replies).unwrap();
+        assert_eq!(info.username.as_deref(), Some("a"));
+        assert_eq!(info.realname.as_deref(), Some("Alice A."));
+        assert_eq!(info.server_info.as_deref(), Some("Test server"));
+        assert_eq!(info.idle.map(|idle| idle.as_secs()), Some(42));
+        assert_eq!(info.signon.map(|t| t.timestamp()), Some(1234567890));
+        assert_eq!(info.channels, vec!["@#test", "+#test2"]);
+        assert_eq!(info.account.as_deref(), Some("acct"));
+        assert_eq!(info.away_message.as_deref(), Some("Gone fishing"));
+
+        let replies = messages(&[
+            ":irc.test.net 401 test bob :No such nick/channel",
+            ":irc.test.net 318 test bob :End of /WHOIS list.",
+        ]);
+        match parse_whois("bob", &replies) {
+            Err(Error::QueryFailed { message, .. }) => assert_eq!(message, "No such nick/channel"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn unrelated_replies() {
+        let mut queries = Queries::default();
+        let (_, mut whois) = queries.add(Query::Whois("alice".to_owned()));
+        let (_, mut who) = queries.add(Query::Who("b*".to_owned(), None));
+        let (_, mut whox) = queries.add(Query::Who("#test".to_owned(), Some("7".to_owned())));
+        let (_, mut list) = queries.add(Query::List);
+
+        for msg in messages(&[
+            // The answer to an earlier message to alice, and a WHO sent by someone else.
+            ":irc.test.net 301 test alice :Gone fishing",
+            ":irc.test.net 352 test #test c host irc.test.net carol H :0 Carol",
+            ":irc.test.net 354 test 8 #test c host irc.test.net carol H 0 :Carol",
+            ":irc.test.net 311 test alice a host * :Alice",
+            ":irc.test.net 301 test alice :Gone fishing",
+            ":irc.test.net 318 test alice :End of /WHOIS list.",
+            ":irc.test.net 352 test #test b host irc.test.net bob H :0 Bob",
+            ":irc.test.net 315 test b* :End of /WHO list.",
+            ":irc.test.net 354 test 7 #test c host irc.test.net carol H 0 :Carol",
+            ":irc.test.net 315 test #test :End of /WHO list.",
+            ":irc.test.net 416 test LIST :Too many matches",
+        ]) {
+            queries.handle(&msg);
+        }
+        assert_eq!(whois.try_recv().unwrap().len(), 3);
+        let who = parse_who(&who.try_recv().unwrap()).unwrap();
+        assert_eq!(who.len(), 1);
+        assert_eq!(who[0].nickname, "bob");
+        let whox = parse_who(&whox.try_recv().unwrap()).unwrap();
+        assert_eq!(whox.len(), 1);
+        assert_eq!(whox[0].realname, "Carol");
+        assert_eq!(whox[0].channel.as_deref(), Some("#test"));
+        assert!(matches!(
+            parse_list(&list.try_recv().unwrap()),
+            Err(Error::QueryFailed { .. })
+        ));
+        assert!(queries.pending.is_empty());
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_matches("*", ""));
+        assert!(wildcard_matches("A*e", "alice"));
+        assert!(wildcard_matches("*!*@host", "alice!a@host"));
+        assert!(wildcard_matches("a?i*", "alice"));
+        assert!(!wildcard_matches("a?i", "alice"));
+        assert!(!wildcard_matches("*@host", "alice!a@host.net"));
+    }
+
+    #[tokio::test]
+    async fn whox_query() -> Result<()> {
+        let value = ":irc.test.net 354 test 1 #test a host irc.test.net alice H@ 0 :Alice\r\n\
+                     :irc.test.net 315 test #test :End of /WHO list.\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        client.state.handle_message(
+            &":irc.test.net 005 test WHOX :are supported by this server".parse()?,
+        )?;
+
+        let stream = client.stream()?;
+        let (who, _) = tokio::join!(client.who("#test"), stream.collect());
+        let who = who?;
+        assert_eq!(who.len(), 1);
+        assert_eq!(who[0].flags, "H@");
+        assert_eq!(
+            client.log_view().sent()?[0].to_string(),
+            "WHO #test %tcuhsnfdr,1\r\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queries_through_the_stream() -> Result<()> {
+        let value = ":irc.test.net 332 test #test :Welcome!\r\n\
+                     :irc.test.net 324 test #test +ntk secret\r\n\
+                     :irc.test.net 353 test = #test :test @alice\r\n\
+                     :irc.test.net 366 test #test :End of /NAMES list.\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        let stream = client.stream()?;
+
+        let (topic, modes, names, messages) = tokio::join!(
+            client.topic("#test"),
+            client.channel_modes("#test"),
+            client.names("#test"),
+            stream.collect(),
+        );
+        assert_eq!(topic?.as_deref(), Some("Welcome!"));
+        assert_eq!(
+            modes?,
+            vec![
+                Mode::Plus(ChannelMode::NoExternalMessages, None),
+                Mode::Plus(ChannelMode::ProtectedTopic, None),
+                Mode::Plus(ChannelMode::Key, Some("secret".to_owned())),
+            ]
+        );
+        let names = names?;
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[1].get_nickname(), "alice");
+        // The replies still reach the stream.
+        assert_eq!(messages?.len(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_timeout() -> Result<()> {
+        let mut client = Client::from_config(Config {
+            query_timeout: Some(0),
+            ..test_config()
+        })
+        .await?;
+        let _stream = client.stream()?;
+        assert!(matches!(
+            client.whois("alice").await,
+            Err(Error::QueryTimeout)
+        ));
+        assert!(client.state.queries.lock().pending.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn labeled_responses() {
+        let mut labels = Labels::new(Duration::from_secs(1));
+        let (_, mut ack) = labels.add();
+        let (_, mut single) = labels.add();
+        let (_, mut batch) = labels.add();
+        for msg in messages(&[
+            "@label=irc2 :irc.test.net BATCH +outer labeled-response",
+            "@label=irc0 :irc.test.net ACK",
+            "@batch=outer :irc.test.net BATCH +inner example",
+            "@batch=inner :irc.test.net NOTICE test :inner",
+            ":irc.test.net BATCH -inner",
+            "@batch=unrelated :irc.test.net NOTICE test :unrelated",
+            "@batch=outer :irc.test.net NOTICE test :outer",
+            "@label=irc1 :irc.test.net PONG irc.test.net",
+            ":irc.test.net BATCH -outer",
+        ]) {
+            labels.handle(&msg);
+        }
+        assert_eq!(ack.try_recv().unwrap().len(), 1);
+        assert_eq!(
+            single.try_recv().unwrap()[0].to_string(),
+            "@label=irc1 :irc.test.net PONG irc.test.net\r\n"
+        );
+        let batch = batch.try_recv().unwrap();
+        assert_eq!(batch.len(), 6);
+        assert_eq!(batch[5].to_string(), ":irc.test.net BATCH -outer\r\n");
+        assert!(labels.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn labeled_query() -> Result<()> {
+        let value = "@label=irc0 :irc.test.net BATCH +b labeled-response\r\n\
+                     @batch=b :irc.test.net 311 test alice a host * :Alice\r\n\
+                     @batch=b :irc.test.net 318 test alice :End of /WHOIS list.\r\n\
+                     :irc.test.net BATCH -b\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        assert!(matches!(
+            client
+                .sender()
+                .send_labeled(Command::PING("test".to_owned(), None))
+                .await,
+            Err(Error::LabeledResponseUnsupported)
+        ));
+        client
+            .state
+            .handle_message(&":irc.test.net CAP * ACK :labeled-response batch".parse()?)?;
+
+        let stream = client.stream()?;
+        let (info, _) = tokio::join!(client.whois("alice"), stream.collect());
+        assert_eq!(info?.realname.as_deref(), Some("Alice"));
+        assert_eq!(
+            client.log_view().sent()?[0].to_string(),
+            "@label=irc0 WHOIS alice\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn history_commands() {
+        let start = Utc.timestamp_opt(1234567890, 0).unwrap();
+        let request = HistoryRequest::Between {
+            target: "#test".to_owned(),
+            start: MessageRef::Timestamp(start),
+            end: MessageRef::MsgId("abc".to_owned()),
+        };
+        assert_eq!(
+            Message::from(request.command(20)).to_string(),
+            "CHATHISTORY BETWEEN #test timestamp=2009-02-13T23:31:30.000Z msgid=abc 20\r\n"
+        );
+        assert_eq!(request.query(), Query::History(Some("#test".to_owned())));
+    }
+
+    #[tokio::test]
+    async fn chathistory() -> Result<()> {
+        let value = ":irc.test.net FAIL CHATHISTORY INVALID_TARGET LATEST #nope :No such target\r\n\
+                     :irc.test.net BATCH +h chathistory #test\r\n\
+                     @batch=h;time=2019-01-04T14:33:26.123Z;msgid=1 :alice!a@host PRIVMSG #test :hi\r\n\
+                     @batch=h;msgid=2 :bob!b@host PRIVMSG #test :hello\r\n\
+                     :irc.test.net BATCH -h\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        let latest = |target: &str| HistoryRequest::Latest {
+            target: target.to_owned(),
+            after: None,
+        };
+        assert!(matches!(
+            client.chathistory(latest("#test"), 50).await,
+            Err(Error::ChatHistoryUnsupported)
+        ));
+        for line in &[
+            ":irc.test.net CAP * ACK :draft/chathistory batch server-time",
+            ":irc.test.net 005 test CHATHISTORY=10 :are supported by this server",
+        ] {
+            client.state.handle_message(&line.parse()?)?;
+        }
+
+        let stream = client.stream()?;
+        let (failed, history, _) = tokio::join!(
+            client.chathistory(latest("#nope"), 50),
+            client.chathistory(latest("#test"), 50),
+            stream.collect(),
+        );
+        assert!(
+            matches!(failed, Err(Error::CommandFailed { code, .. }) if code == "INVALID_TARGET")
+        );
+        let history = history?;
+        assert_eq!(history.len(), 2);
+        assert_eq!(
+            history[0].time.map(|t| t.timestamp_millis()),
+            Some(1546612406123)
+        );
+        assert_eq!(history[1].msgid.as_deref(), Some("2"));
+        assert_eq!(history[1].time, None);
+        assert_eq!(
+            client.log_view().sent()?[1].to_string(),
+            "CHATHISTORY LATEST #test * 10\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn altered_echoes() {
+        let mut echoes = Echoes::default();
+        let (_, mut bold) = echoes.add("#test", "\u{2}hi\u{2}");
+        let (_, mut plain) = echoes.add("#test", "there");
+        let (_, mut other) = echoes.add("#other", "hi");
+        for msg in messages(&[
+            ":test!t@host PRIVMSG #test :there",
+            // The server stripped the formatting.
+            ":test!t@host PRIVMSG #test :hi",
+        ]) {
+            echoes.handle(&msg);
+        }
+        assert!(plain.try_recv().unwrap().to_string().ends_with("there\r\n"));
+        assert!(bold.try_recv().unwrap().to_string().ends_with("hi\r\n"));
+        assert!(other.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn confirmed_delivery() -> Result<()> {
+        let value = ":alice!a@host PRIVMSG #test :hi\r\n\
+                     @msgid=abc;time=2019-01-04T14:33:26.123Z :test!t@host PRIVMSG #Test :hi\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            suppress_echoes: true,
+            ..test_config()
+        })
+        .await?;
+        assert!(matches!(
+            client.send_privmsg_confirmed("#test", "hi").await,
+            Err(Error::EchoMessageUnsupported)
+        ));
+        client
+            .state
+            .handle_message(&":irc.test.net CAP * ACK :echo-message".parse()?)?;
+        assert!(matches!(
+            client.send_privmsg_confirmed("#test", "hi\r\nQUIT").await,
+            Err(Error::LineBreak)
+        ));
+
+        let stream = client.stream()?;
+        let (delivery, messages) = tokio::join!(
+            client.send_privmsg_confirmed("#test", "hi"),
+            stream.collect()
+        );
+        let delivery = delivery?;
+        assert_eq!(delivery.msgid.as_deref(), Some("abc"));
+        assert_eq!(
+            delivery.time.map(|t| t.timestamp_millis()),
+            Some(1546612406123)
+        );
+        // Only alice's message is left once our echo is suppressed.
+        let messages = messages?;
+        assert_eq!(messages.len(), 1);
+        assert!(!client.is_echo(&messages[0]));
+        assert!(client.is_echo(&delivery.message));
+        Ok(())
+    }
+}
@@ -0,0 +1,406 @@
+//! The queue of messages waiting to be sent to the server.
+//!
+//! Messages are queued by [`Priority`], and each priority is emptied before moving on to the next,
+//! so that e.g. replies to users aren't held back by a bot's long output. Messages of the
+//! [`Protocol`](Priority::Protocol) and [`Interactive`](Priority::Interactive) priorities are sent
+//! in the order they were queued, since e.g. a `PART` must not overtake the messages sent to the
+//! channel before it. For [`Bulk`](Priority::Bulk) messages, the messages for each target (i.e.
+//! the channel or user of a `PRIVMSG`, `NOTICE` or `TAGMSG`) are sent in turn instead, so that
+//! flooding one channel doesn't hold back the others. Bulk messages without a target, like a
+//! `PART`, are only sent once all the bulk messages queued before them have been, and those queued
+//! after them wait in turn. Targets are compared ignoring ASCII case, like the targets of
+//! [`Config::channel_encodings`](crate::client::data::Config::channel_encodings).
+//!
+//! # Example
+//! ```no_run
+//! # use irc::client::prelude::*;
+//! use irc::client::queue::Priority;
+//!
+//! # async fn example(client: &Client) -> irc::error::Result<()> {
+//! let bulk = client.sender().with_priority(Priority::Bulk);
+//! for line in 0..1000 {
+//!     bulk.send_privmsg("#logs", line)?;
+//! }
+//! // This is sent before the rest of the logs.
+//! client.send_privmsg("#help", "Hang on, I'm busy!")?;
+//! // And this drops whatever is left of them.
+//! client.cancel_queued("#logs");
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::VecDeque,
+    task::{Context, Poll, Waker},
+};
+
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use crate::{
+    error,
+    proto::{Command, Message},
+};
+
+/// The priority with which a message is sent to the server. Messages of a higher priority are
+/// sent before any of a lower one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Messages that keep the connection going, i.e. `PING` and `PONG`.
+    Protocol,
+    /// Messages that someone is waiting on. This is the default for anything else.
+    Interactive,
+    /// Messages that can wait, like long outputs.
+    Bulk,
+}
+
+impl Priority {
+    /// Gets the priority that a message is sent with by default.
+    pub fn of(msg: &Message) -> Priority {
+        match msg.command {
+            Command::PING(..) | Command::PONG(..) => Priority::Protocol,
+            _ => Priority::Interactive,
+        }
+    }
+}
+
+/// Gets the target whose messages are sent in turn with those of other targets, if any.
+fn target(msg: &Message) -> Option<String> {
+    match msg.command {
+        Command::PRIVMSG(ref target, _) | Command::NOTICE(ref target, _) => {
+            Some(target.to_ascii_lowercase())
+        }
+        Command::Raw(ref command, ref args) if command == "TAGMSG" => {
+            args.first().map(|target| target.to_ascii_lowercase())
+        }
+        _ => None,
+    }
+}
+
+/// The messages of a single priority, grouped by target.
+#[derive(Debug)]
+struct Lane {
+    /// Whether the targets take turns, rather than the messages being sent in the order they were
+    /// queued.
+    fair: bool,
+    /// The messages for each target, in the order that the targets take turns if the lane is fair.
+    /// Otherwise, each group is a run of consecutive messages for the same target, in the order
+    /// they were queued. Either way, a group without a target is a run of consecutive messages
+    /// that must wait for every group before it, and that every group after it waits for.
+    targets: VecDeque<(Option<String>, VecDeque<Message>)>,
+}
+
+impl Lane {
+    fn new(fair: bool) -> Lane {
+        Lane {
+            fair,
+            targets: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, msg: Message) {
+        let target = target(&msg);
+        let group = if self.fair && target.is_some() {
+            // Only the targets queued since the last untargeted messages can take more.
+            let start = self
+                .targets
+                .iter()
+                .rposition(|(t, _)| t.is_none())
+                .map_or(0, |last| last + 1);
+            self.targets.range_mut(start..).find(|(t, _)| *t == target)
+        } else {
+            self.targets.back_mut().filter(|(t, _)| *t == target)
+        };
+        match group {
+            Some((_, messages)) => messages.push_back(msg),
+            None => self.targets.push_back((target, VecDeque::from(vec![msg]))),
+        }
+    }
+
+    /// Takes the next message, from the target whose turn it is if the lane is fair.
+    fn pop(&mut self) -> Option<Message> {
+        let (target, mut messages) = self.targets.pop_front()?;
+        let msg = messages.pop_front();
+        if !messages.is_empty() {
+            if self.fair && target.is_some() {
+                // The target takes its next turn before any untargeted messages.
+                let next = self
+                    .targets
+                    .iter()
+                    .position(|(t, _)| t.is_none())
+                    .unwrap_or(self.targets.len());
+                self.targets.insert(next, (target, messages));
+            } else {
+                self.targets.push_front((target, messages));
+            }
+        }
+        msg
+    }
+
+    /// Removes the messages for a target, returning how many there were.
+    fn cancel(&mut self, target: &str) -> usize {
+        let mut cancelled = 0;
+        self.targets.retain(|(t, messages)| {
+            let matches = t.as_deref() == Some(target);
+            if matches {
+                cancelled += messages.len();
+            }
+            !matches
+        });
+        cancelled
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// The messages of each priority, highest first.
+    lanes: [Lane; 3],
+    len: usize,
+    /// The `QUIT` that ends the connection, which is sent once everything else has been.
+    quit: Option<Message>,
+    /// Whether the messages will never be sent, because the client's stream is gone.
+    closed: bool,
+    /// Wakes the client's stream when there is a message to send.
+    waker: Option<Waker>,
+}
+
+impl Inner {
+    fn new() -> Inner {
+        Inner {
+            lanes: [Lane::new(false), Lane::new(false), Lane::new(true)],
+            len: 0,
+            quit: None,
+            closed: false,
+            waker: None,
+        }
+    }
+
+    fn push(&mut self, msg: Message, priority: Priority) {
+        self.lanes[priority as usize].push(msg);
+        self.len += 1;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The messages queued by the client's [`Senders`](crate::client::Sender), which are limited to a
+/// capacity if the queue is bounded.
+#[derive(Debug)]
+pub(crate) struct Queue {
+    inner: Mutex<Inner>,
+    capacity: Option<usize>,
+    /// Notified whenever a message leaves the queue.
+    dequeued: Notify,
+}
+
+impl Queue {
+    pub(crate) fn new(capacity: Option<usize>) -> Queue {
+        Queue {
+            inner: Mutex::new(Inner::new()),
+            capacity,
+            dequeued: Notify::new(),
+        }
+    }
+
+    /// Gets the number of messages in the queue.
+    pub(crate) fn len(&self) -> usize {
+        self.inner.lock().len
+    }
+
+    /// Adds a message to the queue, failing if it is full and `bounded`.
+    pub(crate) fn push(
+        &self,
+        msg: Message,
+        priority: Priority,
+        bounded: bool,
+    ) -> error::Result<()> {
+        let mut inner = self.inner.lock();
+        if inner.closed {
+            return Err(error::Error::AsyncChannelClosed);
+        }
+        if bounded && self.is_full(&inner) {
+            return Err(error::Error::QueueFull);
+        }
+        inner.push(msg, priority);
+        Ok(())
+    }
+
+    /// Adds a message to the queue, waiting until there is room for it.
+    pub(crate) async fn push_async(&self, msg: Message, priority: Priority) -> error::Result<()> {
+        loop {
+            // The notification must be set up before checking, so that a message leaving the queue
+            // in between isn't missed.
+            let dequeued = self.dequeued.notified();
+            {
+                let mut inner = self.inner.lock();
+                if inner.closed {
+                    return Err(error::Error::AsyncChannelClosed);
+                }
+                if !self.is_full(&inner) {
+                    inner.push(msg, priority);
+                    return Ok(());
+                }
+            }
+            dequeued.await;
+        }
+    }
+
+    /// Queues the `QUIT` to send once the queue is otherwise empty.
+    pub(crate) fn push_quit(&self, msg: Message) -> error::Result<()> {
+        let mut inner = self.inner.lock();
+        if inner.closed {
+            return Err(error::Error::AsyncChannelClosed);
+        }
+        inner.quit = Some(msg);
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Removes the queued messages for a target, returning how many there were.
+    pub(crate) fn cancel(&self, target: &str) -> usize {
+        let target = target.to_ascii_lowercase();
+        let mut inner = self.inner.lock();
+        let cancelled: usize = inner.lanes.iter_mut().map(|l| l.cancel(&target)).sum();
+        inner.len -= cancelled;
+        drop(inner);
+        if cancelled > 0 {
+            self.dequeued.notify_waiters();
+        }
+        cancelled
+    }
+
+    /// Takes the next message to send, if there is any.
+    pub(crate) fn pop(&self) -> Option<Message> {
+        let mut inner = self.inner.lock();
+        let msg = match inner.lanes.iter_mut().find_map(|lane| lane.pop()) {
+            Some(msg) => {
+                inner.len -= 1;
+                msg
+            }
+            None => return inner.quit.take(),
+        };
+        drop(inner);
+        self.dequeued.notify_waiters();
+        Some(msg)
+    }
+
+    /// Takes the next message to send, or registers to be woken once there is one.
+    pub(crate) fn poll_pop(&self, cx: &mut Context<'_>) -> Poll<Message> {
+        if let Some(msg) = self.pop() {
+            return Poll::Ready(msg);
+        }
+        let mut inner = self.inner.lock();
+        // A message may have arrived since we checked.
+        if inner.len > 0 || inner.quit.is_some() {
+            cx.waker().wake_by_ref();
+        } else {
+            inner.waker = Some(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    /// Records that the queued messages will never be sent, failing any further ones.
+    pub(crate) fn close(&self) {
+        self.inner.lock().closed = true;
+        self.dequeued.notify_waiters();
+    }
+
+    fn is_full(&self, inner: &Inner) -> bool {
+        self.capacity.is_some_and(|capacity| inner.len >= capacity)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Priority, Queue};
+    use crate::{error::Error, proto::Command};
+
+    fn privmsg(target: &str, text: &str) -> crate::proto::Message {
+        Command::PRIVMSG(target.to_owned(), text.to_owned()).into()
+    }
+
+    #[test]
+    fn priorities_and_fairness() {
+        let queue = Queue::new(None);
+        for n in 0..3 {
+            let msg = privmsg("#flood", &n.to_string());
+            queue.push(msg, Priority::Bulk, true).unwrap();
+        }
+        queue
+            .push(privmsg("#Quiet", "hi"), Priority::Bulk, true)
+            .unwrap();
+        // Untargeted bulk messages wait for the ones before them, and hold back the ones after.
+        let leave = Command::PART("#flood".to_owned(), None).into();
+        queue.push(leave, Priority::Bulk, true).unwrap();
+        queue
+            .push(privmsg("#quiet", "bye"), Priority::Bulk, true)
+            .unwrap();
+        queue
+            .push(privmsg("#help", "busy"), Priority::Interactive, true)
+            .unwrap();
+        // Interactive messages keep their order, whatever their targets.
+        let part = Command::PART("#help".to_owned(), None).into();
+        queue.push(part, Priority::Interactive, true).unwrap();
+        queue
+            .push(privmsg("#help", "late"), Priority::Interactive, true)
+            .unwrap();
+        queue.push_quit(Command::QUIT(None).into()).unwrap();
+        let pong = Command::PONG("irc.test.net".to_owned(), None).into();
+        queue.push(pong, Priority::Protocol, true).unwrap();
+        assert_eq!(queue.len(), 10);
+
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop())
+            .map(|msg| msg.to_string())
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                "PONG irc.test.net\r\n",
+                "PRIVMSG #help busy\r\n",
+                "PART #help\r\n",
+                "PRIVMSG #help late\r\n",
+                "PRIVMSG #flood 0\r\n",
+                "PRIVMSG #Quiet hi\r\n",
+                "PRIVMSG #flood 1\r\n",
+                "PRIVMSG #flood 2\r\n",
+                "PART #flood\r\n",
+                "PRIVMSG #quiet bye\r\n",
+                "QUIT\r\n",
+            ]
+        );
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn cancel() {
+        let queue = Queue::new(Some(3));
+        queue
+            .push(privmsg("#a", "1"), Priority::Interactive, true)
+            .unwrap();
+        queue
+            .push(privmsg("#b", "2"), Priority::Interactive, true)
+            .unwrap();
+        queue
+            .push(privmsg("#a", "3"), Priority::Bulk, true)
+            .unwrap();
+        // Only ASCII letters are folded.
+        assert_eq!(queue.cancel("#Ä"), 0);
+        assert!(matches!(
+            queue.push(privmsg("#b", "4"), Priority::Interactive, true),
+            Err(Error::QueueFull)
+        ));
+        assert_eq!(queue.cancel("#A"), 2);
+        assert_eq!(queue.cancel("#c"), 0);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.pop().unwrap().to_string(), "PRIVMSG #b 2\r\n");
+        assert!(queue.pop().is_none());
+
+        queue.close();
+        assert!(matches!(
+            queue.push(privmsg("#a", "5"), Priority::Interactive, false),
+            Err(Error::AsyncChannelClosed)
+        ));
+    }
+}
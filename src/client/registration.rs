@@ -0,0 +1,322 @@
+//! Waiting for the server to accept the client's registration.
+use std::{collections::HashMap, fmt};
+
+use tokio::{sync::oneshot, time::Duration};
+
+use crate::{
+    error,
+    proto::{Command, Message, Response},
+};
+
+/// The information that a server sends upon accepting our registration, as returned by
+/// [`Client::register`](crate::client::Client::register).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Welcome {
+    /// The nickname that we registered with.
+    pub nickname: String,
+    /// The welcome message from `RPL_WELCOME`.
+    pub message: String,
+    /// The name of the server, from `RPL_MYINFO`.
+    pub server: Option<String>,
+    /// The version of the server software, from `RPL_MYINFO`.
+    pub version: Option<String>,
+    /// The parameters advertised by the server in `RPL_ISUPPORT`. Parameters without a value have
+    /// an empty one.
+    pub isupport: HashMap<String, String>,
+    /// The messages the information was taken from, from `RPL_WELCOME` through `RPL_ISUPPORT`.
+    pub messages: Vec<Message>,
+}
+
+impl Welcome {
+    fn new(messages: Vec<Message>, isupport: HashMap<String, String>) -> Welcome {
+        let args = |response| {
+            messages.iter().find_map(|msg| match msg.command {
+                Command::Response(r, ref args) if r == response => Some(&args[..]),
+                _ => None,
+            })
+        };
+        let welcome = args(Response::RPL_WELCOME).unwrap_or_default();
+        let myinfo = args(Response::RPL_MYINFO).unwrap_or_default();
+        Welcome {
+            nickname: welcome.first().cloned().unwrap_or_default(),
+            message: welcome.get(1).cloned().unwrap_or_default(),
+            server: myinfo.get(1).cloned(),
+            version: myinfo.get(2).cloned(),
+            isupport,
+            messages,
+        }
+    }
+}
+
+/// How long a server can stay quiet in the middle of welcoming us before the welcome is taken to
+/// be complete, since some servers send nothing after `RPL_ISUPPORT` until they're spoken to.
+pub(crate) const WELCOME_SETTLE: Duration = Duration::from_secs(1);
+
+/// Makes the error that registration failed with, once for each of those waiting on it.
+struct Failure(Box<dyn Fn() -> error::Error + Send>);
+
+impl fmt::Debug for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&(self.0)(), f)
+    }
+}
+
+/// The progress of our registration with the server, along with anyone waiting for it.
+#[derive(Debug, Default)]
+pub(crate) struct Registration {
+    /// The welcome we got from the server, once registration has completed.
+    welcome: Option<Welcome>,
+    /// Why registration failed, if it did and hasn't completed since.
+    failure: Option<Failure>,
+    /// The welcome messages received so far.
+    messages: Vec<Message>,
+    /// Whether no message has been received since the server was last checked on.
+    quiet: bool,
+    waiters: Vec<oneshot::Sender<error::Result<Welcome>>>,
+}
+
+impl Registration {
+    /// Starts waiting for registration to complete. If it already has, or has already failed, the
+    /// receiver is ready.
+    pub(crate) fn wait(&mut self) -> oneshot::Receiver<error::Result<Welcome>> {
+        let (tx, rx) = oneshot::channel();
+        match (&self.welcome, &self.failure) {
+            (Some(welcome), _) => {
+                let _ = tx.send(Ok(welcome.clone()));
+            }
+            (None, Some(failure)) => {
+                let _ = tx.send(Err((failure.0)()));
+            }
+            (None, None) => self.waiters.push(tx),
+        }
+        rx
+    }
+
+    /// Follows registration through a received message. Once registration completes, `isupport`
+    /// is used to get the parameters that the server advertised.
+    pub(crate) fn handle<F>(&mut self, msg: &Message, isupport: F)
+    where
+        F: FnOnce() -> HashMap<String, String>,
+    {
+        if self.welcome.is_some() {
+            return;
+        }
+        self.quiet = false;
+
+        match msg.command {
+            Command::Response(Response::RPL_WELCOME, _) => self.messages.push(msg.clone()),
+            Command::Response(
+                Response::RPL_YOURHOST
+                | Response::RPL_CREATED
+                | Response::RPL_MYINFO
+                | Response::RPL_ISUPPORT,
+                _,
+            ) if !self.messages.is_empty() => self.messages.push(msg.clone()),
+            Command::Response(Response::ERR_PASSWDMISMATCH, _) => {
+                self.fail(|| error::Error::PasswordMismatch)
+            }
+            Command::Response(Response::ERR_YOUREBANNEDCREEP, ref args) => {
+                let reason = args.last().cloned().unwrap_or_default();
+                self.fail(move || error::Error::Banned {
+                    reason: reason.clone(),
+                })
+            }
+            Command::Response(
+                code @ (Response::ERR_SASLFAIL
+                | Response::ERR_SASLTOOLONG
+                | Response::ERR_SASLABORT),
+                ref args,
+            ) => {
+                let message = args.last().cloned().unwrap_or_default();
+                self.fail(move || error::Error::SaslFailed {
+                    code,
+                    message: message.clone(),
+                })
+            }
+            Command::ERROR(ref reason) => {
+                let reason = reason.clone();
+                self.fail(move || error::Error::ConnectionClosed {
+                    reason: reason.clone(),
+                })
+            }
+            // The welcome ends with the first numeric that isn't part of it, which is usually the
+            // start of the MOTD, or `ERR_NOMOTD` without one. Other messages, like notices, can be
+            // interleaved with it.
+            Command::Response(..) => self.finish(isupport),
+            _ => (),
+        }
+    }
+
+    /// Checks on a server that is in the middle of welcoming us, completing the welcome if the
+    /// server has stayed quiet since it was last checked on.
+    pub(crate) fn settle<F>(&mut self, isupport: F)
+    where
+        F: FnOnce() -> HashMap<String, String>,
+    {
+        if self.quiet {
+            self.finish(isupport);
+        }
+        self.quiet = true;
+    }
+
+    /// Completes the welcome in progress, if any, for everyone waiting on it.
+    pub(crate) fn finish<F>(&mut self, isupport: F)
+    where
+        F: FnOnce() -> HashMap<String, String>,
+    {
+        if self.welcome.is_some() || self.messages.is_empty() {
+            return;
+        }
+        let welcome = Welcome::new(std::mem::take(&mut self.messages), isupport());
+        for waiter in self.waiters.drain(..) {
+            let _ = waiter.send(Ok(welcome.clone()));
+        }
+        self.failure = None;
+        self.welcome = Some(welcome);
+    }
+
+    /// Fails registration for everyone waiting on it, and for anyone who starts waiting on it
+    /// later. Registration can still complete afterwards, e.g. if the server carries on without
+    /// SASL.
+    pub(crate) fn fail<F>(&mut self, error: F)
+    where
+        F: Fn() -> error::Error + Send + 'static,
+    {
+        if self.welcome.is_some() {
+            return;
+        }
+        for waiter in self.waiters.drain(..) {
+            let _ = waiter.send(Err(error()));
+        }
+        self.failure = Some(Failure(Box::new(error)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        client::{data::Config, test::test_config, Client},
+        error::Error,
+    };
+    use anyhow::Result;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn register() -> Result<()> {
+        let value = ":irc.test.net 001 test :Welcome to the Test Network test\r\n\
+                     :irc.test.net 002 test :Your host is irc.test.net\r\n\
+                     :irc.test.net NOTICE test :*** You are connected securely\r\n\
+                     :irc.test.net 003 test :This server was created today\r\n\
+                     :irc.test.net 004 test irc.test.net testd-1.0 iow bklmnopst\r\n\
+                     :irc.test.net 005 test NETWORK=Test MONITOR :are supported by this server\r\n\
+                     :irc.test.net 375 test :- irc.test.net Message of the day -\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            channels: vec![],
+            ..test_config()
+        })
+        .await?;
+        let stream = client.stream()?;
+        let (welcome, _) = tokio::join!(client.register(), stream.collect());
+        let welcome = welcome?;
+        assert_eq!(welcome.nickname, "test");
+        assert_eq!(welcome.message, "Welcome to the Test Network test");
+        assert_eq!(welcome.server.as_deref(), Some("irc.test.net"));
+        assert_eq!(welcome.version.as_deref(), Some("testd-1.0"));
+        assert_eq!(
+            welcome.isupport.get("NETWORK").map(|n| &n[..]),
+            Some("Test")
+        );
+        assert_eq!(welcome.messages.len(), 5);
+        assert_eq!(client.register().await?, welcome);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn register_failures() -> Result<()> {
+        let failures = [
+            ":irc.test.net 464 * :Password incorrect\r\n",
+            ":irc.test.net 465 * :You are banned from this server\r\n",
+            ":irc.test.net 904 * :SASL authentication failed\r\n",
+            "ERROR :Closing link: (test@host) [Too many connections]\r\n",
+            "",
+        ];
+        let mut errors = Vec::new();
+        for value in &failures {
+            let mut client = Client::from_config(Config {
+                mock_initial_value: Some((*value).to_owned()),
+                ..test_config()
+            })
+            .await?;
+            let stream = client.stream()?;
+            let (result, _) = tokio::join!(client.register(), stream.collect());
+            errors.push(result.unwrap_err());
+        }
+        assert!(matches!(errors[0], Error::PasswordMismatch));
+        assert!(matches!(&errors[1], Error::Banned { reason } if reason.contains("banned")));
+        assert!(matches!(errors[2], Error::SaslFailed { .. }));
+        assert!(
+            matches!(&errors[3], Error::ConnectionClosed { reason } if reason.contains("Too many"))
+        );
+        assert!(matches!(errors[4], Error::ConnectionClosed { .. }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn register_after_failure() -> Result<()> {
+        let mut client = Client::from_config(test_config()).await?;
+        client
+            .state
+            .handle_message(&":irc.test.net 464 * :Password incorrect".parse()?)?;
+        let stream = client.stream()?;
+        let (result, _) = tokio::join!(client.register(), stream.collect());
+        assert!(matches!(result, Err(Error::PasswordMismatch)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quiet_after_isupport() -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let (stream, mut server) = tokio::io::duplex(4096);
+        let mut client = Client::from_stream(
+            Config {
+                nickname: Some("test".to_owned()),
+                ..Config::default()
+            },
+            stream,
+        )?;
+        let stream = tokio::spawn(client.stream()?.collect());
+        server
+            .write_all(
+                b":irc.test.net 001 test :Welcome to the Test Network test\r\n\
+                  :irc.test.net 005 test NETWORK=Test :are supported by this server\r\n",
+            )
+            .await?;
+        let welcome = tokio::time::timeout(Duration::from_secs(5), client.register()).await??;
+        assert_eq!(welcome.messages.len(), 2);
+        assert_eq!(
+            welcome.isupport.get("NETWORK").map(|n| &n[..]),
+            Some("Test")
+        );
+
+        drop(server);
+        let _ = stream.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn register_without_usable_nick() -> Result<()> {
+        let value = ":irc.test.net 433 * test :Nickname is already in use.\r\n\
+                     :irc.test.net 433 * test2 :Nickname is already in use.\r\n";
+        let mut client = Client::from_config(Config {
+            mock_initial_value: Some(value.to_owned()),
+            ..test_config()
+        })
+        .await?;
+        let stream = client.stream()?;
+        let (result, _) = tokio::join!(client.register(), stream.collect());
+        assert!(matches!(result, Err(Error::NoUsableNick)));
+        Ok(())
+    }
+}
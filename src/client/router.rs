@@ -0,0 +1,719 @@
+//! A router for bot commands like `!repeat 3 hello`.
+//!
+//! A [`Router`] matches incoming `PRIVMSG`s against the [`Routes`](Route) registered with it,
+//! parses their arguments, checks that the sender is allowed to use the command and hasn't used it
+//! too recently, and finally calls the route's handler. A `help` command listing the other commands
+//! is provided automatically, unless a route named `help` is registered.
+//!
+//! Replies, including usage and permission errors, are sent to the message's
+//! [response target](crate::proto::Message::response_target), i.e. the channel the command was used
+//! in or the user who sent it privately.
+//!
+//! # Example
+//! ```no_run
+//! # use irc::client::prelude::*;
+//! use futures::prelude::*;
+//! use irc::client::router::{ArgKind, Permission, Route, Router};
+//! use std::time::Duration;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> irc::error::Result<()> {
+//! let mut client = Client::new("config.toml").await?;
+//! client.identify()?;
+//! let mut stream = client.stream()?;
+//!
+//! let mut router = Router::new("!");
+//! router.add(
+//!     Route::new("repeat", |_, call| {
+//!         for _ in 0..call.integer("count").unwrap_or(1).min(5) {
+//!             call.reply(call.text("message").unwrap_or_default())?;
+//!         }
+//!         Ok(())
+//!     })
+//!     .arg("count", ArgKind::Integer)
+//!     .arg("message", ArgKind::Text)
+//!     .help("Repeats a message up to five times.")
+//!     .permission(Permission::Owner)
+//!     .cooldown(Duration::from_secs(10)),
+//! );
+//!
+//! while let Some(message) = stream.next().await.transpose()? {
+//!     router.handle(&client, &message)?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use super::{data::AccessLevel, Client, Sender};
+use crate::{
+    error,
+    proto::{ChannelExt, Command, Message},
+};
+
+/// The handler called when a route's command is used.
+type Handler = Box<dyn FnMut(&Client, &Invocation) -> error::Result<()> + Send>;
+
+/// The kind of value expected for a command's argument.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgKind {
+    /// A single word.
+    Word,
+    /// A whole number.
+    Integer,
+    /// A channel name.
+    Channel,
+    /// The rest of the message. This must be the last argument.
+    Text,
+}
+
+/// The value of a parsed argument.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    /// A single word.
+    Word(String),
+    /// A whole number.
+    Integer(i64),
+    /// A channel name.
+    Channel(String),
+    /// The rest of the message.
+    Text(String),
+}
+
+impl Value {
+    /// Gets the value as a string, unless it's an integer.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Word(s) | Value::Channel(s) | Value::Text(s) => Some(s),
+            Value::Integer(_) => None,
+        }
+    }
+}
+
+/// A requirement that a user must meet to use a command. A command with several permissions can
+/// be used by anyone meeting any one of them, and a command without permissions by anyone at all.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Permission {
+    /// The user is one of the [owners](crate::client::data::Config::owners) in the configuration.
+    Owner,
+    /// The command was used in a channel where the user has at least this access level. This
+    /// requires the `channel-lists` feature.
+    Access(AccessLevel),
+    /// The user is logged in to this services account, as reported by an `account` tag or by
+    /// account tracking (which requires the `channel-lists` feature).
+    Account(String),
+}
+
+/// An argument in a route's specification.
+#[derive(Debug)]
+struct Arg {
+    name: String,
+    kind: ArgKind,
+    optional: bool,
+}
+
+/// A command that can be registered with a [`Router`].
+pub struct Route {
+    name: String,
+    args: Vec<Arg>,
+    help: Option<String>,
+    permissions: Vec<Permission>,
+    cooldown: Option<Duration>,
+    handler: Handler,
+}
+
+impl fmt::Debug for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Route")
+            .field("name", &self.name)
+            .field("args", &self.args)
+            .field("help", &self.help)
+            .field("permissions", &self.permissions)
+            .field("cooldown", &self.cooldown)
+            .finish()
+    }
+}
+
+impl Route {
+    /// Creates a route for the specified command, which calls `handler` when the command is used.
+    pub fn new<F>(name: &str, handler: F) -> Route
+    where
+        F: FnMut(&Client, &Invocation) -> error::Result<()> + Send + 'static,
+    {
+        Route {
+            name: name.to_owned(),
+            args: Vec::new(),
+            help: None,
+            permissions: Vec::new(),
+            cooldown: None,
+            handler: Box::new(handler),
+        }
+    }
+
+    /// Adds a required argument to the command.
+    ///
+    /// # Panics
+    /// Panics if the command already has an argument of kind [`ArgKind::Text`].
+    pub fn arg(self, name: &str, kind: ArgKind) -> Route {
+        self.push_arg(name, kind, false)
+    }
+
+    /// Adds an optional argument to the command. Optional arguments should come after all of the
+    /// command's required arguments.
+    ///
+    /// # Panics
+    /// Panics if the command already has an argument of kind [`ArgKind::Text`].
+    pub fn optional_arg(self, name: &str, kind: ArgKind) -> Route {
+        self.push_arg(name, kind, true)
+    }
+
+    /// Sets the description of the command shown by `help`.
+    pub fn help(mut self, help: &str) -> Route {
+        self.help = Some(help.to_owned());
+        self
+    }
+
+    /// Adds a permission that allows users to use the command.
+    pub fn permission(mut self, permission: Permission) -> Route {
+        self.permissions.push(permission);
+        self
+    }
+
+    /// Sets how long each user must wait before using the command again.
+    pub fn cooldown(mut self, cooldown: Duration) -> Route {
+        self.cooldown = Some(cooldown);
+        self
+    }
+
+    fn push_arg(mut self, name: &str, kind: ArgKind, optional: bool) -> Route {
+        assert!(
+            self.args
+                .last()
+                .map_or(true, |arg| arg.kind != ArgKind::Text),
+            "a text argument must be the last argument of a command"
+        );
+        self.args.push(Arg {
+            name: name.to_owned(),
+            kind,
+            optional,
+        });
+        self
+    }
+
+    /// Gets the usage of the command, e.g. `!repeat <count> <message...>`.
+    fn usage(&self, prefix: &str) -> String {
+        let mut usage = format!("{}{}", prefix, self.name);
+        for arg in &self.args {
+            let rest = if arg.kind == ArgKind::Text { "..." } else { "" };
+            if arg.optional {
+                usage.push_str(&format!(" [{}{}]", arg.name, rest));
+            } else {
+                usage.push_str(&format!(" <{}{}>", arg.name, rest));
+            }
+        }
+        usage
+    }
+
+    /// Parses the arguments of the command, or returns `None` if they don't match its spec.
+    fn parse_args(&self, mut rest: &str) -> Option<Vec<(String, Value)>> {
+        let mut values = Vec::new();
+        for arg in &self.args {
+            rest = rest.trim_start();
+            if rest.is_empty() {
+                if arg.optional {
+                    break;
+                }
+                return None;
+            }
+
+            let value = if arg.kind == ArgKind::Text {
+                let text = rest.trim_end();
+                rest = "";
+                Value::Text(text.to_owned())
+            } else {
+                let (word, remainder) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                rest = remainder;
+                match arg.kind {
+                    ArgKind::Word => Value::Word(word.to_owned()),
+                    ArgKind::Integer => Value::Integer(word.parse().ok()?),
+                    ArgKind::Channel if word.is_channel_name() => Value::Channel(word.to_owned()),
+                    _ => return None,
+                }
+            };
+            values.push((arg.name.clone(), value));
+        }
+
+        if rest.trim().is_empty() {
+            Some(values)
+        } else {
+            None
+        }
+    }
+}
+
+/// A use of a command, passed to the command's handler.
+#[derive(Debug)]
+pub struct Invocation<'a> {
+    message: &'a Message,
+    command: &'a str,
+    sender: &'a str,
+    target: &'a str,
+    args: Vec<(String, Value)>,
+    tx: Sender,
+}
+
+impl<'a> Invocation<'a> {
+    /// Gets the message that used the command.
+    pub fn message(&self) -> &Message {
+        self.message
+    }
+
+    /// Gets the name of the command that was used.
+    pub fn command(&self) -> &str {
+        self.command
+    }
+
+    /// Gets the nickname of the user who used the command.
+    pub fn sender(&self) -> &str {
+        self.sender
+    }
+
+    /// Gets the target that replies are sent to, i.e. the channel the command was used in, or the
+    /// user who used it if it was sent privately.
+    pub fn target(&self) -> &str {
+        self.target
+    }
+
+    /// Gets the value of the specified argument, if it was given.
+    pub fn arg(&self, name: &str) -> Option<&Value> {
+        self.args
+            .iter()
+            .find(|(arg, _)| arg == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Gets the value of the specified word, channel or text argument, if it was given.
+    pub fn text(&self, name: &str) -> Option<&str> {
+        self.arg(name).and_then(Value::as_str)
+    }
+
+    /// Gets the value of the specified integer argument, if it was given.
+    pub fn integer(&self, name: &str) -> Option<i64> {
+        match self.arg(name) {
+            Some(Value::Integer(n)) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Sends a reply to the [target](Invocation::target) of the command.
+    pub fn reply<S: fmt::Display>(&self, text: S) -> error::Result<()> {
+        self.tx.send_privmsg(self.target, text)
+    }
+}
+
+/// A set of commands, dispatched from incoming messages.
+#[derive(Debug)]
+pub struct Router {
+    prefix: String,
+    routes: Vec<Route>,
+    /// When each command with a cooldown was last used by each user, by lowercase command name
+    /// and nickname. Entries are dropped once their cooldown is over.
+    last_used: HashMap<(String, String), Instant>,
+}
+
+impl Router {
+    /// Creates a router for commands starting with the specified prefix, e.g. `!`.
+    pub fn new(prefix: &str) -> Router {
+        Router {
+            prefix: prefix.to_owned(),
+            routes: Vec::new(),
+            last_used: HashMap::new(),
+        }
+    }
+
+    /// Registers a command with the router, replacing any command with the same name.
+    pub fn add(&mut self, route: Route) {
+        self.routes
+            .retain(|r| !r.name.eq_ignore_ascii_case(&route.name));
+        self.routes.push(route);
+    }
+
+    /// Handles a message, calling the handler of the command it uses if there is one. Returns
+    /// whether the message used one of the router's commands, including `help`. Echoes of our own
+    /// messages are ignored, so that a bot never runs its own commands.
+    pub fn handle(&mut self, client: &Client, message: &Message) -> error::Result<bool> {
+        let text = match message.command {
+            Command::PRIVMSG(_, ref text) if !client.is_echo(message) => text,
+            _ => return Ok(false),
+        };
+        let (sender, target) = match (message.source_nickname(), message.response_target()) {
+            (Some(sender), Some(target)) => (sender, target),
+            _ => return Ok(false),
+        };
+        let (command, rest) = match text.strip_prefix(&self.prefix[..]) {
+            Some(line) => line.split_once(' ').unwrap_or((line, "")),
+            None => return Ok(false),
+        };
+
+        let index = match self
+            .routes
+            .iter()
+            .position(|route| route.name.eq_ignore_ascii_case(command))
+        {
+            Some(index) => index,
+            None if command.eq_ignore_ascii_case("help") => {
+                self.help(client, target, rest.trim())?;
+                return Ok(true);
+            }
+            None => return Ok(false),
+        };
+
+        let route = &self.routes[index];
+        if !self.is_permitted(client, message, sender, target, route) {
+            client.send_privmsg(
+                target,
+                format!(
+                    "{}: you don't have permission to use {}{}.",
+                    sender, self.prefix, route.name
+                ),
+            )?;
+            return Ok(true);
+        }
+
+        // Commands used during their cooldown are ignored, so as not to add to any flooding.
+        let key = (route.name.to_ascii_lowercase(), sender.to_ascii_lowercase());
+        let now = Instant::now();
+        if let (Some(cooldown), Some(last_used)) = (route.cooldown, self.last_used.get(&key)) {
+            if now.duration_since(*last_used) < cooldown {
+                return Ok(true);
+            }
+        }
+
+        let args = match route.parse_args(rest) {
+            Some(args) => args,
+            None => {
+                client.send_privmsg(target, format!("Usage: {}", route.usage(&self.prefix)))?;
+                return Ok(true);
+            }
+        };
+        if route.cooldown.is_some() {
+            let routes = &self.routes;
+            self.last_used.retain(|(name, _), last_used| {
+                routes
+                    .iter()
+                    .find(|route| route.name.eq_ignore_ascii_case(name))
+                    .and_then(|route| route.cooldown)
+                    .is_some_and(|cooldown| now.duration_since(*last_used) < cooldown)
+            });
+            self.last_used.insert(key, now);
+        }
+
+        let invocation = Invocation {
+            message,
+            command,
+            sender,
+            target,
+            args,
+            tx: client.sender(),
+        };
+        (self.routes[index].handler)(client, &invocation)?;
+        Ok(true)
+    }
+
+    /// Replies to `help`, either listing the commands or describing the specified one.
+    fn help(&self, client: &Client, target: &str, command: &str) -> error::Result<()> {
+        if command.is_empty() {
+            let commands: Vec<_> = self
+                .routes
+                .iter()
+                .map(|route| format!("{}{}", self.prefix, route.name))
+                .collect();
+            return client.send_privmsg(
+                target,
+                format!(
+                    "Commands: {}. Use {}help <command> for details.",
+                    commands.join(", "),
+                    self.prefix
+                ),
+            );
+        }
+
+        let command = command.strip_prefix(&self.prefix[..]).unwrap_or(command);
+        match self
+            .routes
+            .iter()
+            .find(|route| route.name.eq_ignore_ascii_case(command))
+        {
+            Some(route) => {
+                let usage = route.usage(&self.prefix);
+                match route.help {
+                    Some(ref help) => client.send_privmsg(target, format!("{} - {}", usage, help)),
+                    None => client.send_privmsg(target, usage),
+                }
+            }
+            None => client.send_privmsg(target, format!("Unknown command: {}", command)),
+        }
+    }
+
+    /// Checks whether the sender of a message is allowed to use a route.
+    fn is_permitted(
+        &self,
+        client: &Client,
+        message: &Message,
+        sender: &str,
+        target: &str,
+        route: &Route,
+    ) -> bool {
+        if route.permissions.is_empty() {
+            return true;
+        }
+
+        route.permissions.iter().any(|permission| match permission {
+            Permission::Owner => client.config().is_owner(sender),
+            Permission::Access(level) => {
+                target.is_channel_name()
+                    && client
+                        .list_users(target)
+                        .unwrap_or_default()
+                        .iter()
+                        .find(|user| user.get_nickname().eq_ignore_ascii_case(sender))
+                        .is_some_and(|user| user.highest_access_level() >= *level)
+            }
+            Permission::Account(account) => message
+                .account()
+                .map(|a| a.to_owned())
+                .or_else(|| {
+                    client
+                        .user(sender)
+                        .and_then(|user| user.account().map(|a| a.to_owned()))
+                })
+                .is_some_and(|a| a.eq_ignore_ascii_case(account)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{ArgKind, Permission, Route, Router};
+    use crate::{
+        client::{
+            data::Config,
+            test::{get_client_value, test_config},
+            Client,
+        },
+        proto::Message,
+    };
+    use anyhow::Result;
+
+    fn repeat() -> Route {
+        Route::new("repeat", |_, call| {
+            for _ in 0..call.integer("count").unwrap() {
+                call.reply(call.text("message").unwrap())?;
+            }
+            Ok(())
+        })
+        .arg("count", ArgKind::Integer)
+        .arg("message", ArgKind::Text)
+        .help("Repeats a message.")
+    }
+
+    async fn route(router: &mut Router, lines: &[&str]) -> Result<String> {
+        let mut client = Client::from_config(test_config()).await?;
+        for line in lines {
+            router.handle(&client, &line.parse::<Message>()?)?;
+        }
+        client.stream()?.collect().await?;
+        Ok(get_client_value(client))
+    }
+
+    #[tokio::test]
+    async fn arguments_and_usage() -> Result<()> {
+        let mut router = Router::new("!");
+        router.add(repeat());
+        let sent = route(
+            &mut router,
+            &[
+                ":alice!a@host PRIVMSG #test :!repeat 2 hello world",
+                ":alice!a@host PRIVMSG test :!REPEAT 1 hi",
+                ":alice!a@host PRIVMSG #test :!repeat many hi",
+                ":alice!a@host PRIVMSG #test :!unknown",
+                ":alice!a@host PRIVMSG #test :repeat 1 hi",
+            ],
+        )
+        .await?;
+        assert_eq!(
+            sent,
+            "PRIVMSG #test :hello world\r\nPRIVMSG #test :hello world\r\n\
+             PRIVMSG alice hi\r\nPRIVMSG #test :Usage: !repeat <count> <message...>\r\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn help() -> Result<()> {
+        let mut router = Router::new("!");
+        router.add(repeat());
+        router.add(
+            Route::new("ping", |_, call| call.reply("pong")).optional_arg("who", ArgKind::Word),
+        );
+        let sent = route(
+            &mut router,
+            &[
+                ":alice!a@host PRIVMSG #test :!help",
+                ":alice!a@host PRIVMSG #test :!help repeat",
+                ":alice!a@host PRIVMSG #test :!help !ping",
+            ],
+        )
+        .await?;
+        assert_eq!(
+            sent,
+            "PRIVMSG #test :Commands: !repeat, !ping. Use !help <command> for details.\r\n\
+             PRIVMSG #test :!repeat <count> <message...> - Repeats a message.\r\n\
+             PRIVMSG #test :!ping [who]\r\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn permissions() -> Result<()> {
+        let mut router = Router::new("!");
+        router.add(
+            Route::new("quit", |_, call| call.reply("bye"))
+                .permission(Permission::Owner)
+                .permission(Permission::Account("admin".to_owned())),
+        );
+        let sent = route(
+            &mut router,
+            &[
+                ":test!a@host PRIVMSG #test :!quit",
+                ":alice!a@host PRIVMSG #test :!quit",
+                "@account=Admin :bob!b@host PRIVMSG #test :!quit",
+            ],
+        )
+        .await?;
+        assert_eq!(
+            sent,
+            "PRIVMSG #test bye\r\n\
+             PRIVMSG #test :alice: you don't have permission to use !quit.\r\n\
+             PRIVMSG #test bye\r\n"
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "channel-lists")]
+    #[tokio::test]
+    async fn access_permission() -> Result<()> {
+        use crate::client::data::AccessLevel;
+
+        let mut client = Client::from_config(test_config()).await?;
+        for line in &[
+            ":test!test@test JOIN #test",
+            ":irc.test.net 353 test = #test :test @alice +bob",
+        ] {
+            client.state.handle_message(&line.parse()?)?;
+        }
+
+        let mut router = Router::new("!");
+        router.add(
+            Route::new("kick", |_, call| call.reply("kicking"))
+                .permission(Permission::Access(AccessLevel::Oper)),
+        );
+        for line in &[
+            ":alice!a@host PRIVMSG #test :!kick",
+            ":bob!b@host PRIVMSG #test :!kick",
+            ":alice!a@host PRIVMSG test :!kick",
+        ] {
+            router.handle(&client, &line.parse::<Message>()?)?;
+        }
+        client.stream()?.collect().await?;
+        assert_eq!(
+            get_client_value(client),
+            "PRIVMSG #test kicking\r\n\
+             PRIVMSG #test :bob: you don't have permission to use !kick.\r\n\
+             PRIVMSG alice :alice: you don't have permission to use !kick.\r\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cooldown() -> Result<()> {
+        let mut router = Router::new("!");
+        router.add(repeat().cooldown(Duration::from_secs(60)));
+        let sent = route(
+            &mut router,
+            &[
+                ":alice!a@host PRIVMSG #test :!repeat 1 one",
+                ":ALICE!a@host PRIVMSG #test :!repeat 1 two",
+                ":bob!b@host PRIVMSG #test :!repeat 1 three",
+            ],
+        )
+        .await?;
+        assert_eq!(sent, "PRIVMSG #test one\r\nPRIVMSG #test three\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cooldown_follows_command() -> Result<()> {
+        let ping = || Route::new("ping", |_, call| call.reply("pong")).cooldown(Duration::ZERO);
+        let mut router = Router::new("!");
+        router.add(ping());
+        router.add(repeat().cooldown(Duration::from_secs(60)));
+        let mut client = Client::from_config(Config {
+            channels: vec![],
+            ..test_config()
+        })
+        .await?;
+        let handle = |router: &mut Router, line: &str| -> Result<()> {
+            router.handle(&client, &line.parse::<Message>()?)?;
+            Ok(())
+        };
+        handle(&mut router, ":alice!a@host PRIVMSG #test :!repeat 1 one")?;
+        // Replacing the first command moves the other one up, but not its cooldowns.
+        router.add(ping());
+        handle(&mut router, ":alice!a@host PRIVMSG #test :!ping")?;
+        handle(&mut router, ":alice!a@host PRIVMSG #test :!repeat 1 two")?;
+        handle(&mut router, ":bob!b@host PRIVMSG #test :!ping")?;
+        client.stream()?.collect().await?;
+        assert_eq!(
+            get_client_value(client),
+            "PRIVMSG #test one\r\nPRIVMSG #test pong\r\nPRIVMSG #test pong\r\n"
+        );
+
+        // Only the cooldowns still running are kept, besides the one just started.
+        let mut keys: Vec<_> = router.last_used.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                ("ping".to_owned(), "bob".to_owned()),
+                ("repeat".to_owned(), "alice".to_owned())
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ignores_echoes() -> Result<()> {
+        let mut router = Router::new("!");
+        router.add(repeat());
+        let mut client = Client::from_config(test_config()).await?;
+        client
+            .state
+            .handle_message(&":irc.test.net CAP * ACK :echo-message".parse()?)?;
+        for line in &[
+            ":test!t@host PRIVMSG #test :!repeat 1 loop",
+            ":alice!a@host PRIVMSG #test :!repeat 1 once",
+        ] {
+            router.handle(&client, &line.parse::<Message>()?)?;
+        }
+        client.stream()?.collect().await?;
+        assert_eq!(get_client_value(client), "PRIVMSG #test once\r\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+//! Quitting the server gracefully.
+use tokio::sync::oneshot;
+
+/// The progress of quitting the server, shared between the client's senders and its stream.
+#[derive(Debug, Default)]
+pub(crate) struct Shutdown {
+    /// Whether a `QUIT` has been sent through [`Sender::quit`](crate::client::Sender::quit).
+    quitting: bool,
+    /// Whether the connection has ended.
+    closed: bool,
+    waiters: Vec<oneshot::Sender<()>>,
+}
+
+impl Shutdown {
+    /// Starts quitting, returning whether a `QUIT` still needs to be sent, along with a receiver
+    /// that is ready once the connection has ended. If it already has, the receiver is ready.
+    pub(crate) fn quit(&mut self) -> (bool, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        let send = !self.quitting && !self.closed;
+        self.quitting = true;
+        if self.closed {
+            let _ = tx.send(());
+        } else {
+            self.waiters.push(tx);
+        }
+        (send, rx)
+    }
+
+    /// Checks whether we are quitting the server.
+    pub(crate) fn is_quitting(&self) -> bool {
+        self.quitting
+    }
+
+    /// Records that the connection has ended, notifying everyone waiting to quit.
+    pub(crate) fn close(&mut self) {
+        self.closed = true;
+        for waiter in self.waiters.drain(..) {
+            let _ = waiter.send(());
+        }
+    }
+}
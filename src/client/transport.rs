@@ -2,6 +2,7 @@
 //! automatic PING replies, automatic sending of PINGs, and message rate-limiting. This can be used
 //! as the basis for implementing a more full IRC client.
 use std::{
+    collections::VecDeque,
     pin::Pin,
     sync::{Arc, RwLock, RwLockReadGuard},
     task::{Context, Poll},
@@ -11,19 +12,55 @@ use std::{
 use chrono::prelude::*;
 use futures_util::{future::Future, ready, sink::Sink, stream::Stream};
 use pin_project::pin_project;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{mpsc::UnboundedSender, watch};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    time::{self, Interval, Sleep},
+    time::{self, Instant, Interval, Sleep},
 };
 use tokio_util::codec::Framed;
 
 use crate::{
-    client::data::Config,
+    client::data::{Config, InvalidMessagePolicy},
     error,
-    proto::{Command, IrcCodec, Message, Response},
+    proto::{error::MessageParseError, Command, IrcCodec, Message, Response},
 };
 
+/// The number of unanswered pings to remember the tokens of.
+const MAX_OUTSTANDING_PINGS: usize = 8;
+
+/// Measurements of the round trip time to the server, taken from the pings that the transport
+/// sends to keep the connection alive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Lag {
+    /// The round trip time of the latest answered ping.
+    pub last: Option<Duration>,
+    /// An average of the recent round trip times, which follows changes in lag without jumping
+    /// around with every ping.
+    pub smoothed: Option<Duration>,
+    /// The shortest round trip time so far.
+    pub min: Option<Duration>,
+    /// The longest round trip time so far.
+    pub max: Option<Duration>,
+    /// Whether the lag is over the configured [warning threshold](Config::lag_warning), either
+    /// because the latest ping took too long or because the server still hasn't answered it.
+    pub lagging: bool,
+}
+
+impl Lag {
+    /// Records the round trip time of a ping.
+    fn record(&mut self, rtt: Duration, threshold: Option<Duration>) {
+        self.last = Some(rtt);
+        // Like TCP's smoothed round trip time, each measurement contributes an eighth.
+        self.smoothed = Some(
+            self.smoothed
+                .map_or(rtt, |smoothed| (smoothed * 7 + rtt) / 8),
+        );
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.max = Some(self.max.map_or(rtt, |max| max.max(rtt)));
+        self.lagging = threshold.is_some_and(|threshold| rtt > threshold);
+    }
+}
+
 /// Pinger-based futures helper.
 #[pin_project]
 struct Pinger {
@@ -38,6 +75,15 @@ struct Pinger {
     /// The interval at which to send pings.
     #[pin]
     ping_interval: Interval,
+    /// The tokens of the pings waiting on a response, oldest first, along with when they were sent.
+    outstanding: VecDeque<(String, Instant)>,
+    /// The latest lag measurements, which also notifies watchers when they change.
+    lag: watch::Sender<Lag>,
+    /// The amount of lag to warn about.
+    lag_warning: Option<Duration>,
+    /// When to warn about the server not answering the oldest outstanding ping.
+    #[pin]
+    lag_deadline: Option<Sleep>,
 }
 
 impl Pinger {
@@ -52,6 +98,12 @@ impl Pinger {
             ping_timeout,
             ping_deadline: None,
             ping_interval: time::interval(ping_time),
+            outstanding: VecDeque::new(),
+            lag: watch::channel(Lag::default()).0,
+            lag_warning: config
+                .lag_warning()
+                .map(|secs| Duration::from_secs(u64::from(secs))),
+            lag_deadline: None,
         }
     }
 
@@ -67,11 +119,22 @@ impl Pinger {
             Command::PING(ref data, _) => {
                 self.send_pong(data)?;
             }
-            // Check `PONG` responses from the server. If it matches, we will update the
-            // last instant that the pong was received. This will prevent timeout.
-            Command::PONG(_, None) | Command::PONG(_, Some(_)) => {
+            // Check `PONG` responses from the server. Any of them shows that the connection is
+            // alive, which prevents timeout, while those answering our pings also measure the lag.
+            Command::PONG(ref server, ref token) => {
                 log::trace!("Received PONG");
-                self.project().ping_deadline.set(None);
+                let mut this = self.project();
+                this.ping_deadline.set(None);
+                this.lag_deadline.set(None);
+
+                let token = token.as_ref().unwrap_or(server);
+                if let Some(n) = this.outstanding.iter().position(|(t, _)| t == token) {
+                    let sent = this.outstanding[n].1;
+                    this.outstanding.drain(..=n);
+                    let threshold = *this.lag_warning;
+                    this.lag
+                        .send_modify(|lag| lag.record(sent.elapsed(), threshold));
+                }
             }
             _ => (),
         }
@@ -92,19 +155,31 @@ impl Pinger {
         log::trace!("Sending PING");
 
         // Creates new ping data using the local timestamp.
-        let data = format!("{}", Local::now().timestamp());
+        let data = format!("{}", Local::now().timestamp_millis());
 
         let mut this = self.project();
 
-        this.tx.send(Command::PING(data, None).into())?;
+        this.tx.send(Command::PING(data.clone(), None).into())?;
+        if this.outstanding.len() == MAX_OUTSTANDING_PINGS {
+            this.outstanding.pop_front();
+        }
+        this.outstanding.push_back((data, Instant::now()));
 
         if this.ping_deadline.is_none() {
             let ping_deadline = time::sleep(*this.ping_timeout);
             this.ping_deadline.set(Some(ping_deadline));
         }
+        if let (true, Some(lag_warning)) = (this.lag_deadline.is_none(), *this.lag_warning) {
+            this.lag_deadline.set(Some(time::sleep(lag_warning)));
+        }
 
         Ok(())
     }
+
+    /// Gets a receiver that is notified whenever the lag measurements change.
+    fn lag(&self) -> watch::Receiver<Lag> {
+        self.lag.subscribe()
+    }
 }
 
 impl Future for Pinger {
@@ -118,6 +193,14 @@ impl Future for Pinger {
             }
         }
 
+        if let Some(lag_deadline) = self.as_mut().project().lag_deadline.as_pin_mut() {
+            if lag_deadline.poll(cx).is_ready() {
+                log::warn!("The server has not answered a PING in time; the connection is lagging");
+                self.as_mut().project().lag_deadline.set(None);
+                self.lag.send_modify(|lag| lag.lagging = true);
+            }
+        }
+
         if self
             .as_mut()
             .project()
@@ -144,6 +227,19 @@ pub struct Transport<T> {
     /// Helper for handle pinging.
     #[pin]
     pinger: Option<Pinger>,
+    /// How to handle messages that the codec could not parse.
+    invalid_message_policy: InvalidMessagePolicy,
+}
+
+/// A message received by a transport.
+#[derive(Debug)]
+pub(crate) struct Received {
+    pub(crate) message: Message,
+    /// Why the message could not be parsed, if it is the [`Command::Raw`] fallback for an invalid
+    /// line.
+    pub(crate) parse_error: Option<MessageParseError>,
+    /// The line that the message was decoded from.
+    pub(crate) line: Option<String>,
 }
 
 impl<T> Transport<T>
@@ -158,7 +254,11 @@ where
     ) -> Transport<T> {
         let pinger = Some(Pinger::new(tx, config));
 
-        Transport { inner, pinger }
+        Transport {
+            inner,
+            pinger,
+            invalid_message_policy: config.invalid_message_policy(),
+        }
     }
 
     /// Gets the inner stream underlying the `Transport`.
@@ -167,13 +267,26 @@ where
     }
 }
 
-impl<T> Stream for Transport<T>
+impl<T> Transport<T> {
+    /// Gets a receiver that is notified whenever the lag measured from the transport's pings
+    /// changes.
+    pub fn lag(&self) -> watch::Receiver<Lag> {
+        match self.pinger {
+            Some(ref pinger) => pinger.lag(),
+            None => watch::channel(Lag::default()).1,
+        }
+    }
+}
+
+impl<T> Transport<T>
 where
     T: Unpin + AsyncRead + AsyncWrite,
 {
-    type Item = Result<Message, error::Error>;
-
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    /// Polls for the next message, along with why it could not be parsed if it is a fallback.
+    pub(crate) fn poll_received(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<error::Result<Received>>> {
         if let Some(pinger) = self.as_mut().project().pinger.as_pin_mut() {
             match pinger.poll(cx) {
                 Poll::Ready(result) => result?,
@@ -181,18 +294,48 @@ where
             }
         }
 
-        let result = ready!(self.as_mut().project().inner.poll_next(cx));
+        loop {
+            let result = ready!(self.as_mut().project().inner.poll_next(cx));
+
+            let message = match result {
+                None => return Poll::Ready(None),
+                Some(message) => message?,
+            };
+
+            let parse_error = self.as_mut().project().inner.codec_mut().take_parse_error();
+            let line = self.as_mut().project().inner.codec_mut().take_line();
+            if let Some(ref cause) = parse_error {
+                match self.invalid_message_policy {
+                    InvalidMessagePolicy::Skip => {
+                        log::warn!("Skipping invalid message ({}): {}", cause, message);
+                        continue;
+                    }
+                    _ => log::debug!("Received invalid message ({}): {}", cause, message),
+                }
+            }
 
-        let message = match result {
-            None => return Poll::Ready(None),
-            Some(message) => message?,
-        };
+            if let Some(pinger) = self.as_mut().project().pinger.as_pin_mut() {
+                pinger.handle_message(&message)?;
+            }
 
-        if let Some(pinger) = self.as_mut().project().pinger.as_pin_mut() {
-            pinger.handle_message(&message)?;
+            return Poll::Ready(Some(Ok(Received {
+                message,
+                parse_error,
+                line,
+            })));
         }
+    }
+}
 
-        Poll::Ready(Some(Ok(message)))
+impl<T> Stream for Transport<T>
+where
+    T: Unpin + AsyncRead + AsyncWrite,
+{
+    type Item = Result<Message, error::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_received(cx)
+            .map(|received| received.map(|received| received.map(|received| received.message)))
     }
 }
 
@@ -233,12 +376,12 @@ pub struct LogView {
 
 impl LogView {
     /// Gets a read guard for all the messages sent on the transport.
-    pub fn sent(&self) -> error::Result<RwLockReadGuard<Vec<Message>>> {
+    pub fn sent(&self) -> error::Result<RwLockReadGuard<'_, Vec<Message>>> {
         self.sent.read().map_err(|_| error::Error::PoisonedLog)
     }
 
     /// Gets a read guard for all the messages received on the transport.
-    pub fn received(&self) -> error::Result<RwLockReadGuard<Vec<Message>>> {
+    pub fn received(&self) -> error::Result<RwLockReadGuard<'_, Vec<Message>>> {
         self.received.read().map_err(|_| error::Error::PoisonedLog)
     }
 }
@@ -271,34 +414,54 @@ where
     pub fn view(&self) -> LogView {
         self.view.clone()
     }
+
+    /// Gets a receiver that is notified whenever the lag measured from the transport's pings
+    /// changes.
+    pub fn lag(&self) -> watch::Receiver<Lag> {
+        self.inner.lag()
+    }
 }
 
-impl<T> Stream for Logged<T>
+impl<T> Logged<T>
 where
     T: Unpin + AsyncRead + AsyncWrite,
 {
-    type Item = Result<Message, error::Error>;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    /// Polls for the next message, along with why it could not be parsed if it is a fallback.
+    pub(crate) fn poll_received(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<error::Result<Received>>> {
         let this = self.project();
 
-        match ready!(this.inner.poll_next(cx)) {
-            Some(msg) => {
-                let msg = msg?;
+        match ready!(this.inner.poll_received(cx)) {
+            Some(received) => {
+                let received = received?;
 
                 this.view
                     .received
                     .write()
                     .map_err(|_| error::Error::PoisonedLog)?
-                    .push(msg.clone());
+                    .push(received.message.clone());
 
-                Poll::Ready(Some(Ok(msg)))
+                Poll::Ready(Some(Ok(received)))
             }
             None => Poll::Ready(None),
         }
     }
 }
 
+impl<T> Stream for Logged<T>
+where
+    T: Unpin + AsyncRead + AsyncWrite,
+{
+    type Item = Result<Message, error::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_received(cx)
+            .map(|received| received.map(|received| received.map(|received| received.message)))
+    }
+}
+
 impl<T> Sink<Message> for Logged<T>
 where
     T: Unpin + AsyncRead + AsyncWrite,
@@ -331,3 +494,87 @@ where
         self.project().inner.poll_flush(cx)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{pin::Pin, time::Duration};
+
+    use futures::poll;
+    use tokio::sync::mpsc;
+
+    use super::Pinger;
+    use crate::{
+        client::{data::Config, test::test_config},
+        proto::{Command, Message},
+    };
+    use anyhow::Result;
+
+    fn pong(server: &str, token: Option<&str>) -> Message {
+        Command::PONG(server.to_owned(), token.map(|t| t.to_owned())).into()
+    }
+
+    #[tokio::test]
+    async fn measures_lag() -> Result<()> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut pinger = Box::pin(Pinger::new(tx, &test_config()));
+        let lag = pinger.lag();
+
+        let mut tokens = Vec::new();
+        for _ in 0..2 {
+            pinger.as_mut().send_ping()?;
+            match rx.recv().await.map(|m| m.command) {
+                Some(Command::PING(token, None)) => tokens.push(token),
+                other => panic!("unexpected message: {:?}", other),
+            }
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+
+        pinger
+            .as_mut()
+            .handle_message(&pong("irc.test.net", Some("unknown")))?;
+        assert_eq!(lag.borrow().last, None);
+
+        // Answering the later ping also accounts for the earlier one.
+        pinger
+            .as_mut()
+            .handle_message(&pong("irc.test.net", Some(&tokens[1])))?;
+        let measured = *lag.borrow();
+        assert!(measured.last.is_some());
+        assert_eq!(measured.min, measured.last);
+        assert_eq!(measured.max, measured.last);
+        assert_eq!(measured.smoothed, measured.last);
+        assert!(!measured.lagging);
+        assert!(pinger.outstanding.is_empty());
+
+        // Servers without a prefix answer with the token as the only argument.
+        pinger.as_mut().send_ping()?;
+        if let Some(Command::PING(token, None)) = rx.recv().await.map(|m| m.command) {
+            pinger.as_mut().handle_message(&pong(&token, None))?;
+        }
+        assert!(pinger.outstanding.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn warns_about_lag() -> Result<()> {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let config = Config {
+            lag_warning: Some(0),
+            ..test_config()
+        };
+        let mut pinger: Pin<Box<Pinger>> = Box::pin(Pinger::new(tx, &config));
+        let mut lag = pinger.lag();
+
+        pinger.as_mut().send_ping()?;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(poll!(pinger.as_mut()).is_pending());
+        assert!(lag.has_changed()?);
+        assert!(lag.borrow_and_update().lagging);
+
+        pinger
+            .as_mut()
+            .handle_message(&pong("irc.test.net", None))?;
+        assert!(pinger.lag_deadline.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+//! IRC over WebSocket, as specified by IRCv3.
+//!
+//! Each WebSocket message carries a single IRC line without its trailing CRLF. [`WebSocket`]
+//! turns a WebSocket connection back into a stream of bytes, so that the rest of the transport
+//! (and its codec) work with it just like with TCP.
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::{ready, sink::Sink, stream::Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    client_async,
+    tungstenite::{self, client::IntoClientRequest, http::HeaderValue, Message},
+    WebSocketStream,
+};
+
+use crate::{client::data::Config, error};
+
+/// The subprotocols offered to the server, preferring binary, which allows for any encoding.
+const PROTOCOLS: &str = "binary.ircv3.net, text.ircv3.net";
+/// The subprotocol in which each message is text, and so must be UTF-8.
+const TEXT_PROTOCOL: &str = "text.ircv3.net";
+
+/// A WebSocket connection, read from and written to as a stream of IRC lines.
+pub struct WebSocket<S> {
+    // Boxed, since the WebSocket's buffers would otherwise dwarf the other connections.
+    inner: Box<WebSocketStream<S>>,
+    /// Whether the server chose the text subprotocol, in which case messages must be UTF-8.
+    text: bool,
+    /// The line being read, and how much of it has been read already.
+    read: Vec<u8>,
+    read_pos: usize,
+    /// The bytes written that don't make up a whole line yet.
+    write: Vec<u8>,
+    /// Whether the server has closed the connection, in which case our reply must still be sent.
+    closed: bool,
+}
+
+impl<S> WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Performs the WebSocket handshake with the server over the specified stream, which is
+    /// `secure` if it uses TLS, offering both IRCv3 subprotocols.
+    pub(crate) async fn connect(
+        config: &Config,
+        stream: S,
+        secure: bool,
+    ) -> error::Result<WebSocket<S>> {
+        let url = format!(
+            "{}://{}:{}{}",
+            if secure { "wss" } else { "ws" },
+            config.server()?,
+            config.port(),
+            config.websocket_path(),
+        );
+        log::info!("Opening WebSocket to {}.", url);
+
+        let mut request = url.into_client_request()?;
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(PROTOCOLS),
+        );
+        let (inner, response) = client_async(request, stream).await?;
+        let text = response
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .is_some_and(|protocol| protocol == TEXT_PROTOCOL);
+
+        Ok(WebSocket {
+            inner: Box::new(inner),
+            text,
+            read: Vec::new(),
+            read_pos: 0,
+            write: Vec::new(),
+            closed: false,
+        })
+    }
+}
+
+impl<S> WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Sends each whole line written so far as its own message.
+    fn poll_send_lines(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while let Some(end) = this.write.iter().position(|&b| b == b'\n') {
+            ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(into_io)?;
+
+            let mut line: Vec<u8> = this.write.drain(..=end).collect();
+            while let Some(b'\r' | b'\n') = line.last() {
+                line.pop();
+            }
+            let message = if this.text {
+                Message::Text(String::from_utf8_lossy(&line).into_owned())
+            } else {
+                Message::Binary(line)
+            };
+            Pin::new(&mut this.inner)
+                .start_send(message)
+                .map_err(into_io)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncRead for WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.read.len() {
+                let len = buf.remaining().min(this.read.len() - this.read_pos);
+                buf.put_slice(&this.read[this.read_pos..this.read_pos + len]);
+                this.read_pos += len;
+                return Poll::Ready(Ok(()));
+            }
+            if this.closed {
+                // The connection is over either way, so a failure to reply doesn't matter.
+                let _ = ready!(Pin::new(&mut this.inner).poll_flush(cx));
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut line = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Text(text))) => text.into_bytes(),
+                Some(Ok(Message::Binary(data))) => data,
+                // The end of the connection reads as the end of the stream.
+                Some(Ok(Message::Close(_))) | None => {
+                    this.closed = true;
+                    continue;
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(into_io(e))),
+            };
+            line.extend_from_slice(b"\r\n");
+            this.read = line;
+            this.read_pos = 0;
+        }
+    }
+}
+
+impl<S> AsyncWrite for WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.as_mut().poll_send_lines(cx))?;
+        self.write.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_send_lines(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(into_io)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_send_lines(cx))?;
+        Pin::new(&mut self.inner).poll_close(cx).map_err(into_io)
+    }
+}
+
+fn into_io(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TEXT_PROTOCOL;
+    use crate::client::{data::Config, Client};
+    use anyhow::Result;
+    use futures::prelude::*;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::{
+        handshake::server::{Request, Response},
+        http::HeaderValue,
+        Message,
+    };
+
+    #[tokio::test]
+    // The handshake callback's error type is tungstenite's to choose.
+    #[allow(clippy::result_large_err)]
+    async fn websocket_echo() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+
+        // An echo server, which also checks that the client offered the IRCv3 subprotocols.
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut ws =
+                tokio_tungstenite::accept_hdr_async(stream, |req: &Request, mut res: Response| {
+                    let offered = req.headers().get("Sec-WebSocket-Protocol");
+                    assert!(offered.is_some_and(|p| p.to_str().unwrap().contains(TEXT_PROTOCOL)));
+                    res.headers_mut().insert(
+                        "Sec-WebSocket-Protocol",
+                        HeaderValue::from_static(TEXT_PROTOCOL),
+                    );
+                    Ok(res)
+                })
+                .await?;
+            let mut received = Vec::new();
+            while let Some(message) = ws.next().await.transpose()? {
+                if let Message::Text(line) = message {
+                    let quit = line.starts_with("QUIT");
+                    received.push(line.clone());
+                    ws.send(Message::Text(line)).await?;
+                    if quit {
+                        ws.close(None).await?;
+                    }
+                }
+            }
+            anyhow::Ok(received)
+        });
+
+        let mut client = Client::from_config(Config {
+            nickname: Some("test".to_owned()),
+            server: Some("127.0.0.1".to_owned()),
+            port: Some(port),
+            #[cfg(any(feature = "tls-native", feature = "tls-rust"))]
+            use_tls: Some(false),
+            use_websocket: Some(true),
+            ..Config::default()
+        })
+        .await?;
+        client.send_privmsg("#test", "Hello over WebSocket!")?;
+        client.send_quit("Bye")?;
+        let messages = client.stream()?.collect().await?;
+
+        assert_eq!(
+            server.await??,
+            vec!["PRIVMSG #test :Hello over WebSocket!", "QUIT Bye"]
+        );
+        assert_eq!(
+            messages.iter().map(|m| m.to_string()).collect::<Vec<_>>(),
+            vec!["PRIVMSG #test :Hello over WebSocket!\r\n", "QUIT Bye\r\n"]
+        );
+        Ok(())
+    }
+}
@@ -9,6 +9,7 @@ use tokio::sync::mpsc::error::{SendError, TrySendError};
 use tokio_rustls::rustls::client::InvalidDnsNameError;
 
 use crate::proto::error::{MessageParseError, ProtocolError};
+use crate::proto::Response;
 
 /// A specialized `Result` type for the `irc` crate.
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -29,6 +30,27 @@ pub enum Error {
     #[error("a proxy error occurred")]
     Proxy(#[from] tokio_socks::Error),
 
+    /// The proxy refused to connect us to the server.
+    #[cfg(feature = "proxy")]
+    #[error("the proxy refused the connection: {}", reason)]
+    ProxyRefused {
+        /// The reason the proxy gave.
+        reason: String,
+    },
+
+    /// The proxy rejected our credentials.
+    #[cfg(feature = "proxy")]
+    #[error("the proxy rejected our credentials: {}", reason)]
+    ProxyAuthFailed {
+        /// The reason the proxy gave.
+        reason: String,
+    },
+
+    /// An internal WebSocket error.
+    #[cfg(feature = "websocket")]
+    #[error("a WebSocket error occurred")]
+    WebSocket(#[source] Box<tokio_tungstenite::tungstenite::Error>),
+
     /// An internal TLS error.
     #[cfg(all(feature = "tls-native", not(feature = "tls-rust")))]
     #[error("a TLS error occurred: {0}")]
@@ -68,6 +90,10 @@ pub enum Error {
     #[error("an async channel closed")]
     AsyncChannelClosed,
 
+    /// A message was sent with the outgoing queue full.
+    #[error("the outgoing queue is full")]
+    QueueFull,
+
     /// An internal oneshot channel closed.
     #[error("a oneshot channel closed")]
     OneShotCanceled,
@@ -120,9 +146,84 @@ pub enum Error {
     #[error("none of the specified nicknames were usable")]
     NoUsableNick,
 
+    /// The server rejected our connection password.
+    #[error("the server rejected the connection password")]
+    PasswordMismatch,
+
+    /// The server refused to let us register because we are banned.
+    #[error("banned from the server: {}", reason)]
+    Banned {
+        /// The reason the server gave.
+        reason: String,
+    },
+
+    /// SASL authentication failed during registration.
+    #[error("SASL authentication failed ({:?}): {}", code, message)]
+    SaslFailed {
+        /// The numeric the server sent.
+        code: Response,
+        /// The message the server sent along with it.
+        message: String,
+    },
+
+    /// The connection was closed before registration completed.
+    #[error("the connection was closed during registration: {}", reason)]
+    ConnectionClosed {
+        /// The reason the server gave, if any.
+        reason: String,
+    },
+
+    /// The server did not accept our registration in time.
+    #[error("the server did not accept the registration in time")]
+    RegistrationTimeout,
+
     /// Stream has already been configured.
     #[error("stream has already been configured")]
     StreamAlreadyConfigured,
+
+    /// The server answered a query with an error numeric.
+    #[error("query failed: {}", message)]
+    QueryFailed {
+        /// The error numeric.
+        code: Response,
+        /// The server's description of the error.
+        message: String,
+    },
+
+    /// The server didn't answer a query in time.
+    #[error("query timed out")]
+    QueryTimeout,
+
+    /// A labeled message was sent without the server enabling `labeled-response`.
+    #[error("the server has not enabled labeled-response")]
+    LabeledResponseUnsupported,
+
+    /// History was requested without the server enabling `chathistory`.
+    #[error("the server has not enabled chathistory")]
+    ChatHistoryUnsupported,
+
+    /// A delivery confirmation was requested without the server enabling `echo-message`.
+    #[error("the server has not enabled echo-message")]
+    EchoMessageUnsupported,
+
+    /// The server responded to a message without echoing it back.
+    #[error("the server did not echo the message")]
+    NotEchoed,
+
+    /// A message that must be sent as a single line contains a line break.
+    #[error("the message contains a line break")]
+    LineBreak,
+
+    /// The server answered a command with a `FAIL` message.
+    #[error("{} failed ({}): {}", command, code, description)]
+    CommandFailed {
+        /// The command that failed.
+        command: String,
+        /// The machine-readable code of the failure.
+        code: String,
+        /// The server's description of the failure.
+        description: String,
+    },
 }
 
 /// Errors that occur with configurations.
@@ -200,6 +301,13 @@ impl From<ProtocolError> for Error {
     }
 }
 
+#[cfg(feature = "websocket")]
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Error {
+        Error::WebSocket(Box::new(e))
+    }
+}
+
 impl<T> From<SendError<T>> for Error {
     fn from(_: SendError<T>) -> Error {
         Error::AsyncChannelClosed